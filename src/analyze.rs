@@ -0,0 +1,447 @@
+use std::path::Path;
+
+use ffmpeg::{
+    codec::Id,
+    format::{
+        Sample,
+        sample::Type::Packed
+    }
+};
+
+use fluxplayer::PlayerError;
+
+use crate::fft;
+
+const WINDOW: usize = 4096;
+const HOP: usize = 512;
+
+// Bins this far below the passband level count as empty.
+const CUTOFF_THRESHOLD_DB: f64 = 45.;
+
+const MIN_BPM: f64 = 60.;
+const MAX_BPM: f64 = 200.;
+
+// Below ~200 Hz a 4096-point bin is wider than a semitone.
+const CHROMA_MIN_HZ: f64 = 200.;
+const CHROMA_MAX_HZ: f64 = 5000.;
+
+const PITCH_NAMES: [&str; 12] = [
+    "C", "Db", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B"
+];
+
+// Krumhansl-Kessler key profiles, tonic first.
+const MAJOR_PROFILE: [f64; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88
+];
+const MINOR_PROFILE: [f64; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17
+];
+
+const LOSSLESS_CODECS: [Id; 15] = [
+    Id::FLAC, Id::ALAC, Id::WAVPACK, Id::APE, Id::TTA, Id::TAK, Id::MLP, Id::TRUEHD,
+    Id::WMALOSSLESS, Id::PCM_S16LE, Id::PCM_S16BE, Id::PCM_S24LE, Id::PCM_S24BE,
+    Id::PCM_S32LE, Id::PCM_F32LE,
+];
+
+pub fn run(path: &Path) -> Result<(), PlayerError> {
+    ffmpeg::init().map_err(PlayerError::Init)?;
+
+    let (codec_id, analyzer) = analyze(path).map_err(|e| PlayerError::unplayable(path, &e))?;
+
+    let nyquist = analyzer.rate / 2.;
+    let lossless = LOSSLESS_CODECS.contains(&codec_id);
+
+    println!("{}[Analysis]", " ".repeat(17));
+    println!("{:>16}: {}",
+            "File Path", path.display());
+    println!("{:>16}: {:?} ({})",
+            "Codec", codec_id, if lossless { "lossless" } else { "lossy" });
+
+    if let Some(bpm) = analyzer.tempo() {
+        println!("{:>16}: {:.1} BPM",
+                "Tempo", bpm);
+    }
+
+    if let Some((tonic, minor)) = analyzer.key() {
+        println!("{:>16}: {}{} ({})",
+                "Key", PITCH_NAMES[tonic], if minor { "m" } else { "" }, camelot(tonic, minor));
+    }
+
+    let cutoff = match analyzer.cutoff() {
+        Some(cutoff) => cutoff,
+        None => {
+            println!("{:>16}: silent", "Spectrum");
+            return Ok(());
+        }
+    };
+
+    println!("{:>16}: {:.1} kHz (Nyquist: {:.1} kHz)",
+            "Cutoff", cutoff / 1000., nyquist / 1000.);
+
+    let verdict = if cutoff >= nyquist * 0.95 || cutoff >= 21_000. {
+        "full bandwidth".to_string()
+    } else if lossless {
+        format!("likely transcoded from lossy ({})", lossy_profile(cutoff))
+    } else {
+        format!("band-limited, consistent with {}", lossy_profile(cutoff))
+    };
+    println!("{:>16}: {}",
+            "Verdict", verdict);
+    Ok(())
+}
+
+fn lossy_profile(cutoff: f64) -> &'static str {
+    match cutoff as u32 {
+        0..=11_500 => "<= 64 kbps",
+        11_501..=15_500 => "~96-112 kbps",
+        15_501..=16_500 => "~128 kbps",
+        16_501..=17_500 => "~160 kbps",
+        17_501..=19_500 => "~192-256 kbps",
+        _ => "~320 kbps or VBR V0",
+    }
+}
+
+fn camelot(tonic: usize, minor: bool) -> String {
+    // minor keys share the wheel position of their relative major
+    let major_tonic = if minor { (tonic + 3) % 12 } else { tonic };
+    let number = (major_tonic * 7 + 7) % 12 + 1;
+
+    format!("{}{}", number, if minor { "A" } else { "B" })
+}
+
+/// Decode the whole file to mono and feed it through the analyzer.
+fn analyze(path: &Path) -> Result<(Id, Analyzer), ffmpeg::Error> {
+    let decoder = MonoDecoder::open(path)?;
+    let codec_id = decoder.codec_id;
+
+    let mut analyzer = Analyzer::new(f64::from(decoder.rate()));
+    decoder.run(|samples| analyzer.push(samples));
+
+    Ok((codec_id, analyzer))
+}
+
+/// The best audio stream of a file, downmixed to mono f32 at its own rate.
+pub struct MonoDecoder {
+    input: ffmpeg::format::context::Input,
+    stream_index: usize,
+    pub codec_id: Id,
+    audio: ffmpeg::decoder::Audio,
+    layout: ffmpeg::ChannelLayout,
+    swr: ffmpeg::software::resampling::Context,
+}
+
+impl MonoDecoder {
+    pub fn open(path: &Path) -> Result<Self, ffmpeg::Error> {
+        let input = ffmpeg::format::input(&path)?;
+
+        let (stream_index, parameters) = {
+            let stream = input.streams().best(ffmpeg::media::Type::Audio)
+                .ok_or(ffmpeg::Error::StreamNotFound)?;
+            (stream.index(), stream.parameters())
+        };
+
+        let codec_id = parameters.id();
+        let audio = ffmpeg::codec::Context::from_parameters(parameters)?.decoder().audio()?;
+
+        let layout = if audio.channel_layout().is_empty() {
+            ffmpeg::ChannelLayout::default(audio.channels() as i32)
+        } else {
+            audio.channel_layout()
+        };
+
+        let swr = ffmpeg::software::resampler(
+            (audio.format(), layout, audio.rate()),
+            (Sample::F32(Packed), ffmpeg::ChannelLayout::MONO, audio.rate()),
+        )?;
+
+        Ok(Self { input, stream_index, codec_id, audio, layout, swr })
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.audio.rate()
+    }
+
+    /// Decode to the end, passing each block of samples to `sink`.
+    pub fn run<F: FnMut(&[f32])>(self, mut sink: F) {
+        let Self { mut input, stream_index, mut audio, layout, mut swr, .. } = self;
+        let mut decode_frame = ffmpeg::frame::Audio::empty();
+        let mut mono_frame = ffmpeg::frame::Audio::empty();
+
+        // everything the codec has ready, which can be several frames per packet
+        let mut receive = |audio: &mut ffmpeg::decoder::Audio| {
+            while audio.receive_frame(&mut decode_frame).is_ok() {
+                decode_frame.set_channel_layout(layout);
+                if swr.run(&decode_frame, &mut mono_frame).is_ok() {
+                    sink(mono_samples(&mono_frame));
+                }
+            }
+        };
+
+        let mut packets = input.packets();
+        while let Some(Ok((read_stream, read_packet))) = packets.next() {
+            if read_stream.index() != stream_index {
+                continue;
+            }
+            if audio.send_packet(&read_packet).is_ok() {
+                receive(&mut audio);
+            }
+        }
+        // the last frames are only given up once the codec knows the file has ended
+        if audio.send_eof().is_ok() {
+            receive(&mut audio);
+        }
+
+        while let Ok(Some(_)) = swr.flush(&mut mono_frame) {
+            sink(mono_samples(&mono_frame));
+        }
+    }
+}
+
+fn mono_samples(frame: &ffmpeg::frame::Audio) -> &[f32] {
+    let (head, data, _) = unsafe { frame.data(0).align_to::<f32>() };
+    assert!(head.is_empty());
+
+    &data[..frame.samples().min(data.len())]
+}
+
+struct Analyzer {
+    rate: f64,
+    window: Vec<f32>,
+    pending: Vec<f32>,
+    re: Vec<f32>,
+    im: Vec<f32>,
+    power: Vec<f64>,
+    frames: usize,
+    previous: Vec<f32>,
+    onsets: Vec<f32>,
+    pitch_classes: Vec<Option<usize>>,
+    chroma: [f64; 12],
+}
+
+impl Analyzer {
+    fn new(rate: f64) -> Self {
+        let bin_hz = rate / WINDOW as f64;
+        let pitch_classes = (0..WINDOW / 2)
+            .map(|bin| {
+                let hz = bin as f64 * bin_hz;
+                if !(CHROMA_MIN_HZ..=CHROMA_MAX_HZ).contains(&hz) {
+                    return None;
+                }
+                // A4 = 440 Hz is pitch class 9
+                let semitone = (12. * (hz / 440.).log2()).round() as i64 + 9;
+                Some(semitone.rem_euclid(12) as usize)
+            })
+            .collect();
+
+        Self {
+            rate,
+            window: fft::hann(WINDOW),
+            pending: Vec::with_capacity(WINDOW * 2),
+            re: vec![0.; WINDOW],
+            im: vec![0.; WINDOW],
+            power: vec![0.; WINDOW / 2],
+            frames: 0,
+            previous: vec![0.; WINDOW / 2],
+            onsets: Vec::new(),
+            pitch_classes,
+            chroma: [0.; 12],
+        }
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        self.pending.extend_from_slice(samples);
+
+        let mut offset = 0;
+        while self.pending.len() - offset >= WINDOW {
+            let block = &self.pending[offset..offset + WINDOW];
+            for (i, sample) in block.iter().enumerate() {
+                self.re[i] = sample * self.window[i];
+                self.im[i] = 0.;
+            }
+
+            fft::fft(&mut self.re, &mut self.im);
+
+            // spectral flux of the log magnitudes drives the onset envelope
+            let mut flux = 0.;
+            for (bin, power) in self.power.iter_mut().enumerate() {
+                let bin_power = self.re[bin] * self.re[bin] + self.im[bin] * self.im[bin];
+                *power += f64::from(bin_power);
+
+                let magnitude = bin_power.sqrt().ln_1p();
+                flux += (magnitude - self.previous[bin]).max(0.);
+                self.previous[bin] = magnitude;
+
+                if let Some(pitch_class) = self.pitch_classes[bin] {
+                    self.chroma[pitch_class] += f64::from(magnitude);
+                }
+            }
+            self.onsets.push(flux);
+            self.frames += 1;
+            offset += HOP;
+        }
+
+        self.pending.drain(..offset);
+    }
+
+    fn bin_hz(&self) -> f64 {
+        self.rate / WINDOW as f64
+    }
+
+    /// Highest frequency that still carries meaningful energy.
+    fn cutoff(&self) -> Option<f64> {
+        if self.frames == 0 {
+            return None;
+        }
+
+        let db: Vec<f64> = self.power.iter()
+            .map(|p| 10. * (p / self.frames as f64 + 1e-20).log10())
+            .collect();
+
+        // low rates cut the band short, or off
+        let band = |lo: f64, hi: f64| {
+            let hi = ((hi / self.bin_hz()) as usize).min(db.len());
+            let lo = ((lo / self.bin_hz()) as usize).min(hi);
+            db[lo..hi].iter().sum::<f64>() / (hi - lo).max(1) as f64
+        };
+
+        let reference = band(1_000., 8_000.);
+        if reference < -150. {
+            return None;
+        }
+
+        // smoothed over a few bins so single spurious peaks don't count
+        const SMOOTH: usize = 4;
+        (SMOOTH..db.len() - SMOOTH)
+            .rev()
+            .find(|&bin| {
+                let avg = db[bin - SMOOTH..=bin + SMOOTH].iter().sum::<f64>() / (SMOOTH * 2 + 1) as f64;
+                avg > reference - CUTOFF_THRESHOLD_DB
+            })
+            .map(|bin| bin as f64 * self.bin_hz())
+    }
+
+    /// Tempo from the autocorrelation of the onset envelope.
+    fn tempo(&self) -> Option<f64> {
+        let fps = self.rate / HOP as f64;
+        let min_lag = (fps * 60. / MAX_BPM).floor() as usize;
+        let max_lag = (fps * 60. / MIN_BPM).ceil() as usize;
+
+        // so few onset frames a second can't resolve a beat
+        if min_lag == 0 || self.onsets.len() < max_lag * 4 {
+            return None;
+        }
+
+        // remove the local mean (~0.5s) so only the peaks remain
+        let span = (fps / 4.) as usize;
+        let envelope: Vec<f64> = (0..self.onsets.len())
+            .map(|i| {
+                let lo = i.saturating_sub(span);
+                let hi = (i + span + 1).min(self.onsets.len());
+                let mean = self.onsets[lo..hi].iter().sum::<f32>() / (hi - lo) as f32;
+                f64::from((self.onsets[i] - mean).max(0.))
+            })
+            .collect();
+
+        let acf = |lag: usize| -> f64 {
+            envelope.iter().zip(&envelope[lag..]).map(|(a, b)| a * b).sum()
+        };
+        let acfs: Vec<f64> = (min_lag - 1..=max_lag + 1).map(acf).collect();
+
+        // favour tempos near 120 BPM, one octave either side
+        let weight = |lag: f64| (-0.5 * (60. * fps / lag / 120.).log2().powi(2)).exp();
+
+        let best = (1..acfs.len() - 1)
+            .max_by(|&a, &b| {
+                let wa = acfs[a] * weight((a + min_lag - 1) as f64);
+                let wb = acfs[b] * weight((b + min_lag - 1) as f64);
+                wa.total_cmp(&wb)
+            })?;
+
+        if acfs[best] <= 0. {
+            return None;
+        }
+
+        // parabolic interpolation for sub-hop precision
+        let (prev, cur, next) = (acfs[best - 1], acfs[best], acfs[best + 1]);
+        let denom = prev - 2. * cur + next;
+        let offset = if denom.abs() > f64::EPSILON { 0.5 * (prev - next) / denom } else { 0. };
+        let lag = (best + min_lag - 1) as f64 + offset;
+
+        Some(60. * fps / lag)
+    }
+
+    /// Best matching (tonic pitch class, is minor) for the accumulated chroma.
+    fn key(&self) -> Option<(usize, bool)> {
+        if self.chroma.iter().all(|&c| c <= 0.) {
+            return None;
+        }
+
+        let correlate = |profile: &[f64; 12], tonic: usize| {
+            let chroma_mean = self.chroma.iter().sum::<f64>() / 12.;
+            let profile_mean = profile.iter().sum::<f64>() / 12.;
+            let (mut num, mut den_c, mut den_p) = (0., 0., 0.);
+            for (i, p) in profile.iter().enumerate() {
+                let c = self.chroma[(i + tonic) % 12] - chroma_mean;
+                let p = p - profile_mean;
+                num += c * p;
+                den_c += c * c;
+                den_p += p * p;
+            }
+            num / (den_c * den_p).sqrt().max(f64::EPSILON)
+        };
+
+        (0..12)
+            .flat_map(|tonic| vec![
+                (correlate(&MAJOR_PROFILE, tonic), tonic, false),
+                (correlate(&MINOR_PROFILE, tonic), tonic, true),
+            ])
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, tonic, minor)| (tonic, minor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(rate: f64, seconds: f64) -> Vec<f32> {
+        (0..(rate * seconds) as usize)
+            .map(|i| (i as f64 * 2. * std::f64::consts::PI * 220. / rate).sin() as f32 * 0.5)
+            .collect()
+    }
+
+    #[test]
+    fn low_rates() {
+        for rate in [1_000., 8_000.] {
+            let mut analyzer = Analyzer::new(rate);
+            analyzer.push(&tone(rate, 10.));
+            let _ = analyzer.cutoff();
+        }
+    }
+
+    #[test]
+    fn no_tempo_at_low_rates() {
+        let mut analyzer = Analyzer::new(1_000.);
+        analyzer.push(&tone(1_000., 10.));
+        assert_eq!(analyzer.tempo(), None);
+    }
+
+    #[test]
+    fn camelot_wheel() {
+        assert_eq!(camelot(0, false), "8B");
+        assert_eq!(camelot(9, true), "8A");
+        assert_eq!(camelot(7, false), "9B");
+        assert_eq!(camelot(6, true), "11A");
+        assert_eq!(camelot(4, true), "9A");
+        assert_eq!(camelot(5, false), "7B");
+    }
+
+    #[test]
+    fn lossy_profiles() {
+        assert_eq!(lossy_profile(11_000.), "<= 64 kbps");
+        assert_eq!(lossy_profile(16_000.), "~128 kbps");
+        assert_eq!(lossy_profile(16_500.9), "~128 kbps");
+        assert_eq!(lossy_profile(19_000.), "~192-256 kbps");
+        assert_eq!(lossy_profile(20_500.), "~320 kbps or VBR V0");
+    }
+}
@@ -0,0 +1,261 @@
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+use fluxplayer::infer::Patterns;
+use fluxplayer::playlist::Entry;
+use fluxplayer::tags::Tags;
+
+use crate::files;
+use crate::queue;
+
+/// Tracks added each time the queue runs out.
+const BATCH: usize = 5;
+
+/// How tracks to keep playing are picked, `--autofill-by`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    /// Sharing a genre, same artist first.
+    Genre,
+    Artist,
+    /// Nearest tempo, counting double and half time.
+    Tempo,
+    /// Nearest on the Camelot wheel, what DJs mix by.
+    Key,
+}
+
+impl Strategy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "genre" => Some(Self::Genre),
+            "artist" => Some(Self::Artist),
+            "tempo" | "bpm" => Some(Self::Tempo),
+            "key" => Some(Self::Key),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Genre => "genre",
+            Self::Artist => "artist",
+            Self::Tempo => "tempo",
+            Self::Key => "key",
+        }
+    }
+
+    /// How far `candidate` is from `seed`, None if it isn't similar at all.
+    fn distance(self, seed: &Tags, candidate: &Tags) -> Option<f64> {
+        let same_artist = |a: &Tags, b: &Tags| {
+            let artists = a.values("artist");
+            b.values("artist").iter().any(|artist| artists.iter().any(|a| a.eq_ignore_ascii_case(artist)))
+        };
+
+        match self {
+            Self::Genre => {
+                let genres = seed.values("genre");
+                let shared = candidate.values("genre").iter()
+                    .any(|genre| genres.iter().any(|g| g.eq_ignore_ascii_case(genre)));
+                match (shared, same_artist(seed, candidate)) {
+                    (false, _) => None,
+                    (true, true) => Some(0.),
+                    (true, false) => Some(1.),
+                }
+            }
+            Self::Artist => Some(0.).filter(|_| same_artist(seed, candidate)),
+            Self::Tempo => {
+                let (a, b) = (bpm(seed)?, bpm(candidate)?);
+                Some((a - b).abs().min((2. * a - b).abs()).min((a - 2. * b).abs()))
+            }
+            Self::Key => {
+                let ((n1, minor1), (n2, minor2)) = (camelot(seed.get("key")?)?, camelot(candidate.get("key")?)?);
+                let steps = (i32::from(n1) - i32::from(n2)).rem_euclid(12);
+                Some(f64::from(steps.min(12 - steps)) + if minor1 == minor2 { 0. } else { 1. })
+            }
+        }
+    }
+}
+
+/// The music directory tracks are drawn from when the queue runs out, or
+/// recommendations are queued.
+pub struct Library {
+    // walked and tagged on a thread of its own, so playback doesn't wait on it
+    scan: Option<JoinHandle<Vec<(PathBuf, Tags)>>>,
+    tracks: Vec<(PathBuf, Tags)>,
+    pub strategy: Strategy,
+}
+
+impl Library {
+    /// Start scanning `root`, guessing the tags of untagged files by `patterns`.
+    /// ffmpeg must be initialized.
+    pub fn new(root: PathBuf, strategy: Strategy, patterns: Patterns) -> Self {
+        let scan = thread::spawn(move || {
+            files::collect_files(&root).into_iter()
+                .filter(|path| files::is_audio(path))
+                .map(|path| {
+                    let tags = queue::read_tags(&path, &patterns);
+                    (path, tags)
+                })
+                .collect()
+        });
+        Self { scan: Some(scan), tracks: Vec::new(), strategy }
+    }
+
+    /// Whether the library is still being scanned.
+    pub fn is_scanning(&self) -> bool {
+        self.scan.as_ref().is_some_and(|scan| !scan.is_finished())
+    }
+
+    /// Up to `BATCH` tracks like the one tagged `seed` at `seed_path`, none of
+    /// them already in `queue`. Ties go to the tracks that follow the seed in the
+    /// library, so repeated fills walk on through it.
+    ///
+    /// Waits for the scan to finish if `wait`, otherwise there are none until it has.
+    pub fn similar(&mut self, seed_path: &Path, seed: &Tags, queue: &[Entry], wait: bool) -> Vec<Entry> {
+        let strategy = self.strategy;
+        let tracks = match self.tracks(wait) {
+            Some(tracks) if !tracks.is_empty() => tracks,
+            _ => return Vec::new(),
+        };
+
+        let start = tracks.iter().position(|(path, _)| path == seed_path).unwrap_or(0);
+        let count = tracks.len();
+
+        let mut scored = Vec::new();
+        for offset in 1..=count {
+            let (path, tags) = &tracks[(start + offset) % count];
+            if queue.iter().any(|entry| entry.path == *path) {
+                continue;
+            }
+            if let Some(distance) = strategy.distance(seed, tags) {
+                scored.push((distance, offset, path.clone()));
+            }
+        }
+
+        scored.sort_by(|(a, i, _), (b, j, _)| a.total_cmp(b).then(i.cmp(j)));
+        scored.into_iter()
+            .take(BATCH)
+            .map(|(_, _, path)| Entry::new(path))
+            .collect()
+    }
+
+    /// The tracks tagged with MusicBrainz recording IDs in `ids`, in that order,
+    /// leaving out any already in `queue`. None while the library is scanned.
+    #[cfg(feature = "listenbrainz")]
+    pub fn recordings(&mut self, ids: &[String], queue: &[Entry]) -> Option<Vec<Entry>> {
+        let tracks = self.tracks(false)?;
+
+        let entries = ids.iter()
+            .filter_map(|id| {
+                tracks.iter().find(|(_, tags)| {
+                    tags.get("musicbrainz_trackid").is_some_and(|track_id| track_id.eq_ignore_ascii_case(id))
+                })
+            })
+            .filter(|(path, _)| !queue.iter().any(|entry| entry.path == *path))
+            .map(|(path, _)| Entry::new(path.clone()))
+            .collect();
+        Some(entries)
+    }
+
+    /// The scanned tracks, None while the scan is going on and not `wait`.
+    fn tracks(&mut self, wait: bool) -> Option<&[(PathBuf, Tags)]> {
+        if let Some(scan) = self.scan.take() {
+            if !wait && !scan.is_finished() {
+                self.scan = Some(scan);
+                return None;
+            }
+            // a scan that panicked leaves the library empty
+            self.tracks = scan.join().unwrap_or_default();
+        }
+        Some(&self.tracks)
+    }
+}
+
+fn bpm(tags: &Tags) -> Option<f64> {
+    tags.get("bpm")?.trim().parse().ok().filter(|bpm: &f64| *bpm > 0.)
+}
+
+/// Position on the Camelot wheel, 1-12, and whether the key is minor. Takes
+/// `8A`, `Am`, `C#m`, `Bb`, `F# minor` and the like.
+fn camelot(key: &str) -> Option<(u8, bool)> {
+    let key = key.trim().to_lowercase();
+
+    // already Camelot, A the minor keys
+    let (number, letter) = key.split_at(key.char_indices().last()?.0);
+    if let Ok(n @ 1..=12) = number.parse::<u8>() {
+        return match letter {
+            "a" => Some((n, true)),
+            "b" => Some((n, false)),
+            _ => None,
+        };
+    }
+
+    let mut chars = key.chars();
+    let semitone: i32 = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (semitone, rest) = if let Some(rest) = rest.strip_prefix(['#', '\u{266f}']) {
+        (semitone + 1, rest)
+    } else if let Some(rest) = rest.strip_prefix(['b', '\u{266d}']) {
+        (semitone - 1, rest)
+    } else {
+        (semitone, rest)
+    };
+    let rest = rest.trim();
+    let minor = rest.starts_with('m') && !rest.starts_with("maj");
+
+    // a minor key sits with its relative major, three semitones up; C major is 8B
+    let major = if minor { semitone + 3 } else { semitone };
+    Some(((major * 7 + 7).rem_euclid(12) as u8 + 1, minor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(entries: &[(&str, &str)]) -> Tags {
+        Tags { entries: entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(), inferred: false }
+    }
+
+    #[test]
+    fn camelot_keys() {
+        assert_eq!(camelot("8A"), Some((8, true)));
+        assert_eq!(camelot(" 12b "), Some((12, false)));
+        assert_eq!(camelot("C"), Some((8, false)));
+        assert_eq!(camelot("Am"), Some((8, true)));
+        assert_eq!(camelot("G"), Some((9, false)));
+        assert_eq!(camelot("F# minor"), Some((11, true)));
+        assert_eq!(camelot("Bb"), Some((6, false)));
+        assert_eq!(camelot("C#m"), Some((12, true)));
+        assert_eq!(camelot("Dmaj"), Some((10, false)));
+        for key in ["", "13A", "H", "8C"] {
+            assert_eq!(camelot(key), None, "{}", key);
+        }
+    }
+
+    #[test]
+    fn distances() {
+        let seed = tags(&[("artist", "Someone"), ("genre", "Rock"), ("bpm", "120"), ("key", "8A")]);
+
+        assert_eq!(Strategy::Genre.distance(&seed, &tags(&[("artist", "someone"), ("genre", "rock")])), Some(0.));
+        assert_eq!(Strategy::Genre.distance(&seed, &tags(&[("artist", "Other"), ("genre", "Rock")])), Some(1.));
+        assert_eq!(Strategy::Genre.distance(&seed, &tags(&[("artist", "Someone"), ("genre", "Jazz")])), None);
+        assert_eq!(Strategy::Artist.distance(&seed, &tags(&[("artist", "Other")])), None);
+
+        // half and double time count as the same tempo
+        assert_eq!(Strategy::Tempo.distance(&seed, &tags(&[("bpm", "60")])), Some(0.));
+        assert_eq!(Strategy::Tempo.distance(&seed, &tags(&[("bpm", "125")])), Some(5.));
+        assert_eq!(Strategy::Tempo.distance(&seed, &tags(&[])), None);
+
+        // a step round the wheel, and one more from minor to major; 12 is next to 1
+        assert_eq!(Strategy::Key.distance(&seed, &tags(&[("key", "9B")])), Some(2.));
+        assert_eq!(Strategy::Key.distance(&tags(&[("key", "1B")]), &tags(&[("key", "12B")])), Some(1.));
+    }
+}
@@ -0,0 +1,147 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use fluxplayer::{diagnose, PlayerError};
+
+use crate::files;
+
+// Decoded length may differ from the header by this much before we complain.
+const DURATION_TOLERANCE: f64 = 0.5;
+
+enum Problem {
+    Open(ffmpeg::Error),
+    NoAudio,
+    Decoder(ffmpeg::Error),
+    DecodeErrors { count: usize, first: ffmpeg::Error },
+    Truncated { expected: f64, decoded: f64 },
+    DurationMismatch { expected: f64, decoded: f64 },
+}
+
+pub fn run(dir: &Path) -> Result<(), PlayerError> {
+    ffmpeg::init().map_err(PlayerError::Init)?;
+
+    let files: Vec<_> = files::collect_files(dir)
+        .into_iter()
+        .filter(|f| files::is_audio(f))
+        .collect();
+    let total = files.len();
+    let queue = Arc::new(Mutex::new(files.into_iter()));
+    let failed = Arc::new(Mutex::new(0usize));
+
+    let workers = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let handles: Vec<_> = (0..workers)
+        .map(|_| {
+            let queue = queue.clone();
+            let failed = failed.clone();
+            thread::spawn(move || loop {
+                let file = match queue.lock().unwrap().next() {
+                    Some(file) => file,
+                    None => break,
+                };
+
+                if let Err(problem) = check_file(&file) {
+                    *failed.lock().unwrap() += 1;
+                    println!("{}: {}", file.display(), describe(&problem, &file));
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    println!("\nChecked {} file(s), {} with problems.", total, failed.lock().unwrap());
+    Ok(())
+}
+
+fn check_file(path: &Path) -> Result<(), Problem> {
+    let mut input = ffmpeg::format::input(&path).map_err(Problem::Open)?;
+
+    let (stream_index, expected, parameters) = {
+        let stream = input.streams().best(ffmpeg::media::Type::Audio).ok_or(Problem::NoAudio)?;
+        let expected = if stream.duration() > 0 {
+            stream.duration() as f64 * f64::from(stream.time_base())
+        } else {
+            input.duration() as f64 / f64::from(ffmpeg::ffi::AV_TIME_BASE)
+        };
+        (stream.index(), expected, stream.parameters())
+    };
+
+    let mut audio = ffmpeg::codec::Context::from_parameters(parameters)
+        .and_then(|context| context.decoder().audio())
+        .map_err(Problem::Decoder)?;
+    let rate = f64::from(audio.rate());
+
+    let mut frame = ffmpeg::frame::Audio::empty();
+    let mut samples = 0usize;
+    let mut errors = 0usize;
+    let mut first_error = None;
+
+    let mut error = |e| {
+        errors += 1;
+        first_error.get_or_insert(e);
+    };
+
+    // a packet can hold several frames, and the last ones only come out after send_eof()
+    let mut packets = input.packets();
+    let mut ended = false;
+    while !ended {
+        let sent = match packets.next() {
+            Some(Ok((read_stream, read_packet))) => {
+                if read_stream.index() != stream_index {
+                    continue;
+                }
+                audio.send_packet(&read_packet)
+            }
+            _ => {
+                ended = true;
+                audio.send_eof()
+            }
+        };
+        if let Err(e) = sent {
+            error(e);
+            continue;
+        }
+
+        loop {
+            match audio.receive_frame(&mut frame) {
+                Ok(()) => samples += frame.samples(),
+                Err(ffmpeg::Error::Other { errno: ffmpeg::util::error::EAGAIN }) | Err(ffmpeg::Error::Eof) => break,
+                Err(e) => {
+                    error(e);
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(first) = first_error {
+        return Err(Problem::DecodeErrors { count: errors, first });
+    }
+
+    let decoded = samples as f64 / rate;
+    if expected > 0. && expected - decoded > DURATION_TOLERANCE {
+        return Err(Problem::Truncated { expected, decoded });
+    }
+    if expected > 0. && decoded - expected > DURATION_TOLERANCE {
+        return Err(Problem::DurationMismatch { expected, decoded });
+    }
+
+    Ok(())
+}
+
+fn describe(problem: &Problem, path: &Path) -> String {
+    match problem {
+        Problem::Open(e) => format!("cannot open ({})", diagnose::describe(e, path)),
+        Problem::NoAudio => "no audio stream".to_string(),
+        Problem::Decoder(e) => format!("no decoder ({})", diagnose::describe(e, path)),
+        Problem::DecodeErrors { count, first } =>
+            format!("{} decode error(s), first: {}", count, first),
+        Problem::Truncated { expected, decoded } =>
+            format!("truncated, decoded {:.1}s of {:.1}s", decoded, expected),
+        Problem::DurationMismatch { expected, decoded } =>
+            format!("duration mismatch, decoded {:.1}s but header says {:.1}s", decoded, expected),
+    }
+}
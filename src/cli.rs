@@ -0,0 +1,496 @@
+use std::path::PathBuf;
+
+use clap::{value_parser, Arg, ArgAction, Command};
+
+use crate::autofill::Strategy;
+use fluxplayer::{
+    dsp::CompressorSettings,
+    infer::Patterns,
+    mix::Downmix,
+    output::OutputFormat,
+    tags::TagSelection,
+    VolumeScale, DEFAULT_VOLUME, FRAMES_PER_BUFFER, SAMPLE_RATE
+};
+
+// Limits on --buffer-size, in frames. Below this callbacks can't keep up, above it
+// pausing and seeking lag noticeably.
+const MIN_BUFFER_FRAMES: u32 = 32;
+const MAX_BUFFER_FRAMES: u32 = 16384;
+
+const AFTER_HELP: &str = "\
+templates: any tag, plus {path} {filename} {bitrate} {pos} {dur} {state} {volume}; {title}
+           is what a radio station says is playing, {dur} is \"live\" for radio
+
+keys: space/p pause and resume, left/right seek 10s, +/- volume, m mute, d dim by 20 dB,
+      n next, a autofill, r queue recommendations, [/] previous/next album,
+      {/} previous/next artist, s sort queue, q quit
+
+config: ~/.config/fluxplayer/config.toml sets defaults for the options above and rebinds
+        keys, `fluxplayercli config init` writes one to start from";
+
+/// Everything the command line takes: playback options and files, or a subcommand.
+pub fn command() -> Command {
+    Command::new("fluxplayercli")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Plays audio files and playlists, bit for bit where the device allows")
+        .after_help(AFTER_HELP)
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .arg(Arg::new("paths")
+            .value_name("FILE | PLAYLIST")
+            .help("files or http(s) streams to play, M3U and PLS playlists are expanded in place")
+            .num_args(1..)
+            .required(true)
+            .value_parser(value_parser!(PathBuf)))
+        .arg(Arg::new("quiet")
+            .long("quiet")
+            .short('q')
+            .help("don't print the device and track reports, only progress and errors")
+            .action(ArgAction::SetTrue))
+        .arg(Arg::new("profile")
+            .long("profile")
+            .value_name("NAME")
+            .help("use the settings of a [profile.<NAME>] section of the config file"))
+        .args(tui_arg())
+        .args(output_args())
+        .args(track_args())
+        .args(dsp_args())
+        .args(queue_args())
+        .subcommand(Command::new("config")
+            .about("Manage the config file")
+            .subcommand_required(true)
+            .subcommand(Command::new("init")
+                .about("Write a commented config file with every setting")
+                .arg(Arg::new("force")
+                    .long("force")
+                    .help("overwrite the config file if there is one")
+                    .action(ArgAction::SetTrue))))
+        .subcommand(Command::new("devices")
+            .about("List output devices and the sample rates they support"))
+        .subcommand(Command::new("features")
+            .about("Show the build's cargo features, ffmpeg decoders and PortAudio host APIs"))
+        .subcommands(tool_commands())
+}
+
+/// The subcommands of the `tools` feature.
+#[cfg(feature = "tools")]
+fn tool_commands() -> Vec<Command> {
+    vec![
+        Command::new("analyze")
+            .about("Estimate tempo and key, and spot lossy transcodes by their frequency cutoff")
+            .arg(Arg::new("file")
+                .required(true)
+                .value_parser(value_parser!(PathBuf))),
+        Command::new("check")
+            .about("Decode every audio file under a directory, reporting damaged ones")
+            .arg(Arg::new("dir")
+                .required(true)
+                .value_parser(value_parser!(PathBuf))),
+        Command::new("gapscan")
+            .about("Find tracks that don't join up gaplessly")
+            .arg(Arg::new("paths")
+                .value_name("DIR | FILES")
+                .num_args(1..)
+                .required(true)
+                .value_parser(value_parser!(PathBuf))),
+        Command::new("monitor")
+            .about("Play an input device through to the output, with level meters")
+            .arg(Arg::new("input")
+                .long("input")
+                .value_name("INDEX | NAME")
+                .help("input device, by index or part of its name"))
+            .arg(Arg::new("ascii")
+                .long("ascii")
+                .help("draw the meters with plain ASCII")
+                .action(ArgAction::SetTrue)),
+        Command::new("organize")
+            .about("Move files into folders named after their tags")
+            .arg(Arg::new("src")
+                .required(true)
+                .value_parser(value_parser!(PathBuf)))
+            .arg(Arg::new("pattern")
+                .long("pattern")
+                .help("where each file goes, as a template")
+                .default_value(crate::organize::DEFAULT_PATTERN))
+            .arg(Arg::new("dest")
+                .long("dest")
+                .value_name("DIR")
+                .help("root to move into, the source directory by default")
+                .value_parser(value_parser!(PathBuf)))
+            .arg(Arg::new("apply")
+                .long("apply")
+                .help("move the files, instead of only listing the moves")
+                .action(ArgAction::SetTrue))
+            .arg(filename_patterns_arg()),
+    ]
+}
+
+#[cfg(not(feature = "tools"))]
+fn tool_commands() -> Vec<Command> {
+    Vec::new()
+}
+
+#[cfg(feature = "tui")]
+fn tui_arg() -> Vec<Arg> {
+    vec![
+        Arg::new("tui")
+            .long("tui")
+            .help("play full screen, with a progress bar, the track's tags, the queue and the volume")
+            .conflicts_with("status-line-format")
+            .action(ArgAction::SetTrue),
+    ]
+}
+
+#[cfg(not(feature = "tui"))]
+fn tui_arg() -> Vec<Arg> {
+    Vec::new()
+}
+
+// taken by organize too, which guesses tags the same way
+fn filename_patterns_arg() -> Arg {
+    Arg::new("filename-patterns")
+        .long("filename-patterns")
+        .value_name("PATTERNS")
+        .help("how untagged files are named, tried in order and separated by ;, \
+               e.g. \"%track% - %artist% - %title%;%artist% - %title%\"")
+        .value_parser(Patterns::parse)
+}
+
+fn output_args() -> Vec<Arg> {
+    vec![
+        Arg::new("device")
+            .long("device")
+            .value_name("INDEX | NAME")
+            .help("play on this output device instead of the default, `null` for none")
+            .help_heading("Output"),
+        Arg::new("output-format")
+            .long("output-format")
+            .value_name("FORMAT")
+            .help("open the device as s16, s24, s32 or f32")
+            .default_value("f32")
+            .value_parser(output_format)
+            .help_heading("Output"),
+        Arg::new("buffer-size")
+            .long("buffer-size")
+            .value_name("FRAMES")
+            .help(format!("frames the device is handed at a time, more if it crackles (default {})", FRAMES_PER_BUFFER))
+            .value_parser(buffer_size)
+            .help_heading("Output"),
+        Arg::new("prime")
+            .long("prime")
+            .value_name("SECS")
+            .help(format!("buffer this much before playing (default {}s)", crate::DEFAULT_PRIME_SECONDS))
+            .value_parser(seconds)
+            .help_heading("Output"),
+        Arg::new("volume")
+            .long("volume")
+            .value_name("LEVEL")
+            .help(format!("start at 0.5, 50% or -6dB (default {:.0}%)", DEFAULT_VOLUME * 100.))
+            .value_parser(volume)
+            .help_heading("Output"),
+        Arg::new("volume-scale")
+            .long("volume-scale")
+            .value_name("SCALE")
+            .help("+/- step 5% (linear) or 2 dB (db)")
+            .default_value("linear")
+            .value_parser(volume_scale)
+            .help_heading("Output"),
+        Arg::new("no-gapless")
+            .long("no-gapless")
+            .help("stop and restart the device between tracks")
+            .action(ArgAction::SetTrue)
+            .help_heading("Output"),
+        Arg::new("crossfade")
+            .long("crossfade")
+            .value_name("SECS")
+            .help("overlap the end of each track with the start of the next")
+            .value_parser(seconds)
+            .help_heading("Output"),
+    ]
+}
+
+fn track_args() -> Vec<Arg> {
+    vec![
+        Arg::new("start-at")
+            .long("start-at")
+            .value_name("TIME")
+            .help("start playing at e.g. 1:23 (or h:mm:ss, or seconds)")
+            .value_parser(time)
+            .help_heading("Tracks"),
+        Arg::new("preview")
+            .long("preview")
+            .value_name("SECS")
+            .help("play a normalized excerpt from 30% into the track")
+            .value_parser(seconds)
+            .help_heading("Tracks"),
+        Arg::new("loop")
+            .long("loop")
+            .help("repeat each track until skipped, between the loop points game music rips mark \
+                   (LOOPSTART/LOOPLENGTH tags or a WAV smpl chunk) if it has them")
+            .conflicts_with("preview")
+            .action(ArgAction::SetTrue)
+            .help_heading("Tracks"),
+        Arg::new("tags")
+            .long("tags")
+            .value_name("LIST")
+            .help("tags shown and their order, relabelled with key=Label, e.g. \"title,artist,album_artist=Album Artist\"")
+            .value_parser(TagSelection::parse)
+            .help_heading("Tracks"),
+        Arg::new("show-all-tags")
+            .long("show-all-tags")
+            .help("show every tag the file has")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("tags")
+            .help_heading("Tracks"),
+        Arg::new("classical")
+            .long("classical")
+            .help("show composer, work, movement and performers, grouping tracks by work")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["tags", "show-all-tags"])
+            .help_heading("Tracks"),
+        filename_patterns_arg()
+            .help_heading("Tracks"),
+        Arg::new("play-log")
+            .long("play-log")
+            .value_name("FILE")
+            .help("append a CSV (or .json) record of each play")
+            .value_parser(value_parser!(PathBuf))
+            .help_heading("Tracks"),
+        Arg::new("play-log-format")
+            .long("play-log-format")
+            .value_name("TEMPLATE")
+            .help("write play log entries as template lines instead")
+            .requires("play-log")
+            .help_heading("Tracks"),
+        Arg::new("status-line-format")
+            .long("status-line-format")
+            .value_name("TEMPLATE")
+            .help("print a line like \"{artist} - {title} [{pos}/{dur}]\" on every change, for status bar modules")
+            .help_heading("Tracks"),
+        Arg::new("reconnect")
+            .long("reconnect")
+            .value_name("TRIES")
+            .help(format!("when a live stream drops, try reopening it up to TRIES times in a row (default {}), \
+                           waiting twice as long after each failed try", crate::DEFAULT_RECONNECT_ATTEMPTS))
+            .num_args(0..=1)
+            .value_parser(tries)
+            .help_heading("Tracks"),
+        Arg::new("reconnect-delay")
+            .long("reconnect-delay")
+            .value_name("SECS")
+            .help("wait before the first reconnection try, a minute at most")
+            .default_value("1")
+            .value_parser(seconds)
+            .requires("reconnect")
+            .help_heading("Tracks"),
+    ]
+}
+
+fn dsp_args() -> Vec<Arg> {
+    vec![
+        Arg::new("precision")
+            .long("precision")
+            .value_name("32 | 64")
+            .help("float width DSP stages compute in")
+            .default_value("32")
+            .value_parser(["32", "64"])
+            .help_heading("Processing"),
+        Arg::new("deemphasis")
+            .long("deemphasis")
+            .help("apply 50/15 us CD de-emphasis")
+            .action(ArgAction::SetTrue)
+            .help_heading("Processing"),
+        Arg::new("highpass")
+            .long("highpass")
+            .value_name("HZ")
+            .help(format!("remove DC offset and rumble (default {} Hz)", crate::DEFAULT_HIGHPASS_HZ))
+            .num_args(0..=1)
+            .value_parser(cutoff)
+            .help_heading("Processing"),
+        Arg::new("night-mode")
+            .long("night-mode")
+            .help("compress dynamics for low-volume listening")
+            .action(ArgAction::SetTrue)
+            .help_heading("Processing"),
+        Arg::new("compressor")
+            .long("compressor")
+            .value_name("SETTINGS")
+            .help("compress dynamics as set, a preset (night, broadcast) and/or pre-gain, threshold, knee, \
+                   makeup (dB), ratio, attack and release (ms), e.g. \"broadcast,makeup=9\"")
+            .value_parser(compressor)
+            .help_heading("Processing"),
+        Arg::new("delay")
+            .long("delay")
+            .value_name("L:MS,R:MS")
+            .help("delay channels for speaker alignment (or e.g. R:64samples)")
+            .value_parser(delays)
+            .help_heading("Processing"),
+        Arg::new("mix-matrix")
+            .long("mix-matrix")
+            .value_name("L;R")
+            .help("per-input-channel gains for the left and right outputs, e.g. \"1,0,0.7;0,1,0.7\" for a 3-channel file")
+            .help_heading("Processing"),
+        Arg::new("channels")
+            .long("channels")
+            .value_name("MIX")
+            .help("mix surround and mono files to stereo keeping every speaker (stereo), by ITU-R BS.775 \
+                   without LFE (itu), from the front speakers only (front), or play everything as mono")
+            .default_value("stereo")
+            .value_parser(downmix)
+            .conflicts_with("mix-matrix")
+            .help_heading("Processing"),
+    ]
+}
+
+fn queue_args() -> Vec<Arg> {
+    vec![
+        Arg::new("autofill")
+            .long("autofill")
+            .value_name("DIR")
+            .help("when the queue runs out, keep playing similar tracks from DIR")
+            .value_parser(value_parser!(PathBuf))
+            .conflicts_with("library")
+            .help_heading("Queue"),
+        Arg::new("autofill-by")
+            .long("autofill-by")
+            .value_name("STRATEGY")
+            .help("pick them by genre, artist, tempo or key")
+            .default_value("genre")
+            .value_parser(strategy)
+            .help_heading("Queue"),
+        Arg::new("library")
+            .long("library")
+            .value_name("DIR")
+            .help("music to autofill from once turned on, and to queue recommendations from")
+            .value_parser(value_parser!(PathBuf))
+            .help_heading("Queue"),
+        Arg::new("listenbrainz")
+            .long("listenbrainz")
+            .value_name("USER")
+            .help("queue the user's ListenBrainz recommendations with r")
+            .help_heading("Queue"),
+    ]
+}
+
+/// `2`, `2.5` or `2s`.
+fn seconds(secs: &str) -> Result<f64, String> {
+    secs.trim_end_matches('s').parse::<f64>().ok()
+        .filter(|secs| secs.is_finite() && *secs >= 0.)
+        .ok_or_else(|| "expected a number of seconds".to_string())
+}
+
+// checked against half the output rate once that's known
+fn cutoff(hz: &str) -> Result<f64, String> {
+    hz.parse::<f64>().ok()
+        .filter(|hz| hz.is_finite() && *hz > 0.)
+        .ok_or_else(|| "expected a frequency in Hz, over 0".to_string())
+}
+
+fn buffer_size(frames: &str) -> Result<u32, String> {
+    frames.parse::<u32>().ok()
+        .filter(|frames| (MIN_BUFFER_FRAMES..=MAX_BUFFER_FRAMES).contains(frames))
+        .ok_or_else(|| format!("expected {} to {} frames", MIN_BUFFER_FRAMES, MAX_BUFFER_FRAMES))
+}
+
+fn tries(count: &str) -> Result<u32, String> {
+    count.parse::<u32>().ok()
+        .filter(|count| *count > 0)
+        .ok_or_else(|| "expected a number of tries, at least 1".to_string())
+}
+
+/// `0.5`, `50%` or `-6dB`.
+fn volume(level: &str) -> Result<f32, String> {
+    let lower = level.trim().to_lowercase();
+    let volume = if let Some(db) = lower.strip_suffix("db") {
+        db.trim().parse::<f32>().ok().map(|db| 10f32.powf(db / 20.))
+    } else if let Some(percent) = lower.strip_suffix('%') {
+        percent.trim().parse::<f32>().ok().map(|percent| percent / 100.)
+    } else {
+        lower.parse::<f32>().ok()
+    };
+
+    volume.filter(|v| (0. ..=1.).contains(v))
+        .ok_or_else(|| "expected 0 to 1, a percentage or dB at most 0, e.g. 0.5, 50% or -6dB".to_string())
+}
+
+/// `1:23`, `1:02:03` or plain seconds.
+fn time(time: &str) -> Result<f64, String> {
+    let mut seconds = 0.;
+    for part in time.split(':') {
+        let value = part.trim().parse::<f64>().ok()
+            .filter(|v| v.is_finite() && *v >= 0.)
+            .ok_or_else(|| "expected a time like 1:23, 1:02:03 or 83".to_string())?;
+        seconds = seconds * 60. + value;
+    }
+    Ok(seconds)
+}
+
+// checked here, converted to samples once the output rate is known
+fn delays(spec: &str) -> Result<String, String> {
+    crate::parse_delays(spec, SAMPLE_RATE).map(|_| spec.to_string())
+}
+
+fn compressor(spec: &str) -> Result<CompressorSettings, String> {
+    CompressorSettings::parse(spec)
+}
+
+fn output_format(name: &str) -> Result<OutputFormat, String> {
+    OutputFormat::parse(name).ok_or_else(|| "expected s16, s24, s32 or f32".to_string())
+}
+
+fn volume_scale(name: &str) -> Result<VolumeScale, String> {
+    VolumeScale::parse(name).ok_or_else(|| "expected linear or db".to_string())
+}
+
+fn downmix(name: &str) -> Result<Downmix, String> {
+    Downmix::parse(name).ok_or_else(|| "expected stereo, itu, front or mono".to_string())
+}
+
+fn strategy(name: &str) -> Result<Strategy, String> {
+    Strategy::parse(name).ok_or_else(|| "expected genre, artist, tempo or key".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cutoffs() {
+        assert_eq!(cutoff("20"), Ok(20.));
+        assert_eq!(cutoff("7.5"), Ok(7.5));
+        for hz in ["0", "-20", "nan", "inf", "20Hz"] {
+            assert!(cutoff(hz).is_err(), "{}", hz);
+        }
+    }
+
+    #[test]
+    fn volumes() {
+        assert_eq!(volume("0.5"), Ok(0.5));
+        assert_eq!(volume("50%"), Ok(0.5));
+        assert_eq!(volume(" 0 dB "), Ok(1.));
+        assert!((volume("-6dB").unwrap() - 0.501).abs() < 0.001);
+        for level in ["1.5", "150%", "6dB", "-0.1", "loud", ""] {
+            assert!(volume(level).is_err(), "{}", level);
+        }
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(time("83"), Ok(83.));
+        assert_eq!(time("1:23"), Ok(83.));
+        assert_eq!(time("1:02:03.5"), Ok(3723.5));
+        for t in ["", "1:", "-5", "1:-2", "inf", "1m"] {
+            assert!(time(t).is_err(), "{}", t);
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(seconds("2"), Ok(2.));
+        assert_eq!(seconds("2.5s"), Ok(2.5));
+        assert_eq!(seconds("0"), Ok(0.));
+        for secs in ["-1", "nan", "infs", "2m", ""] {
+            assert!(seconds(secs).is_err(), "{}", secs);
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use clap::Command;
+use toml::{Table, Value};
+
+use fluxplayer::PlayerError;
+
+/// Settings the config file takes, by section, and the command line options
+/// they're defaults for. `output.gapless`, the `[keys]` section and profiles are
+/// handled on their own.
+// How often watch() looks at the config file.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+const SETTINGS: [(&str, &str, &str); 19] = [
+    ("output", "device", "device"),
+    ("output", "format", "output-format"),
+    ("output", "buffer_size", "buffer-size"),
+    ("output", "prime", "prime"),
+    ("output", "volume", "volume"),
+    ("output", "volume_scale", "volume-scale"),
+    ("output", "crossfade", "crossfade"),
+    ("processing", "precision", "precision"),
+    ("processing", "channels", "channels"),
+    ("processing", "night_mode", "night-mode"),
+    ("processing", "compressor", "compressor"),
+    ("network", "reconnect", "reconnect"),
+    ("network", "reconnect_delay", "reconnect-delay"),
+    ("display", "tags", "tags"),
+    ("display", "show_all_tags", "show-all-tags"),
+    ("display", "classical", "classical"),
+    ("display", "filename_patterns", "filename-patterns"),
+    ("display", "quiet", "quiet"),
+    ("display", "status_line_format", "status-line-format"),
+];
+
+/// Written by `config init`.
+const TEMPLATE: &str = r#"# fluxplayercli settings. Each is a default for the command line option of the
+# same name, which still overrides it. Uncomment a line to change it.
+#
+# Changes to volume, volume_scale, status_line_format and [keys] are picked up
+# while playing, the rest once it's started again.
+
+[output]
+# by index or part of its name (see `fluxplayercli devices`), or "null" for none
+#device = "USB"
+# s16, s24, s32 or f32
+#format = "f32"
+# frames the device is handed at a time, more if it crackles
+#buffer_size = 512
+# seconds of audio buffered before playing
+#prime = 0.25
+# 0.5, "50%" or "-6dB"
+#volume = "50%"
+# +/- step 5% (linear) or 2 dB (db)
+#volume_scale = "linear"
+#gapless = true
+# seconds the end of each track overlaps the start of the next
+#crossfade = 0
+
+[processing]
+# float width DSP stages compute in, "32" or "64"
+#precision = "32"
+# how surround and mono files are mixed: stereo, itu, front or mono
+#channels = "stereo"
+#night_mode = false
+# a preset, night or broadcast, and/or pre-gain, threshold, knee, makeup (dB),
+# ratio, attack and release (ms) changed from it
+#compressor = "night,threshold=-30,ratio=3,knee=6,attack=10,release=250,makeup=8"
+
+[network]
+# tries at reopening a live stream that drops, set to turn reconnecting on
+#reconnect = 5
+# seconds before the first try, doubled after each failed one
+#reconnect_delay = 1
+
+[display]
+# tags shown and their order, relabelled with key=Label
+#tags = "title,artist,album_artist=Album Artist,album"
+#show_all_tags = false
+#classical = false
+# how untagged files are named, tried in order
+#filename_patterns = "%track% - %artist% - %title%;%artist% - %track% - %title%;%track% - %title%;%track%. %title%;%artist% - %title%"
+#quiet = false
+#status_line_format = "{artist} - {title} [{pos}/{dur}]"
+
+[keys]
+# keys given for an action replace its defaults: a character, one of space, left,
+# right, up, down, enter, esc, tab and backspace, or a list of them
+#pause = ["space", "p"]
+#seek_back = "left"
+#seek_forward = "right"
+#volume_up = ["+", "="]
+#volume_down = "-"
+#mute = "m"
+#dim = "d"
+#next = "n"
+#previous_album = "["
+#next_album = "]"
+#previous_artist = "{"
+#next_artist = "}"
+#sort = "s"
+#autofill = "a"
+#recommend = "r"
+#quit = ["q", "esc"]
+
+# Profiles bundle settings from any of the sections above, picked with
+# --profile <name>. Their settings replace the ones above.
+#[profile.headphones]
+#device = "USB"
+#volume = "40%"
+#crossfade = 2
+#
+#[profile.livingroom]
+#device = "HDMI"
+#channels = "itu"
+#night_mode = true
+"#;
+
+/// Defaults from the config file, see `path()`.
+#[derive(Default)]
+pub struct Config {
+    /// Where it was read from, for reporting errors in it.
+    pub path: PathBuf,
+    /// Ids of the command line options set, and their values as they'd be given.
+    pub defaults: Vec<(&'static str, String)>,
+    /// Actions bound to other keys, and the key names.
+    pub keys: Vec<(String, Vec<String>)>,
+    /// `[profile.<name>]` sections, by name, with their settings as for `defaults`.
+    profiles: Vec<(String, Vec<(&'static str, String)>)>,
+}
+
+impl Config {
+    /// `command`, defaulting to the settings. Options given still override them.
+    pub fn apply(&self, command: Command) -> Command {
+        self.defaults.iter().fold(command, |command, (id, value)| {
+            command.mut_arg(*id, |arg| arg.default_value(value.clone()))
+        })
+    }
+
+    /// Take the settings of profile `name` over the ones outside of profiles.
+    pub fn use_profile(&mut self, name: &str) -> Result<(), PlayerError> {
+        let settings = match self.profiles.iter().find(|(profile, _)| profile == name) {
+            Some((_, settings)) => settings.clone(),
+            None => {
+                let names: Vec<&str> = self.profiles.iter().map(|(profile, _)| profile.as_str()).collect();
+                let reason = match names.len() {
+                    0 => format!("no profile \"{}\", there are no [profile.<name>] sections", name),
+                    _ => format!("no profile \"{}\", expected one of {}", name, names.join(", ")),
+                };
+                return Err(PlayerError::Config { path: self.path.clone(), reason });
+            }
+        };
+        // later defaults replace earlier ones in apply()
+        self.defaults.extend(settings);
+        Ok(())
+    }
+}
+
+/// `$XDG_CONFIG_HOME/fluxplayer/config.toml`, `~/.config` if that isn't set.
+pub fn path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join("fluxplayer").join("config.toml"))
+}
+
+/// The config file's settings, none if there isn't one.
+pub fn load() -> Result<Config, PlayerError> {
+    let path = match path() {
+        Some(path) => path,
+        None => return Ok(Config::default()),
+    };
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(PlayerError::Config { path, reason: e.to_string() }),
+    };
+
+    let mut config = parse(&text).map_err(|reason| PlayerError::Config { path: path.clone(), reason })?;
+    config.path = path;
+    Ok(config)
+}
+
+/// Send `ConfigChanged` to `commands` from a background thread each time the
+/// config file is written, created or removed.
+pub fn watch(commands: Sender<fluxplayer::Command>) {
+    let path = match path() {
+        Some(path) => path,
+        None => return,
+    };
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+
+    thread::spawn(move || {
+        let mut last = modified(&path);
+        loop {
+            thread::sleep(WATCH_INTERVAL);
+            let now = modified(&path);
+            if now == last {
+                continue;
+            }
+            last = now;
+            if commands.send(fluxplayer::Command::ConfigChanged).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Write the commented template, unless there's a config file already and
+/// not `force`.
+pub fn init(force: bool) -> Result<(), PlayerError> {
+    let path = path().ok_or_else(|| PlayerError::Config {
+        path: PathBuf::from("~/.config/fluxplayer/config.toml"),
+        reason: "neither $XDG_CONFIG_HOME nor $HOME is set".to_string(),
+    })?;
+    if path.exists() && !force {
+        return Err(PlayerError::Config { path, reason: "already exists, overwrite it with --force".to_string() });
+    }
+
+    let written = path.parent().map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, TEMPLATE));
+    if let Err(e) = written {
+        return Err(PlayerError::Config { path, reason: e.to_string() });
+    }
+
+    println!("{:>16}: {}", "Wrote", path.display());
+    Ok(())
+}
+
+fn parse(text: &str) -> Result<Config, String> {
+    let table: Table = text.parse().map_err(|e: toml::de::Error| e.message().to_string())?;
+
+    let mut config = Config::default();
+    for (section, values) in &table {
+        let values = values.as_table()
+            .ok_or_else(|| format!("{}: expected a [{}] section", section, section))?;
+        if section == "profile" {
+            for (name, settings) in values {
+                config.profiles.push((name.clone(), profile(name, settings)?));
+            }
+            continue;
+        }
+        for (key, value) in values {
+            if section == "keys" {
+                let names = key_names(value)
+                    .ok_or_else(|| format!("keys.{}: expected a key or a list of keys", key))?;
+                config.keys.push((key.clone(), names));
+            } else {
+                let setting = setting(section, key, value).map_err(|e| format!("{}.{}: {}", section, key, e))?;
+                config.defaults.push(setting);
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// The settings of `[profile.<name>]`, which are named as in their own sections.
+fn profile(name: &str, settings: &Value) -> Result<Vec<(&'static str, String)>, String> {
+    let settings = settings.as_table()
+        .ok_or_else(|| format!("profile.{}: expected a [profile.{}] section", name, name))?;
+    settings.iter()
+        .map(|(key, value)| {
+            let section = match key.as_str() {
+                "gapless" => "output",
+                key => SETTINGS.iter().find(|(_, k, _)| *k == key).map_or("", |(section, ..)| *section),
+            };
+            setting(section, key, value).map_err(|e| format!("profile.{}.{}: {}", name, key, e))
+        })
+        .collect()
+}
+
+/// The option `section.key` is a default for, and `value` as it would be given
+/// on the command line.
+fn setting(section: &str, key: &str, value: &Value) -> Result<(&'static str, String), String> {
+    let text = match value {
+        Value::String(text) => text.clone(),
+        Value::Integer(n) => n.to_string(),
+        Value::Float(x) => x.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        _ => return Err("expected a string, number or true/false".to_string()),
+    };
+
+    // the command line can only turn gapless playback off
+    if (section, key) == ("output", "gapless") {
+        let gapless = value.as_bool()
+            .ok_or_else(|| "expected true or false".to_string())?;
+        return Ok(("no-gapless", (!gapless).to_string()));
+    }
+
+    SETTINGS.iter()
+        .find(|(s, k, _)| *s == section && *k == key)
+        .map(|(_, _, id)| (*id, text))
+        .ok_or_else(|| "no such setting".to_string())
+}
+
+fn key_names(value: &Value) -> Option<Vec<String>> {
+    match value {
+        Value::String(key) => Some(vec![key.clone()]),
+        Value::Array(keys) => keys.iter().map(|key| key.as_str().map(str::to_string)).collect(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings() {
+        let config = parse("[output]\nvolume = \"50%\"\ngapless = false\ncrossfade = 2\n\n[display]\nquiet = true\n").unwrap();
+        assert_eq!(config.defaults, vec![
+            ("quiet", "true".to_string()),
+            ("crossfade", "2".to_string()),
+            ("no-gapless", "true".to_string()),
+            ("volume", "50%".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn keys() {
+        let config = parse("[keys]\npause = \"x\"\nquit = [\"q\", \"esc\"]\n").unwrap();
+        assert_eq!(config.keys, vec![
+            ("pause".to_string(), vec!["x".to_string()]),
+            ("quit".to_string(), vec!["q".to_string(), "esc".to_string()]),
+        ]);
+        assert_eq!(parse("[keys]\npause = 1\n").err().unwrap(), "keys.pause: expected a key or a list of keys");
+    }
+
+    #[test]
+    fn mistakes() {
+        assert_eq!(parse("[output]\nloudness = 1\n").err().unwrap(), "output.loudness: no such setting");
+        // keys are looked up in their own section
+        assert_eq!(parse("[display]\nvolume = 1\n").err().unwrap(), "display.volume: no such setting");
+        assert_eq!(parse("[output]\ngapless = \"no\"\n").err().unwrap(), "output.gapless: expected true or false");
+        assert_eq!(parse("[output]\nvolume = [1]\n").err().unwrap(), "output.volume: expected a string, number or true/false");
+    }
+
+    #[test]
+    fn profiles() {
+        let mut config = parse("[output]\nvolume = \"50%\"\n\n[profile.headphones]\nvolume = 0.4\ngapless = false\nchannels = \"itu\"\n").unwrap();
+        assert_eq!(config.defaults, vec![("volume", "50%".to_string())]);
+        config.use_profile("headphones").unwrap();
+        assert_eq!(config.defaults, vec![
+            ("volume", "50%".to_string()),
+            ("channels", "itu".to_string()),
+            ("no-gapless", "true".to_string()),
+            ("volume", "0.4".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn bad_profiles() {
+        let error = parse("[profile.headphones]\nloudness = 1\n").err().unwrap();
+        assert_eq!(error, "profile.headphones.loudness: no such setting");
+        let mut config = parse("[profile.headphones]\nvolume = 0.4\n").unwrap();
+        match config.use_profile("speakers") {
+            Err(PlayerError::Config { reason, .. }) => assert_eq!(reason, "no profile \"speakers\", expected one of headphones"),
+            _ => panic!("expected a config error"),
+        }
+    }
+}
@@ -0,0 +1,498 @@
+use std::path::{Path, PathBuf};
+use std::slice;
+
+use ffmpeg::{
+    codec,
+    format::{context::Input, stream::Stream},
+    frame::Audio
+};
+
+use crate::diagnose;
+use crate::mix::Mixer;
+use crate::resample::Resampler;
+use crate::stream;
+use crate::{CHANNELS, SAMPLE_RATE};
+
+/// The best audio stream of a file, decoded to the output format.
+pub struct Decoder {
+    input: Input,
+    stream_index: usize,
+    audio: ffmpeg::decoder::Audio,
+    resampler: Option<Resampler>,
+    frame: Audio,
+    // planar frames, interleaved
+    interleaved: Vec<f32>,
+    // the end of the file was sent to the codec, and everything it held has been received
+    draining: bool,
+    finished: bool,
+    time_base: f64,
+    // timestamp of the last decoded frame, in seconds
+    frame_time: Option<f64>,
+    // seeks land on the packet before the target, samples up to here are dropped
+    skip_to: Option<f64>,
+    origin: f64,
+    params: CodecParams,
+    // only the first is reported, a damaged file tends to produce a run of them
+    decode_errors: usize,
+    // codec and resampler contexts were carried over from the previous track
+    reused: bool,
+    // what the output is resampled to, if it has to be
+    output_rate: u32,
+    // with --loop, the start and end in seconds, None for the end of the stream
+    loop_points: Option<(f64, Option<f64>)>,
+    // the loop's end was reached, the next samples come from its start
+    loop_pending: bool,
+    // times it jumped back to the start
+    loops: usize,
+    // where it's streamed from over HTTP, see stream::is_url()
+    url: Option<PathBuf>,
+}
+
+/// Stream parameters the codec context was opened with.
+struct CodecParams {
+    id: codec::Id,
+    format: i32,
+    rate: i32,
+    channels: i32,
+    layout: u64,
+    extradata: Vec<u8>,
+    bit_rate: usize,
+}
+
+impl CodecParams {
+    fn of(stream: &Stream) -> Self {
+        let parameters = stream.parameters();
+        // rust-ffmpeg has no accessors for these on Parameters
+        unsafe {
+            let raw = &*parameters.as_ptr();
+            let extradata = if raw.extradata.is_null() {
+                Vec::new()
+            } else {
+                slice::from_raw_parts(raw.extradata, raw.extradata_size as usize).to_vec()
+            };
+
+            Self {
+                id: parameters.id(),
+                format: raw.format,
+                rate: raw.sample_rate,
+                channels: raw.channels,
+                layout: raw.channel_layout,
+                extradata,
+                bit_rate: raw.bit_rate.max(0) as usize,
+            }
+        }
+    }
+
+    /// Whether a codec context opened for `self` can carry on with `other`.
+    fn matches(&self, other: &Self) -> bool {
+        self.id == other.id
+            && self.format == other.format
+            && self.rate == other.rate
+            && self.channels == other.channels
+            && self.layout == other.layout
+            && self.extradata == other.extradata
+    }
+}
+
+enum Ready {
+    Decoded,
+    Interleaved,
+    Resampled,
+}
+
+impl Decoder {
+    pub fn open(path: &Path) -> Result<Self, ffmpeg::Error> {
+        let input = stream::open(path)?;
+
+        let (stream_index, time_base, parameters, params) = {
+            let stream = input.streams().best(ffmpeg::media::Type::Audio)
+                .ok_or(ffmpeg::Error::StreamNotFound)?;
+            (stream.index(), f64::from(stream.time_base()), stream.parameters(), CodecParams::of(&stream))
+        };
+
+        let audio = codec::Context::from_parameters(parameters)?.decoder().audio()?;
+        let output_rate = SAMPLE_RATE as u32;
+        let resampler = if Resampler::is_needed(&audio, output_rate) {
+            Some(Resampler::new(&audio, output_rate)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            input,
+            stream_index,
+            audio,
+            resampler,
+            frame: Audio::empty(),
+            interleaved: Vec::new(),
+            draining: false,
+            finished: false,
+            time_base,
+            frame_time: None,
+            skip_to: None,
+            origin: 0.,
+            params,
+            decode_errors: 0,
+            reused: false,
+            output_rate,
+            loop_points: None,
+            loop_pending: false,
+            loops: 0,
+            url: Some(path.to_path_buf()).filter(|path| stream::is_url(path)),
+        })
+    }
+
+    /// Open `path` as the track after this one. If its stream has the same codec
+    /// and sample parameters, the codec and resampler contexts are kept instead of
+    /// being set up again, which is most of the cost of a track change.
+    pub fn open_next(mut self, path: &Path) -> Result<Self, ffmpeg::Error> {
+        let input = stream::open(path)?;
+
+        let (stream_index, time_base, params) = {
+            let stream = input.streams().best(ffmpeg::media::Type::Audio)
+                .ok_or(ffmpeg::Error::StreamNotFound)?;
+            (stream.index(), f64::from(stream.time_base()), CodecParams::of(&stream))
+        };
+        if !self.params.matches(&params) {
+            let mut decoder = Self::open(path)?;
+            decoder.set_output_rate(self.output_rate)?;
+            return Ok(decoder);
+        }
+
+        self.audio.flush();
+        // a track that was played to the end has drained the resampler, a skipped one may not have
+        if !self.finished {
+            if let Some(ref mut resampler) = self.resampler {
+                resampler.reset()?;
+            }
+        }
+
+        self.input = input;
+        self.stream_index = stream_index;
+        self.time_base = time_base;
+        self.draining = false;
+        self.finished = false;
+        self.frame_time = None;
+        self.skip_to = None;
+        self.origin = 0.;
+        self.params = params;
+        self.decode_errors = 0;
+        self.reused = true;
+        self.loop_points = None;
+        self.loop_pending = false;
+        self.url = Some(path.to_path_buf()).filter(|path| stream::is_url(path));
+        Ok(self)
+    }
+
+    /// Whether the codec and resampler contexts were kept from the previous track.
+    pub fn is_reused(&self) -> bool {
+        self.reused
+    }
+
+    /// The stream's bit rate, 0 if unknown. Unlike the codec context's, this is
+    /// the current file's when the context was reused.
+    pub fn bit_rate(&self) -> usize {
+        self.params.bit_rate
+    }
+
+    pub fn input(&self) -> &Input {
+        &self.input
+    }
+
+    pub fn stream(&self) -> Stream<'_> {
+        self.input.stream(self.stream_index).unwrap()
+    }
+
+    pub fn audio(&self) -> &ffmpeg::decoder::Audio {
+        &self.audio
+    }
+
+    pub fn resampler(&self) -> Option<&Resampler> {
+        self.resampler.as_ref()
+    }
+
+    /// Sample rate the output is converted to, `SAMPLE_RATE` unless changed.
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    /// Convert the output to `rate` instead, setting up (or dropping) the
+    /// resampler as needed. Call it before decoding anything.
+    pub fn set_output_rate(&mut self, rate: u32) -> Result<(), ffmpeg::Error> {
+        if rate == self.output_rate {
+            return Ok(());
+        }
+
+        // a --mix-matrix mixer is kept
+        let mixer = self.resampler.take().and_then(Resampler::into_mixer);
+        self.output_rate = rate;
+        if mixer.is_some() || Resampler::is_needed(&self.audio, rate) {
+            self.resampler = Some(Resampler::new(&self.audio, rate)?);
+        }
+        if let Some(mixer) = mixer {
+            self.resampler.as_mut().unwrap().set_mixer(mixer);
+        }
+        Ok(())
+    }
+
+    /// Mix channels with `mixer` instead of the default matrix for the layout.
+    pub fn set_mixer(&mut self, mixer: Mixer) -> Result<(), ffmpeg::Error> {
+        if self.resampler.is_none() {
+            self.resampler = Some(Resampler::new(&self.audio, self.output_rate)?);
+        }
+        self.resampler.as_mut().unwrap().set_mixer(mixer);
+        Ok(())
+    }
+
+    /// Stream duration in seconds, 0 if unknown, as for internet radio.
+    pub fn duration(&self) -> f64 {
+        let stream = self.stream();
+        if stream.duration() == ffmpeg::ffi::AV_NOPTS_VALUE {
+            return 0.;
+        }
+        (stream.duration() as f64 * f64::from(stream.time_base())).max(0.)
+    }
+
+    /// A stream without an end, like internet radio, which can't be sought in.
+    pub fn is_live(&self) -> bool {
+        self.url.is_some() && self.duration() == 0.
+    }
+
+    /// What an internet radio station last said is playing, None for files.
+    pub fn stream_title(&self) -> Option<String> {
+        if self.url.is_some() { stream::icy_title(&self.input) } else { None }
+    }
+
+    /// Bytes read from the file or stream so far, counting from 0 again when
+    /// it's reconnected.
+    pub fn bytes_read(&self) -> u64 {
+        stream::bytes_read(&self.input)
+    }
+
+    /// Open the stream again after it dropped, and carry on decoding where the
+    /// server is now. It has to come back in the same format.
+    pub fn reconnect(&mut self) -> Result<(), ffmpeg::Error> {
+        let url = self.url.as_ref().ok_or(ffmpeg::Error::InvalidData)?;
+        let input = stream::open(url)?;
+
+        let (stream_index, time_base, params) = {
+            let stream = input.streams().best(ffmpeg::media::Type::Audio)
+                .ok_or(ffmpeg::Error::StreamNotFound)?;
+            (stream.index(), f64::from(stream.time_base()), CodecParams::of(&stream))
+        };
+        if !self.params.matches(&params) {
+            return Err(ffmpeg::Error::InputChanged);
+        }
+
+        self.audio.flush();
+        if let Some(ref mut resampler) = self.resampler {
+            resampler.reset()?;
+        }
+
+        self.input = input;
+        self.stream_index = stream_index;
+        self.time_base = time_base;
+        self.draining = false;
+        self.finished = false;
+        self.frame_time = None;
+        self.skip_to = None;
+        Ok(())
+    }
+
+    /// Seek to `seconds` from the start of the file.
+    pub fn seek(&mut self, seconds: f64) -> Result<(), ffmpeg::Error> {
+        let seconds = seconds.max(0.);
+        let ts = (seconds * f64::from(ffmpeg::ffi::AV_TIME_BASE)) as i64;
+        self.input.seek(ts, ..ts)?;
+
+        self.audio.flush();
+        if let Some(ref mut resampler) = self.resampler {
+            resampler.reset()?;
+        }
+
+        self.draining = false;
+        self.finished = false;
+        self.skip_to = Some(seconds);
+        self.origin = seconds;
+        self.loop_pending = false;
+        Ok(())
+    }
+
+    /// Play from `start` to `end` (seconds, the end of the stream if None) over
+    /// and over, never finishing. The jump back is cut to the output frame.
+    pub fn set_loop(&mut self, start: f64, end: Option<f64>) {
+        self.loop_points = Some((start, end));
+    }
+
+    /// Position of the first sample after the last seek, 0 before any.
+    pub fn origin(&self) -> f64 {
+        self.origin
+    }
+
+    /// How many times the loop has jumped back to its start, counting up from
+    /// when the file was opened.
+    pub fn loops(&self) -> usize {
+        self.loops
+    }
+
+    /// Next block of interleaved output-format samples, `None` at the end of the stream.
+    pub fn next_samples(&mut self) -> Option<&[f32]> {
+        if self.loop_pending {
+            let (start, _) = self.loop_points?;
+            self.seek(start).ok()?;
+            self.loops += 1;
+        }
+
+        let (ready, skip, len) = loop {
+            let ready = match self.decode_next() {
+                Some(ready) => ready,
+                // the stream ended before the loop did, unless nothing came since the last jump
+                None => match self.loop_points {
+                    Some((start, _)) if self.frame_time.is_some() => {
+                        self.seek(start).ok()?;
+                        self.loops += 1;
+                        self.frame_time = None;
+                        continue;
+                    }
+                    _ => return None,
+                },
+            };
+            let len = match ready {
+                Ready::Decoded => frame_samples(&self.frame).len(),
+                Ready::Interleaved => self.interleaved.len(),
+                Ready::Resampled => self.resampler.as_ref().unwrap().output().len(),
+            };
+
+            let skip = match (self.skip_to, self.frame_time) {
+                (Some(target), Some(time)) if time < target => {
+                    ((target - time) * f64::from(self.output_rate)) as usize * CHANNELS as usize
+                }
+                _ => 0,
+            };
+            if skip < len {
+                self.skip_to = None;
+                break (ready, skip, len);
+            }
+        };
+
+        // cut where the loop ends, the rest comes from its start
+        let mut end = len;
+        if let (Some((_, Some(loop_end))), Some(time)) = (self.loop_points, self.frame_time) {
+            let rate = f64::from(self.output_rate);
+            let first = time + (skip / CHANNELS as usize) as f64 / rate;
+            let frames = ((loop_end - first) * rate).round().max(0.) as usize;
+            if skip + frames * (CHANNELS as usize) < len {
+                end = skip + frames * CHANNELS as usize;
+                self.loop_pending = true;
+            }
+        }
+
+        let samples = match ready {
+            Ready::Decoded => frame_samples(&self.frame),
+            Ready::Interleaved => &self.interleaved,
+            Ready::Resampled => self.resampler.as_ref().unwrap().output(),
+        };
+        Some(&samples[skip..end])
+    }
+
+    fn decode_next(&mut self) -> Option<Ready> {
+        while !self.finished {
+            // frames the codec already has come first, it may hold several per packet
+            match self.audio.receive_frame(&mut self.frame) {
+                Ok(()) => {
+                    let ts = self.frame.timestamp();
+                    self.frame.set_pts(ts);
+                    self.frame_time = ts.map(|ts| ts as f64 * self.time_base);
+
+                    match self.resampler {
+                        Some(ref mut resampler) => {
+                            if resampler.run(&mut self.frame).is_ok() {
+                                return Some(Ready::Resampled);
+                            }
+                        }
+                        None if self.frame.is_planar() => {
+                            interleave(&self.frame, &mut self.interleaved);
+                            return Some(Ready::Interleaved);
+                        }
+                        None => return Some(Ready::Decoded),
+                    }
+                    continue;
+                }
+                Err(ffmpeg::Error::Eof) => {
+                    self.finished = true;
+                    break;
+                }
+                Err(ffmpeg::Error::Other { errno: ffmpeg::util::error::EAGAIN }) => (),
+                Err(e) => {
+                    self.report(&e);
+                    if self.draining {
+                        self.finished = true;
+                        continue;
+                    }
+                }
+            }
+
+            match self.input.packets().next() {
+                Some(Ok((read_stream, read_packet))) => {
+                    if read_stream.index() != self.stream_index {
+                        continue;
+                    }
+                    if let Err(e) = self.audio.send_packet(&read_packet) {
+                        self.report(&e);
+                    }
+                }
+                // the codec still holds the last frames, they're received above until Eof
+                _ if !self.draining => {
+                    self.draining = true;
+                    if self.audio.send_eof().is_err() {
+                        self.finished = true;
+                    }
+                }
+                _ => self.finished = true,
+            }
+        }
+
+        match self.resampler.as_mut().map(Resampler::flush) {
+            Some(true) => Some(Ready::Resampled),
+            _ => None,
+        }
+    }
+
+    fn report(&mut self, error: &ffmpeg::Error) {
+        self.decode_errors += 1;
+        if self.decode_errors == 1 {
+            eprintln!("Error: {}", diagnose::describe_decode(error));
+        }
+    }
+}
+
+/// Samples of a packed float frame. The plane may be padded past the last one.
+#[inline]
+pub fn frame_samples(audio_frame: &Audio) -> &[f32] {
+    let len = audio_frame.samples() * usize::from(audio_frame.channels());
+    &plane(audio_frame, 0)[..len]
+}
+
+/// Samples of a planar float frame, one plane per channel, interleaved into `out`.
+pub fn interleave(audio_frame: &Audio, out: &mut Vec<f32>) {
+    let samples = audio_frame.samples();
+    let planes: Vec<&[f32]> = (0..audio_frame.planes())
+        .map(|i| &plane(audio_frame, i)[..samples])
+        .collect();
+
+    out.clear();
+    out.reserve(samples * planes.len());
+    for i in 0..samples {
+        out.extend(planes.iter().map(|plane| plane[i]));
+    }
+}
+
+#[inline]
+fn plane(audio_frame: &Audio, index: usize) -> &[f32] {
+    // void* arrays in C makes me unsafe :(
+    let (head, data, _) = unsafe { audio_frame.data(index).align_to::<f32>() };
+
+    assert!(head.is_empty());
+
+    data
+}
@@ -0,0 +1,57 @@
+use portaudio as pa;
+
+use fluxplayer::{PlayerError, CHANNELS};
+
+// Rates worth checking a device for, the ones music is distributed at.
+const SAMPLE_RATES: [f64; 6] = [44100., 48000., 88200., 96000., 176400., 192000.];
+
+pub fn run() -> Result<(), PlayerError> {
+    let pa = pa::PortAudio::new()?;
+    let default_output = pa.default_output_device().ok();
+
+    let devices: Vec<_> = pa.devices()?
+        .filter_map(Result::ok)
+        .filter(|(_, info)| info.max_output_channels > 0)
+        .collect();
+
+    for (index, api) in pa.host_apis() {
+        println!("{}[{}]", " ".repeat(17), api.name);
+
+        let api_devices: Vec<_> = devices.iter().filter(|(_, info)| info.host_api == index).collect();
+        if api_devices.is_empty() {
+            println!("{:>16}: none", "Output Devices");
+        }
+
+        for (device, info) in api_devices {
+            println!("{:>16}: {}{}",
+                    format!("Device {}", device.0), info.name,
+                    if Some(*device) == default_output { " (default)" } else { "" });
+            println!("{:>16}: {}",
+                    "Channels", info.max_output_channels);
+            println!("{:>16}: {} Hz default, supports {}",
+                    "Sample Rates", info.default_sample_rate, supported_rates(&pa, *device, info));
+        }
+        println!();
+    }
+
+    println!("play on one with --device <index|name>");
+    Ok(())
+}
+
+/// `SAMPLE_RATES` the device can open a stereo f32 stream at.
+fn supported_rates(pa: &pa::PortAudio, device: pa::DeviceIndex, info: &pa::DeviceInfo) -> String {
+    let channels = CHANNELS.min(info.max_output_channels);
+    let rates: Vec<String> = SAMPLE_RATES.iter()
+        .filter(|&&rate| {
+            let params = pa::StreamParameters::<f32>::new(device, channels, true, info.default_low_output_latency);
+            pa.is_output_format_supported(params, rate).is_ok()
+        })
+        .map(|rate| format!("{}", rate / 1000.))
+        .collect();
+
+    if rates.is_empty() {
+        "none of the usual rates".to_string()
+    } else {
+        format!("{} kHz", rates.join(", "))
+    }
+}
@@ -0,0 +1,97 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+
+use ffmpeg::Error;
+
+// How much of a file is searched for DRM and index markers.
+const SCAN_LIMIT: u64 = 16 * 1024 * 1024;
+
+// ASF Content Encryption Object, 2211B3FB-BD23-11D2-B4B7-00AA00A4B6BB
+const ASF_ENCRYPTION_GUID: [u8; 16] = [
+    0xfb, 0xb3, 0x11, 0x22, 0x23, 0xbd, 0xd2, 0x11,
+    0xb4, 0xb7, 0x00, 0xaa, 0x00, 0xa4, 0xb6, 0xbb,
+];
+
+/// What `error` from opening or decoding `path` means, phrased so the user knows
+/// what to do about it. Falls back to ffmpeg's own message.
+pub fn describe(error: &Error, path: &Path) -> String {
+    if let Some(drm) = drm_scheme(path) {
+        return format!("this {} appears to be {}, it can't be played", file_type(path), drm);
+    }
+
+    match error {
+        Error::StreamNotFound => "the file has no audio stream".to_string(),
+        Error::DecoderNotFound => match codec_name(path) {
+            Some(codec) => format!("this ffmpeg build has no {} decoder, it wasn't compiled in", codec),
+            None => "this ffmpeg build has no decoder for the audio stream".to_string(),
+        },
+        Error::DemuxerNotFound => format!("{} files aren't supported by this ffmpeg build", file_type(path)),
+        Error::InvalidData | Error::Eof if is_mp4(path) && !contains(path, &[b"moov"]) => {
+            "the MP4 index (moov atom) is missing, the file was probably cut off while downloading".to_string()
+        }
+        Error::Eof => "the file ends early, it looks truncated".to_string(),
+        Error::InvalidData => "the file is corrupt or not an audio file".to_string(),
+        Error::Other { errno } => match io::Error::from_raw_os_error(*errno).kind() {
+            ErrorKind::NotFound => "the file doesn't exist".to_string(),
+            ErrorKind::PermissionDenied => "permission denied".to_string(),
+            _ => error.to_string(),
+        },
+        error => error.to_string(),
+    }
+}
+
+/// A packet that failed to decode mid-stream.
+pub fn describe_decode(error: &Error) -> String {
+    match error {
+        Error::InvalidData => "corrupt audio data, skipping it (the file may be damaged or truncated)".to_string(),
+        error => error.to_string(),
+    }
+}
+
+/// DRM the file's container says it's wrapped in, if any.
+fn drm_scheme(path: &Path) -> Option<&'static str> {
+    let extension = extension(path);
+    match extension.as_str() {
+        // protected iTunes tracks carry a `drms` sample entry inside a `sinf` box
+        "m4a" | "m4p" | "m4b" | "mp4" if contains(path, &[b"drms", b"sinf"]) => Some("FairPlay-protected"),
+        "aax" | "aa" => Some("an Audible audiobook, which needs your activation bytes to decrypt"),
+        "wma" | "asf" if contains(path, &[&ASF_ENCRYPTION_GUID]) => Some("protected with Windows Media DRM"),
+        _ => None,
+    }
+}
+
+/// Whether the first `SCAN_LIMIT` bytes of `path` contain any of `needles`.
+fn contains(path: &Path, needles: &[&[u8]]) -> bool {
+    let mut data = Vec::new();
+    let read = File::open(path).and_then(|file| file.take(SCAN_LIMIT).read_to_end(&mut data));
+    if read.is_err() {
+        return false;
+    }
+
+    needles.iter().any(|needle| data.windows(needle.len()).any(|window| window == *needle))
+}
+
+fn codec_name(path: &Path) -> Option<&'static str> {
+    let input = ffmpeg::format::input(&path).ok()?;
+    let stream = input.streams().best(ffmpeg::media::Type::Audio)?;
+    Some(stream.parameters().id().name())
+}
+
+fn is_mp4(path: &Path) -> bool {
+    matches!(extension(path).as_str(), "m4a" | "m4b" | "m4p" | "mp4")
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+/// `M4A`, or `file` without an extension.
+fn file_type(path: &Path) -> String {
+    match extension(path) {
+        ext if ext.is_empty() => "file".to_string(),
+        ext => ext.to_uppercase(),
+    }
+}
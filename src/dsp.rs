@@ -0,0 +1,465 @@
+use std::marker::PhantomData;
+use std::ops::{Add, Mul, MulAssign, Sub};
+
+/// Number type a chain computes in. Samples come in and leave as f32 either way.
+pub trait Sample:
+    Copy + Default + Send + 'static
+    + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + MulAssign
+{
+    fn from_f32(value: f32) -> Self;
+    fn from_f64(value: f64) -> Self;
+    fn to_f32(self) -> f32;
+
+    /// `samples` as f32, converted into `out` if they aren't already.
+    fn narrow<'a>(samples: &'a [Self], out: &'a mut Vec<f32>) -> &'a [f32];
+}
+
+impl Sample for f32 {
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+
+    fn from_f64(value: f64) -> Self {
+        value as f32
+    }
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn narrow<'a>(samples: &'a [Self], _: &'a mut Vec<f32>) -> &'a [f32] {
+        samples
+    }
+}
+
+impl Sample for f64 {
+    fn from_f32(value: f32) -> Self {
+        f64::from(value)
+    }
+
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn narrow<'a>(samples: &'a [Self], out: &'a mut Vec<f32>) -> &'a [f32] {
+        // rounding to f32 is far below the dither added when quantizing for the device
+        out.clear();
+        out.extend(samples.iter().map(|s| s.to_f32()));
+        out
+    }
+}
+
+/// A processing stage run on interleaved output-format samples before they're queued.
+pub trait Stage<T: Sample = f32>: Send {
+    fn name(&self) -> String;
+    fn process(&mut self, samples: &mut [T]);
+}
+
+/// What the player runs samples through, a `Chain` of either precision.
+pub trait Process: Send {
+    fn run<'a>(&'a mut self, samples: &'a [f32]) -> &'a [f32];
+    fn names(&self) -> Vec<String>;
+    fn is_empty(&self) -> bool;
+}
+
+pub struct Chain<T: Sample = f32> {
+    stages: Vec<Box<dyn Stage<T>>>,
+    scratch: Vec<T>,
+    output: Vec<f32>,
+}
+
+impl<T: Sample> Chain<T> {
+    pub fn new() -> Self {
+        Self {
+            stages: Vec::new(),
+            scratch: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, stage: Box<dyn Stage<T>>) {
+        self.stages.push(stage);
+    }
+}
+
+impl<T: Sample> Process for Chain<T> {
+    /// Run all stages over a copy of `samples`.
+    fn run<'a>(&'a mut self, samples: &'a [f32]) -> &'a [f32] {
+        if self.stages.is_empty() {
+            return samples;
+        }
+
+        self.scratch.clear();
+        self.scratch.extend(samples.iter().map(|&s| T::from_f32(s)));
+
+        for stage in &mut self.stages {
+            stage.process(&mut self.scratch);
+        }
+
+        T::narrow(&self.scratch, &mut self.output)
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.stages.iter().map(|s| s.name()).collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+}
+
+impl<T: Sample> Default for Chain<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Constant gain, e.g. from ReplayGain tags.
+pub struct Gain<T = f32> {
+    db: f32,
+    factor: T,
+}
+
+impl<T: Sample> Gain<T> {
+    pub fn new(db: f32) -> Self {
+        Self {
+            db,
+            factor: T::from_f64(10f64.powf(f64::from(db) / 20.)),
+        }
+    }
+}
+
+impl<T: Sample> Stage<T> for Gain<T> {
+    fn name(&self) -> String {
+        format!("gain {:+.2} dB", self.db)
+    }
+
+    fn process(&mut self, samples: &mut [T]) {
+        samples.iter_mut().for_each(|s| *s *= self.factor);
+    }
+}
+
+/// Reverses CD pre-emphasis (50/15 µs shelf), first order IIR per channel.
+pub struct Deemphasis<T = f32> {
+    b0: T,
+    b1: T,
+    a1: T,
+    state: Vec<(T, T)>,
+}
+
+impl<T: Sample> Deemphasis<T> {
+    pub fn new(sample_rate: f64, channels: usize) -> Self {
+        const T1: f64 = 50e-6;
+        const T2: f64 = 15e-6;
+
+        // bilinear transform of (1 + s*T2) / (1 + s*T1)
+        let k = 2. * sample_rate;
+        let norm = 1. + T1 * k;
+
+        Self {
+            b0: T::from_f64((1. + T2 * k) / norm),
+            b1: T::from_f64((1. - T2 * k) / norm),
+            a1: T::from_f64((1. - T1 * k) / norm),
+            state: vec![(T::default(), T::default()); channels],
+        }
+    }
+}
+
+impl<T: Sample> Stage<T> for Deemphasis<T> {
+    fn name(&self) -> String {
+        "de-emphasis (50/15 us)".to_string()
+    }
+
+    fn process(&mut self, samples: &mut [T]) {
+        let channels = self.state.len();
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let (x1, y1) = &mut self.state[i % channels];
+            let y = self.b0 * *sample + self.b1 * *x1 - self.a1 * *y1;
+            *x1 = *sample;
+            *y1 = y;
+            *sample = y;
+        }
+    }
+}
+
+/// RBJ cookbook biquad, transposed direct form II, one state per channel.
+pub struct Biquad<T = f32> {
+    label: String,
+    b: [T; 3],
+    a: [T; 2],
+    state: Vec<[T; 2]>,
+}
+
+impl<T: Sample> Biquad<T> {
+    pub fn highpass(sample_rate: f64, cutoff: f64, q: f64, channels: usize) -> Self {
+        let w0 = 2. * std::f64::consts::PI * cutoff / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * q);
+        let a0 = 1. + alpha;
+
+        Self {
+            label: format!("high-pass {} Hz", cutoff),
+            b: [
+                T::from_f64((1. + cos) / 2. / a0),
+                T::from_f64(-(1. + cos) / a0),
+                T::from_f64((1. + cos) / 2. / a0),
+            ],
+            a: [
+                T::from_f64(-2. * cos / a0),
+                T::from_f64((1. - alpha) / a0),
+            ],
+            state: vec![[T::default(); 2]; channels],
+        }
+    }
+}
+
+impl<T: Sample> Stage<T> for Biquad<T> {
+    fn name(&self) -> String {
+        self.label.clone()
+    }
+
+    fn process(&mut self, samples: &mut [T]) {
+        let channels = self.state.len();
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let z = &mut self.state[i % channels];
+            let x = *sample;
+            let y = self.b[0] * x + z[0];
+            z[0] = self.b[1] * x - self.a[0] * y + z[1];
+            z[1] = self.b[2] * x - self.a[1] * y;
+            *sample = y;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CompressorSettings {
+    /// Gain applied before the compressor, driving more of the signal over the threshold.
+    pub pre_gain_db: f32,
+    pub threshold_db: f32,
+    pub ratio: f32,
+    pub knee_db: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+    pub makeup_db: f32,
+}
+
+/// Gentle settings for quiet listening: loud parts come down, quiet ones up.
+pub const NIGHT_MODE: CompressorSettings = CompressorSettings {
+    pre_gain_db: 0.,
+    threshold_db: -30.,
+    ratio: 3.,
+    knee_db: 6.,
+    attack_ms: 10.,
+    release_ms: 250.,
+    makeup_db: 8.,
+};
+
+/// Dense and even, the way radio sounds: a lower threshold, faster and harder.
+pub const BROADCAST: CompressorSettings = CompressorSettings {
+    pre_gain_db: 3.,
+    threshold_db: -24.,
+    ratio: 6.,
+    knee_db: 3.,
+    attack_ms: 2.,
+    release_ms: 120.,
+    makeup_db: 12.,
+};
+
+impl CompressorSettings {
+    /// Parse `--compressor`: a preset, `night` or `broadcast`, and/or settings
+    /// changed from it, e.g. `broadcast,makeup=9` or `threshold=-20,ratio=4`.
+    /// Settings without a preset change `NIGHT_MODE`'s.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut settings = NIGHT_MODE;
+
+        for (i, part) in spec.split(',').map(str::trim).enumerate() {
+            let (key, value) = match part.split_once('=') {
+                Some(split) => split,
+                None if i == 0 => {
+                    settings = match part.to_lowercase().as_str() {
+                        "night" => NIGHT_MODE,
+                        "broadcast" => BROADCAST,
+                        _ => return Err(format!("\"{}\": expected night, broadcast or <setting>=<value>", part)),
+                    };
+                    continue;
+                }
+                None => return Err(format!("expected <setting>=<value>, got \"{}\"", part)),
+            };
+            let value = value.trim().parse::<f32>().map_err(|e| format!("\"{}\": {}", part, e))?;
+            if !value.is_finite() {
+                return Err(format!("\"{}\" is not a number", part));
+            }
+
+            let (field, valid) = match key.trim().to_lowercase().as_str() {
+                "pre-gain" => (&mut settings.pre_gain_db, true),
+                "threshold" => (&mut settings.threshold_db, value <= 0.),
+                "ratio" => (&mut settings.ratio, value >= 1.),
+                "knee" => (&mut settings.knee_db, value >= 0.),
+                "attack" => (&mut settings.attack_ms, value > 0.),
+                "release" => (&mut settings.release_ms, value > 0.),
+                "makeup" => (&mut settings.makeup_db, true),
+                _ => return Err(format!("\"{}\": expected pre-gain, threshold, ratio, knee, attack, release or makeup", key)),
+            };
+            if !valid {
+                return Err(format!("\"{}\" is out of range: the threshold is at most 0 dB, the ratio at least 1, \
+                                    the knee at least 0 dB and the attack and release over 0 ms", part));
+            }
+            *field = value;
+        }
+        Ok(settings)
+    }
+}
+
+/// Feed-forward soft-knee compressor, linked across channels. The gain is
+/// computed in f32 at any precision, only applying it is done in `T`.
+pub struct Compressor<T = f32> {
+    settings: CompressorSettings,
+    channels: usize,
+    pre_gain: f32,
+    attack: f32,
+    release: f32,
+    makeup: f32,
+    gain_db: f32,
+    sample: PhantomData<T>,
+}
+
+impl<T: Sample> Compressor<T> {
+    pub fn new(settings: CompressorSettings, sample_rate: f64, channels: usize) -> Self {
+        let coeff = |ms: f32| (-1. / (ms / 1000. * sample_rate as f32)).exp();
+
+        Self {
+            settings,
+            channels,
+            pre_gain: 10f32.powf(settings.pre_gain_db / 20.),
+            attack: coeff(settings.attack_ms),
+            release: coeff(settings.release_ms),
+            makeup: 10f32.powf(settings.makeup_db / 20.),
+            gain_db: 0.,
+            sample: PhantomData,
+        }
+    }
+
+    /// Static gain reduction (<= 0 dB) for an input level.
+    fn reduction_db(&self, level_db: f32) -> f32 {
+        let CompressorSettings { threshold_db: t, ratio: r, knee_db: w, .. } = self.settings;
+        let over = level_db - t;
+
+        let out_db = if 2. * over < -w {
+            level_db
+        } else if w > 0. && 2. * over.abs() <= w {
+            level_db + (1. / r - 1.) * (over + w / 2.).powi(2) / (2. * w)
+        } else {
+            t + over / r
+        };
+
+        out_db - level_db
+    }
+}
+
+impl<T: Sample> Stage<T> for Compressor<T> {
+    fn name(&self) -> String {
+        let CompressorSettings { pre_gain_db, threshold_db, ratio, knee_db, attack_ms, release_ms, makeup_db } = self.settings;
+        let pre_gain = if pre_gain_db != 0. { format!("{:+} dB into ", pre_gain_db) } else { String::new() };
+        format!("{}compressor {} dB {}:1, {} dB knee, {}/{} ms (+{} dB)",
+                pre_gain, threshold_db, ratio, knee_db, attack_ms, release_ms, makeup_db)
+    }
+
+    fn process(&mut self, samples: &mut [T]) {
+        for frame in samples.chunks_mut(self.channels) {
+            let peak = frame.iter().fold(0f32, |peak, s| peak.max(s.to_f32().abs())) * self.pre_gain;
+            let level_db = 20. * peak.max(1e-6).log10();
+            let target = self.reduction_db(level_db);
+
+            let coeff = if target < self.gain_db { self.attack } else { self.release };
+            self.gain_db = target + coeff * (self.gain_db - target);
+
+            let gain = T::from_f32(self.pre_gain * 10f32.powf(self.gain_db / 20.) * self.makeup);
+            frame.iter_mut().for_each(|s| *s *= gain);
+        }
+    }
+}
+
+/// Fixed per-channel delay for speaker time alignment.
+pub struct Delay<T = f32> {
+    lines: Vec<Vec<T>>,
+    positions: Vec<usize>,
+}
+
+impl<T: Sample> Delay<T> {
+    /// `delays` in samples, one per channel.
+    pub fn new(delays: &[usize]) -> Self {
+        Self {
+            lines: delays.iter().map(|&d| vec![T::default(); d]).collect(),
+            positions: vec![0; delays.len()],
+        }
+    }
+}
+
+impl<T: Sample> Stage<T> for Delay<T> {
+    fn name(&self) -> String {
+        let delays: Vec<String> = self.lines.iter().map(|l| l.len().to_string()).collect();
+        format!("delay {} samples", delays.join("/"))
+    }
+
+    fn process(&mut self, samples: &mut [T]) {
+        let channels = self.lines.len();
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let channel = i % channels;
+            let line = &mut self.lines[channel];
+            if line.is_empty() {
+                continue;
+            }
+
+            let pos = &mut self.positions[channel];
+            std::mem::swap(sample, &mut line[*pos]);
+            *pos = (*pos + 1) % line.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compressor_settings() {
+        let settings = CompressorSettings::parse("broadcast, ratio=2, makeup=-3").unwrap();
+        assert_eq!(settings.threshold_db, BROADCAST.threshold_db);
+        assert_eq!(settings.ratio, 2.);
+        assert_eq!(settings.makeup_db, -3.);
+
+        let settings = CompressorSettings::parse("threshold=-20").unwrap();
+        assert_eq!(settings.threshold_db, -20.);
+        assert_eq!(settings.ratio, NIGHT_MODE.ratio);
+    }
+
+    #[test]
+    fn compressor_settings_out_of_range() {
+        for spec in ["threshold=3", "ratio=0.5", "knee=-1", "attack=0", "release=-10"] {
+            assert!(CompressorSettings::parse(spec).is_err(), "{}", spec);
+        }
+        for spec in ["loud", "night,broadcast", "ratio", "ratio=x", "gain=3",
+                     "pre-gain=inf", "makeup=NaN", "makeup=-inf", "knee=inf"] {
+            assert!(CompressorSettings::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn hard_knee_at_threshold() {
+        let settings = CompressorSettings::parse("threshold=-120,knee=0").unwrap();
+        let mut compressor = Compressor::<f32>::new(settings, 48_000., 2);
+        assert_eq!(compressor.reduction_db(-120.), 0.);
+        assert_eq!(compressor.reduction_db(-60.), -60. + 60. / settings.ratio);
+
+        // digital silence sits right at the threshold
+        let mut samples = [0f32; 64];
+        compressor.process(&mut samples);
+        let mut samples = [0.5f32; 64];
+        compressor.process(&mut samples);
+        assert!(samples.iter().all(|s| s.is_finite()));
+    }
+}
@@ -0,0 +1,47 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use portaudio as pa;
+
+use crate::diagnose;
+
+/// Why something couldn't be played, worded to be shown to the user as it is.
+#[derive(Debug, thiserror::Error)]
+pub enum PlayerError {
+    /// `--device` matched no output device.
+    #[error("no output device matches \"{0}\" (see `fluxplayercli devices`)")]
+    DeviceNotFound(String),
+    #[error("there is no default output device, pick one with --device (see `fluxplayercli devices`)")]
+    NoDefaultDevice,
+    #[error("no input device matches \"{0}\"")]
+    InputNotFound(String),
+    #[error("there is no default input device, pick one with --input")]
+    NoDefaultInput,
+    #[error("\"{0}\" has no input channels, pick another device with --input")]
+    NoInputChannels(String),
+    #[error("the input and output devices can't run together at {0} Hz")]
+    DuplexUnsupported(f64),
+    /// PortAudio failed opening or running the output stream.
+    #[error("audio output failed: {0}")]
+    Output(#[from] pa::Error),
+    #[error("could not initialize ffmpeg: {0}")]
+    Init(ffmpeg::Error),
+    /// A file that can't be opened or set up for playback, and why.
+    #[error("{}: {reason}", .path.display())]
+    Unplayable { path: PathBuf, reason: String },
+    #[error("{}: {source}", .path.display())]
+    Playlist { path: PathBuf, source: io::Error },
+    /// The config file couldn't be read or written, or has a mistake in it.
+    #[error("{}: {reason}", .path.display())]
+    Config { path: PathBuf, reason: String },
+    /// Some of the queue was skipped over one of the errors above.
+    #[error("{0} of the files could not be played")]
+    Skipped(usize),
+}
+
+impl PlayerError {
+    /// `error` from opening or decoding `path`, explained by `diagnose::describe()`.
+    pub fn unplayable(path: &Path, error: &ffmpeg::Error) -> Self {
+        Self::Unplayable { path: path.to_path_buf(), reason: diagnose::describe(error, path) }
+    }
+}
@@ -0,0 +1,94 @@
+use ffmpeg::codec::{self, Id};
+use portaudio as pa;
+
+use fluxplayer::PlayerError;
+
+// Audio codecs worth asking about, and the files they usually come in.
+const CODECS: [(Id, &str); 24] = [
+    (Id::MP3, "mp3"),
+    (Id::AAC, "m4a, aac"),
+    (Id::ALAC, "m4a"),
+    (Id::FLAC, "flac"),
+    (Id::VORBIS, "ogg"),
+    (Id::OPUS, "opus"),
+    (Id::WAVPACK, "wv"),
+    (Id::APE, "ape"),
+    (Id::TTA, "tta"),
+    (Id::TAK, "tak"),
+    (Id::MUSEPACK7, "mpc"),
+    (Id::MUSEPACK8, "mpc"),
+    (Id::WMAV2, "wma"),
+    (Id::WMAPRO, "wma"),
+    (Id::WMALOSSLESS, "wma"),
+    (Id::AC3, "ac3, mka"),
+    (Id::EAC3, "eac3, mka"),
+    (Id::DTS, "dts, mka"),
+    (Id::TRUEHD, "thd, mka"),
+    (Id::DSD_LSBF, "dsf"),
+    (Id::DSD_MSBF, "dff"),
+    (Id::PCM_S16LE, "wav"),
+    (Id::PCM_S24LE, "wav"),
+    (Id::PCM_F32LE, "wav"),
+];
+
+/// Optional cargo features this binary was built with.
+fn cargo_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "keys") {
+        features.push("keys");
+    }
+    if cfg!(feature = "tools") {
+        features.push("tools");
+    }
+    if cfg!(feature = "listenbrainz") {
+        features.push("listenbrainz");
+    }
+    if cfg!(feature = "tui") {
+        features.push("tui");
+    }
+    features
+}
+
+pub fn run() -> Result<(), PlayerError> {
+    ffmpeg::init().map_err(PlayerError::Init)?;
+
+    println!("{}[Build]", " ".repeat(17));
+    println!("{:>16}: {}",
+            "Version", env!("CARGO_PKG_VERSION"));
+    let features = cargo_features();
+    println!("{:>16}: {}",
+            "Cargo Features", if features.is_empty() { "none".to_string() } else { features.join(", ") });
+
+    let version = codec::version();
+    println!("\n{}[ffmpeg]", " ".repeat(17));
+    println!("{:>16}: {}.{}.{}",
+            "libavcodec", version >> 16, (version >> 8) & 0xff, version & 0xff);
+    println!("{:>16}: {}",
+            "Configuration", codec::configuration());
+
+    println!("\n{}[Decoders]", " ".repeat(17));
+    for (id, files) in CODECS.iter() {
+        let available = codec::decoder::find(*id).is_some();
+        println!("{:>16}: {} ({})",
+                id.name(), if available { "yes" } else { "no, not compiled into ffmpeg" }, files);
+    }
+
+    println!("\n{}[PortAudio]", " ".repeat(17));
+    let pa = match pa::PortAudio::new() {
+        Ok(pa) => pa,
+        Err(e) => {
+            println!("{:>16}: {}", "Error", e);
+            return Ok(());
+        }
+    };
+    println!("{:>16}: {}",
+            "Version", pa.version_text().unwrap_or("unknown"));
+
+    let default_api = pa.default_host_api().ok();
+    for (index, info) in pa.host_apis() {
+        println!("{:>16}: {} device(s){}",
+                info.name, info.device_count,
+                if Some(index) == default_api { ", default" } else { "" });
+    }
+    Ok(())
+}
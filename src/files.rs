@@ -0,0 +1,60 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// All regular files under `path` (or `path` itself), sorted. Symlinked
+/// directories are followed, each directory is only walked once.
+pub fn collect_files(path: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    walk(path, &mut HashSet::new(), &mut files);
+    files.sort();
+    files
+}
+
+// `visited` holds canonical paths, a link back up the tree would loop forever
+fn walk(path: &Path, visited: &mut HashSet<PathBuf>, files: &mut Vec<PathBuf>) {
+    if path.is_dir() {
+        if !fs::canonicalize(path).is_ok_and(|canonical| visited.insert(canonical)) {
+            return;
+        }
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.flatten() {
+                walk(&entry.path(), visited, files);
+            }
+        }
+    } else if path.is_file() {
+        files.push(path.to_path_buf());
+    }
+}
+
+const AUDIO_EXTENSIONS: [&str; 16] = [
+    "flac", "mp3", "m4a", "m4b", "aac", "ogg", "oga", "opus",
+    "wav", "aif", "aiff", "wv", "ape", "wma", "mka", "mpc",
+];
+
+pub fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| AUDIO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn symlink_loops() {
+        let root = std::env::temp_dir().join(format!("fluxplayer-files-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("album")).unwrap();
+        fs::write(root.join("album/01.flac"), b"").unwrap();
+        symlink(&root, root.join("album/up")).unwrap();
+        symlink(".", root.join("self")).unwrap();
+
+        let files = collect_files(&root);
+        let _ = fs::remove_dir_all(&root);
+        assert_eq!(files, [root.join("album/01.flac")]);
+    }
+}
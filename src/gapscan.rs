@@ -0,0 +1,184 @@
+use std::path::{Path, PathBuf};
+
+use fluxplayer::{diagnose, PlayerError};
+
+use crate::analyze::MonoDecoder;
+use crate::files;
+
+// How much of each track's start and end is kept for comparison, in seconds.
+const EDGE_SECONDS: f64 = 2.;
+
+// -60 dBFS
+const SILENCE: f32 = 0.001;
+
+// Silence at a boundary shorter than this is still treated as gapless.
+const GAP_TOLERANCE_MS: f64 = 10.;
+
+// Length of the start of the next track searched for in the previous tail.
+const PROBE_SECONDS: f64 = 0.05;
+const OVERLAP_CORRELATION: f32 = 0.95;
+
+// Step at the boundary, relative to a linear prediction, that counts as a click.
+const STEP_THRESHOLD: f32 = 0.1;
+
+/// First and last few seconds of a track.
+struct Edges {
+    rate: u32,
+    head: Vec<f32>,
+    tail: Vec<f32>,
+}
+
+enum Transition {
+    RateChange(u32, u32),
+    Gap { tail_ms: f64, head_ms: f64 },
+    Overlap { ms: f64 },
+    Step(f32),
+    Continuous,
+}
+
+pub fn run(paths: &[PathBuf]) -> Result<(), PlayerError> {
+    let paths: Vec<PathBuf> = match paths {
+        [dir] if dir.is_dir() => files::collect_files(dir)
+            .into_iter()
+            .filter(|f| files::is_audio(f))
+            .collect(),
+        files => files.to_vec(),
+    };
+
+    ffmpeg::init().map_err(PlayerError::Init)?;
+
+    let mut previous: Option<(&Path, Edges)> = None;
+    let mut transitions = 0;
+    let mut broken = 0;
+
+    for path in &paths {
+        let edges = match read_edges(path) {
+            Ok(edges) => edges,
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), diagnose::describe(&e, path));
+                previous = None;
+                continue;
+            }
+        };
+
+        if let Some((prev_path, prev)) = &previous {
+            let transition = compare(prev, &edges);
+            transitions += 1;
+            if !matches!(transition, Transition::Continuous) {
+                broken += 1;
+            }
+
+            println!("{} -> {}: {}",
+                    file_name(prev_path), file_name(path), describe(&transition));
+        }
+
+        previous = Some((path, edges));
+    }
+
+    println!("\nScanned {} transition(s), {} not gapless.", transitions, broken);
+    Ok(())
+}
+
+fn read_edges(path: &Path) -> Result<Edges, ffmpeg::Error> {
+    let decoder = MonoDecoder::open(path)?;
+    let rate = decoder.rate();
+    let edge_len = (EDGE_SECONDS * f64::from(rate)) as usize;
+
+    let mut head = Vec::with_capacity(edge_len);
+    let mut tail = Vec::with_capacity(edge_len * 2);
+
+    decoder.run(|samples| {
+        let take = (edge_len - head.len()).min(samples.len());
+        head.extend_from_slice(&samples[..take]);
+
+        tail.extend_from_slice(samples);
+        if tail.len() > edge_len * 2 {
+            tail.drain(..tail.len() - edge_len);
+        }
+    });
+
+    if tail.len() > edge_len {
+        tail.drain(..tail.len() - edge_len);
+    }
+
+    Ok(Edges { rate, head, tail })
+}
+
+fn compare(prev: &Edges, next: &Edges) -> Transition {
+    if prev.rate != next.rate {
+        return Transition::RateChange(prev.rate, next.rate);
+    }
+
+    let to_ms = |samples: usize| samples as f64 * 1000. / f64::from(prev.rate);
+
+    let tail_silence = prev.tail.iter().rev().take_while(|s| s.abs() < SILENCE).count();
+    let head_silence = next.head.iter().take_while(|s| s.abs() < SILENCE).count();
+    if to_ms(tail_silence + head_silence) > GAP_TOLERANCE_MS {
+        return Transition::Gap { tail_ms: to_ms(tail_silence), head_ms: to_ms(head_silence) };
+    }
+
+    if let Some(repeated) = find_overlap(&prev.tail, &next.head, prev.rate) {
+        return Transition::Overlap { ms: to_ms(repeated) };
+    }
+
+    // continue the last two samples in a straight line and see where the next track lands
+    if let ([.., a, b], [c, ..]) = (prev.tail.as_slice(), next.head.as_slice()) {
+        let step = (c - (2. * b - a)).abs();
+        if step > STEP_THRESHOLD {
+            return Transition::Step(step);
+        }
+    }
+
+    Transition::Continuous
+}
+
+/// Samples at the end of `tail` that `head` starts by repeating, if any.
+fn find_overlap(tail: &[f32], head: &[f32], rate: u32) -> Option<usize> {
+    let probe_len = (PROBE_SECONDS * f64::from(rate)) as usize;
+    if head.len() < probe_len || tail.len() < probe_len {
+        return None;
+    }
+
+    let probe = &head[..probe_len];
+    let probe_energy: f32 = probe.iter().map(|s| s * s).sum();
+    if probe_energy.sqrt() < SILENCE * (probe_len as f32).sqrt() {
+        // nothing distinctive to look for
+        return None;
+    }
+
+    let (offset, correlation) = tail.windows(probe_len)
+        .enumerate()
+        .map(|(offset, window)| {
+            let dot: f32 = window.iter().zip(probe).map(|(a, b)| a * b).sum();
+            let energy: f32 = window.iter().map(|s| s * s).sum();
+            (offset, dot / (probe_energy * energy).sqrt().max(f32::EPSILON))
+        })
+        .fold((0, 0f32), |best, item| if item.1 > best.1 { item } else { best });
+
+    if correlation >= OVERLAP_CORRELATION {
+        Some(tail.len() - offset)
+    } else {
+        None
+    }
+}
+
+fn describe(transition: &Transition) -> String {
+    match transition {
+        Transition::RateChange(from, to) =>
+            format!("sample rate changes ({} -> {} Hz)", from, to),
+        Transition::Gap { tail_ms, head_ms } =>
+            format!("gap of {:.0} ms ({:.0} ms trailing + {:.0} ms leading silence)",
+                    tail_ms + head_ms, tail_ms, head_ms),
+        Transition::Overlap { ms } =>
+            format!("overlap, last {:.0} ms repeated at the start of the next track", ms),
+        Transition::Step(step) =>
+            format!("discontinuity, {:.2} step at the boundary", step),
+        Transition::Continuous => "continuous".to_string(),
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
@@ -0,0 +1,177 @@
+use std::path::Path;
+
+// Tried in order against the file stem, first match wins, unless others are set.
+const FILENAME_PATTERNS: [&str; 5] = [
+    "%track% - %artist% - %title%",
+    "%artist% - %track% - %title%",
+    "%track% - %title%",
+    "%track%. %title%",
+    "%artist% - %title%",
+];
+
+// Matched against the trailing directories, only for files that look like album tracks.
+const DIRECTORY_PATTERN: &str = "%album_artist%/%album%";
+
+/// The file name patterns untagged files are matched against, in order,
+/// `FILENAME_PATTERNS` unless `--filename-patterns` gives others.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Patterns(Vec<String>);
+
+impl Default for Patterns {
+    fn default() -> Self {
+        Self(FILENAME_PATTERNS.iter().map(|pattern| pattern.to_string()).collect())
+    }
+}
+
+impl Patterns {
+    /// Parse `;`-separated patterns like `%track% - %title%`, checking their fields.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let patterns: Vec<String> = spec.split(';')
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .map(str::to_string)
+            .collect();
+        if patterns.is_empty() {
+            return Err("expected patterns like \"%track% - %title%\", separated by ;".to_string());
+        }
+
+        for pattern in &patterns {
+            if pattern.matches('%').count() % 2 == 1 {
+                return Err(format!("\"{}\": a % is left open", pattern));
+            }
+            if let Some(field) = pattern.split('%').skip(1).step_by(2).find(|field| field_key(field).is_none()) {
+                return Err(format!("\"{}\": unknown field %{}%, expected title, artist, album, \
+                                    album_artist, track, disc or genre", pattern, field));
+            }
+        }
+        Ok(Self(patterns))
+    }
+}
+
+/// Guess tags from the file name and its parent directories for untagged files.
+pub fn infer_metadata(path: &Path, patterns: &Patterns) -> Vec<(&'static str, String)> {
+    let stem = match path.file_stem().and_then(|s| s.to_str()) {
+        Some(stem) => stem.replace('_', " "),
+        None => return Vec::new(),
+    };
+
+    let mut fields = patterns.0.iter()
+        .find_map(|pattern| match_pattern(pattern, &stem))
+        .unwrap_or_else(|| vec![("title", stem.trim().to_string())]);
+
+    if fields.iter().any(|(key, _)| *key == "track") {
+        let depth = DIRECTORY_PATTERN.matches('/').count() + 1;
+        let dirs: Vec<&str> = path
+            .parent()
+            .into_iter()
+            .flat_map(|p| p.iter().rev().take(depth))
+            .filter_map(|c| c.to_str())
+            .collect();
+
+        if dirs.len() == depth {
+            let joined = dirs.into_iter().rev().collect::<Vec<_>>().join("/");
+            if let Some(dir_fields) = match_pattern(DIRECTORY_PATTERN, &joined) {
+                for (key, val) in dir_fields {
+                    if !fields.iter().any(|(k, _)| *k == key) {
+                        fields.push((key, val));
+                    }
+                }
+            }
+        }
+    }
+
+    fields
+}
+
+fn match_pattern(pattern: &str, input: &str) -> Option<Vec<(&'static str, String)>> {
+    let mut fields = Vec::new();
+    let mut rest = input;
+    let mut parts = pattern.split('%').enumerate().peekable();
+
+    while let Some((i, part)) = parts.next() {
+        if i % 2 == 0 {
+            // literal separator
+            rest = rest.strip_prefix(part)?;
+            continue;
+        }
+
+        let key = field_key(part)?;
+        let value = match parts.peek() {
+            Some((_, sep)) if !sep.is_empty() => {
+                let end = rest.find(sep)?;
+                let (value, tail) = rest.split_at(end);
+                rest = tail;
+                value
+            }
+            _ => std::mem::take(&mut rest),
+        };
+
+        let value = value.trim();
+        if value.is_empty() || (key == "track" && !value.chars().all(|c| c.is_ascii_digit())) {
+            return None;
+        }
+        fields.push((key, value.to_string()));
+    }
+
+    if rest.is_empty() {
+        Some(fields)
+    } else {
+        None
+    }
+}
+
+fn field_key(name: &str) -> Option<&'static str> {
+    match name {
+        "title" => Some("title"),
+        "artist" => Some("artist"),
+        "album" => Some("album"),
+        "album_artist" => Some("album_artist"),
+        "track" => Some("track"),
+        "disc" => Some("disc"),
+        "genre" => Some("genre"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_fall_through() {
+        let patterns = Patterns::default();
+        // "Intro" isn't a track number, so the first patterns don't match
+        assert_eq!(infer_metadata(Path::new("Artist - Intro.flac"), &patterns),
+                   [("artist", "Artist".to_string()), ("title", "Intro".to_string())]);
+        assert_eq!(infer_metadata(Path::new("03. Song.flac"), &patterns),
+                   [("track", "03".to_string()), ("title", "Song".to_string())]);
+        assert_eq!(infer_metadata(Path::new("Just_a_Song.flac"), &patterns),
+                   [("title", "Just a Song".to_string())]);
+
+        assert_eq!(match_pattern("%track% - %title%", "Song"), None);
+        assert_eq!(match_pattern("%track% - %title%", " - Song"), None);
+    }
+
+    #[test]
+    fn album_from_directories() {
+        let fields = infer_metadata(Path::new("music/Artist/Album/01 - Song.flac"), &Patterns::default());
+        assert_eq!(fields, [
+            ("track", "01".to_string()),
+            ("title", "Song".to_string()),
+            ("album_artist", "Artist".to_string()),
+            ("album", "Album".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn given_patterns() {
+        let patterns = Patterns::parse(" %title% (%artist%) ;; %title%").unwrap();
+        assert_eq!(patterns.0, ["%title% (%artist%)", "%title%"]);
+        assert_eq!(infer_metadata(Path::new("Song (Artist).ogg"), &patterns),
+                   [("title", "Song".to_string()), ("artist", "Artist".to_string())]);
+
+        assert!(Patterns::parse("").is_err());
+        assert!(Patterns::parse("%track - %title%").is_err());
+        assert!(Patterns::parse("%year% - %title%").is_err());
+    }
+}
@@ -0,0 +1,254 @@
+#[cfg(feature = "keys")]
+use std::io::{self, IsTerminal};
+use std::sync::{mpsc::Sender, Arc, Mutex};
+#[cfg(feature = "keys")]
+use std::thread;
+#[cfg(feature = "keys")]
+use std::time::Duration;
+
+#[cfg(feature = "keys")]
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+#[cfg(feature = "keys")]
+use crossterm::terminal;
+
+use fluxplayer::Command;
+
+#[cfg(feature = "keys")]
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const SEEK_STEP_SECONDS: f64 = 10.;
+
+/// What keys can do, as named in the config file's `[keys]` section, and the
+/// keys that do it unless it says otherwise.
+const ACTIONS: [(&str, Command, &[&str]); 16] = [
+    ("pause", Command::TogglePause, &["space", "p"]),
+    ("seek_back", Command::SeekBy(-SEEK_STEP_SECONDS), &["left"]),
+    ("seek_forward", Command::SeekBy(SEEK_STEP_SECONDS), &["right"]),
+    ("volume_up", Command::VolumeUp, &["+", "="]),
+    ("volume_down", Command::VolumeDown, &["-"]),
+    ("mute", Command::ToggleMute, &["m"]),
+    ("dim", Command::ToggleDim, &["d"]),
+    ("next", Command::Next, &["n"]),
+    ("previous_album", Command::PreviousAlbum, &["["]),
+    ("next_album", Command::NextAlbum, &["]"]),
+    ("previous_artist", Command::PreviousArtist, &["{"]),
+    ("next_artist", Command::NextArtist, &["}"]),
+    ("sort", Command::CycleSort, &["s"]),
+    ("autofill", Command::ToggleAutofill, &["a"]),
+    ("recommend", Command::QueueRecommendations, &["r"]),
+    ("quit", Command::Stop, &["q", "esc"]),
+];
+
+/// Keys other than characters, by name.
+const NAMED_KEYS: [&str; 9] = ["space", "left", "right", "up", "down", "enter", "esc", "tab", "backspace"];
+
+/// How the legend above the progress line groups actions.
+const LEGEND: [(&str, &[&str]); 12] = [
+    ("pause", &["pause"]),
+    ("seek", &["seek_back", "seek_forward"]),
+    ("volume", &["volume_up", "volume_down"]),
+    ("mute", &["mute"]),
+    ("dim", &["dim"]),
+    ("next", &["next"]),
+    ("autofill", &["autofill"]),
+    ("recommend", &["recommend"]),
+    ("album", &["previous_album", "next_album"]),
+    ("artist", &["previous_artist", "next_artist"]),
+    ("sort", &["sort"]),
+    ("quit", &["quit"]),
+];
+
+/// Which keys do what: `ACTIONS`, with the ones the config file rebinds moved.
+#[derive(Clone)]
+pub struct Bindings {
+    // action names and key names
+    keys: Vec<(&'static str, Vec<String>)>,
+}
+
+impl Bindings {
+    /// The defaults, with each action in `overrides` bound to the keys given
+    /// instead. Those keys stop doing whatever they did by default.
+    pub fn new(overrides: &[(String, Vec<String>)]) -> Result<Self, String> {
+        let mut keys: Vec<(&'static str, Vec<String>)> = ACTIONS.iter()
+            .map(|(action, _, keys)| (*action, keys.iter().map(|key| key.to_string()).collect()))
+            .collect();
+
+        for (action, names) in overrides {
+            let names = names.iter()
+                .map(|name| key_name(name).ok_or_else(|| {
+                    format!("keys.{}: \"{}\" isn't a key, expected a character or one of {}", action, name, NAMED_KEYS.join(", "))
+                }))
+                .collect::<Result<Vec<_>, _>>()?;
+            if !keys.iter().any(|(a, _)| a == action) {
+                let actions: Vec<&str> = ACTIONS.iter().map(|(action, ..)| *action).collect();
+                return Err(format!("keys.{}: no such action, expected one of {}", action, actions.join(", ")));
+            }
+
+            for (a, bound) in keys.iter_mut() {
+                if a == action {
+                    bound.clone_from(&names);
+                } else {
+                    bound.retain(|key| !names.contains(key));
+                }
+            }
+        }
+        Ok(Self { keys })
+    }
+
+    /// What the keys do, e.g. `space: pause, left/right: seek, ...`.
+    pub fn legend(&self) -> String {
+        let first_key = |action: &str| {
+            self.keys.iter()
+                .find(|(a, _)| *a == action)
+                .and_then(|(_, keys)| keys.first())
+                .map(String::as_str)
+        };
+
+        LEGEND.iter()
+            .filter_map(|(label, actions)| {
+                let keys: Vec<&str> = actions.iter().filter_map(|action| first_key(action)).collect();
+                Some(format!("{}: {}", keys.join("/"), label)).filter(|_| !keys.is_empty())
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    #[cfg(feature = "keys")]
+    fn command(&self, key: &str) -> Option<Command> {
+        let (action, _) = self.keys.iter().find(|(_, keys)| keys.iter().any(|k| k == key))?;
+        ACTIONS.iter().find(|(a, ..)| a == action).map(|(_, command, _)| *command)
+    }
+}
+
+/// `name` as bindings store it: characters as they are, other keys lowercased.
+fn key_name(name: &str) -> Option<String> {
+    if name == " " {
+        return Some("space".to_string());
+    }
+    if name.chars().count() == 1 {
+        return Some(name.to_string());
+    }
+    let name = name.to_lowercase();
+    Some(name).filter(|name| NAMED_KEYS.contains(&name.as_str()))
+}
+
+/// Keeps the terminal in raw mode until dropped.
+// never made without the keys feature, spawn() reports there's no keyboard
+#[cfg_attr(not(feature = "keys"), allow(dead_code))]
+pub struct RawMode;
+
+impl RawMode {
+    #[cfg(feature = "keys")]
+    pub fn enable() -> Option<Self> {
+        terminal::enable_raw_mode().ok().map(|_| Self)
+    }
+
+    #[cfg(not(feature = "keys"))]
+    pub fn enable() -> Option<Self> {
+        None
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        #[cfg(feature = "keys")]
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Forward key presses to `commands` from a background thread. They arrive one
+/// at a time only while a `RawMode` is held, otherwise once a line is entered.
+///
+/// Returns false when stdin isn't a terminal, e.g. when run from a status bar.
+/// `bindings` can be changed while it runs.
+#[cfg(feature = "keys")]
+pub fn spawn(commands: Sender<Command>, bindings: Arc<Mutex<Bindings>>) -> bool {
+    if !io::stdin().is_terminal() {
+        return false;
+    }
+
+    thread::spawn(move || loop {
+        match event::poll(POLL_INTERVAL) {
+            Ok(true) => (),
+            Ok(false) => continue,
+            Err(_) => break,
+        }
+
+        if let Ok(Event::Key(key)) = event::read() {
+            let command = command(key, &bindings.lock().unwrap());
+            if let Some(command) = command {
+                if commands.send(command).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    true
+}
+
+/// Built without the keys feature, playback can only be stopped with Ctrl+C.
+#[cfg(not(feature = "keys"))]
+pub fn spawn(_commands: Sender<Command>, _bindings: Arc<Mutex<Bindings>>) -> bool {
+    false
+}
+
+#[cfg(feature = "keys")]
+fn command(key: KeyEvent, bindings: &Bindings) -> Option<Command> {
+    if key.kind != KeyEventKind::Press {
+        return None;
+    }
+
+    let name = match key.code {
+        // raw mode swallows SIGINT
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Some(Command::Interrupt),
+        KeyCode::Char(' ') => "space".to_string(),
+        KeyCode::Char(c) => c.to_string(),
+        KeyCode::Left => "left".to_string(),
+        KeyCode::Right => "right".to_string(),
+        KeyCode::Up => "up".to_string(),
+        KeyCode::Down => "down".to_string(),
+        KeyCode::Enter => "enter".to_string(),
+        KeyCode::Esc => "esc".to_string(),
+        KeyCode::Tab => "tab".to_string(),
+        KeyCode::Backspace => "backspace".to_string(),
+        _ => return None,
+    };
+    bindings.command(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys_for<'a>(bindings: &'a Bindings, action: &str) -> &'a [String] {
+        &bindings.keys.iter().find(|(a, _)| *a == action).unwrap().1
+    }
+
+    #[test]
+    fn defaults() {
+        let bindings = Bindings::new(&[]).unwrap();
+        assert_eq!(keys_for(&bindings, "pause"), ["space", "p"]);
+        assert!(bindings.legend().starts_with("space: pause, left/right: seek, +/-: volume"));
+    }
+
+    #[test]
+    fn rebound_keys_move() {
+        let overrides = [("mute".to_string(), vec!["P".to_string(), " ".to_string()])];
+        let bindings = Bindings::new(&overrides).unwrap();
+        assert_eq!(keys_for(&bindings, "mute"), ["P", "space"]);
+        // space was pause's first key
+        assert_eq!(keys_for(&bindings, "pause"), ["p"]);
+        assert!(bindings.legend().starts_with("p: pause"));
+
+        let overrides = [("quit".to_string(), vec!["Esc".to_string()])];
+        assert_eq!(keys_for(&Bindings::new(&overrides).unwrap(), "quit"), ["esc"]);
+    }
+
+    #[test]
+    fn bad_bindings() {
+        let overrides = [("louder".to_string(), vec!["u".to_string()])];
+        assert!(Bindings::new(&overrides).err().unwrap().starts_with("keys.louder: no such action"));
+        let overrides = [("pause".to_string(), vec!["f13".to_string()])];
+        assert!(Bindings::new(&overrides).err().unwrap().starts_with("keys.pause: \"f13\" isn't a key"));
+    }
+}
@@ -0,0 +1,37 @@
+//! Playback engine behind fluxplayercli: decoding, resampling, DSP and output.
+
+use ffmpeg::format::{
+    Sample,
+    sample::Type::Packed
+};
+
+pub mod decoder;
+pub mod diagnose;
+pub mod dsp;
+pub mod error;
+pub mod infer;
+pub mod loops;
+pub mod mix;
+pub mod output;
+pub mod player;
+pub mod playlist;
+pub mod resample;
+pub mod status;
+pub mod stream;
+pub mod tags;
+pub mod template;
+
+pub use error::PlayerError;
+pub use player::{Command, Player, Reconnect, VolumeScale};
+
+pub const CHANNELS: i32 = 2;
+/// Output rate for files the device can't play at their own rate.
+pub const SAMPLE_RATE: f64 = 48000.0;
+/// Frames handed to the device per callback, unless `--buffer-size` says otherwise.
+pub const FRAMES_PER_BUFFER: u32 = 512;
+
+pub const SAMPLE_TYPE: Sample = Sample::F32(Packed);
+pub const CHANNEL_LAYOUT: ffmpeg::ChannelLayout = ffmpeg::ChannelLayout::STEREO;
+
+/// Linear output volume until changed with `--volume` or at runtime.
+pub const DEFAULT_VOLUME: f32 = 0.5;
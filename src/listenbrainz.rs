@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+const API_ROOT: &str = "https://api.listenbrainz.org/1";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many recommendations are asked for at a time.
+const COUNT: usize = 100;
+
+/// MusicBrainz recording IDs ListenBrainz recommends to `user` from their
+/// submitted listens, best first. Empty if none have been generated yet.
+pub fn recommendations(user: &str) -> Result<Vec<String>, String> {
+    let url = format!("{}/cf/recommendation/user/{}/recording", API_ROOT, path_segment(user));
+    let response = ureq::get(&url)
+        .query("count", &COUNT.to_string())
+        .set("User-Agent", concat!("fluxplayercli/", env!("CARGO_PKG_VERSION")))
+        .timeout(TIMEOUT)
+        .call()
+        .map_err(|e| match e {
+            ureq::Error::Status(404, _) => format!("no ListenBrainz user \"{}\"", user),
+            e => e.to_string(),
+        })?;
+
+    // recommendations are generated periodically, until then there's no content
+    if response.status() == 204 {
+        return Ok(Vec::new());
+    }
+
+    let body = response.into_string().map_err(|e| e.to_string())?;
+    Ok(string_values(&body, "recording_mbid"))
+}
+
+/// `text` percent-encoded to fit in one segment of a URL path.
+fn path_segment(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => char::from(byte).to_string(),
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Every string value of `key` in `json`, wherever it's nested. The IDs are all
+/// that's needed from the response, so it isn't parsed any further.
+fn string_values(json: &str, key: &str) -> Vec<String> {
+    let quoted = format!("\"{}\"", key);
+    json.match_indices(&quoted)
+        .filter_map(|(at, _)| {
+            let rest = json[at + quoted.len()..].trim_start().strip_prefix(':')?;
+            let rest = rest.trim_start().strip_prefix('"')?;
+            rest.split_once('"').map(|(value, _)| value.to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_names_in_paths() {
+        assert_eq!(path_segment("rob_1.x~"), "rob_1.x~");
+        assert_eq!(path_segment("a b/c?d#e"), "a%20b%2Fc%3Fd%23e");
+        assert_eq!(path_segment("jürgen"), "j%C3%BCrgen");
+    }
+
+    #[test]
+    fn recording_ids() {
+        let json = r#"{"payload": {"mbids": [{"recording_mbid": "a1", "score": 1}, {"recording_mbid" : "b2"}]}}"#;
+        assert_eq!(string_values(json, "recording_mbid"), ["a1", "b2"]);
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::tags::Tags;
+
+// Bytes of a smpl chunk up to the end of its first loop.
+const SMPL_FIRST_LOOP: usize = 60;
+
+/// Where a track loops, as game music rips mark it: in frames of the file's
+/// own sample rate, `end` exclusive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoopPoints {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl LoopPoints {
+    /// The loop in seconds, for a file sampled at `rate`.
+    pub fn seconds(self, rate: u32) -> (f64, f64) {
+        (self.start as f64 / f64::from(rate), self.end as f64 / f64::from(rate))
+    }
+}
+
+/// The loop `tags` give with LOOPSTART and LOOPLENGTH (or LOOPEND), else the
+/// first one in a WAV file's smpl chunk.
+pub fn read(path: &Path, tags: &Tags) -> Option<LoopPoints> {
+    from_tags(tags).or_else(|| smpl_loop(path).ok().flatten())
+}
+
+fn from_tags(tags: &Tags) -> Option<LoopPoints> {
+    let samples = |key| tags.get(key).and_then(|value| value.trim().parse::<u64>().ok());
+
+    let start = samples("loopstart")?;
+    let end = match samples("looplength") {
+        Some(length) => start.checked_add(length)?,
+        None => samples("loopend")?,
+    };
+    Some(LoopPoints { start, end }).filter(|points| points.end > points.start)
+}
+
+/// The first sampler loop of a RIFF WAVE file, None for other files or WAVs
+/// without one.
+fn smpl_loop(path: &Path) -> io::Result<Option<LoopPoints>> {
+    let mut file = BufReader::new(File::open(path)?);
+
+    let mut header = [0; 12];
+    file.read_exact(&mut header)?;
+    if &header[..4] != b"RIFF" || &header[8..] != b"WAVE" {
+        return Ok(None);
+    }
+
+    let mut chunk = [0; 8];
+    while file.read_exact(&mut chunk).is_ok() {
+        // chunks are padded to an even length
+        let size = i64::from(u32_le(&chunk[4..]));
+        if &chunk[..4] != b"smpl" {
+            file.seek(SeekFrom::Current(size + size % 2))?;
+            continue;
+        }
+
+        // 36 bytes of sampler details, the loop count among them, then 24 per
+        // loop. Only the first loop is read.
+        let mut smpl = [0; SMPL_FIRST_LOOP];
+        if size < SMPL_FIRST_LOOP as i64 {
+            return Ok(None);
+        }
+        file.read_exact(&mut smpl)?;
+        if u32_le(&smpl[28..]) == 0 {
+            return Ok(None);
+        }
+        let (start, end) = (u32_le(&smpl[44..]), u32_le(&smpl[48..]));
+        // the end sample is played, then the start
+        return Ok(Some(LoopPoints { start: start.into(), end: u64::from(end) + 1 }).filter(|points| points.end > points.start));
+    }
+    Ok(None)
+}
+
+fn u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(entries: &[(&str, &str)]) -> Tags {
+        Tags {
+            entries: entries.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect(),
+            inferred: false,
+        }
+    }
+
+    #[test]
+    fn loop_tags() {
+        let points = from_tags(&tags(&[("loopstart", "44100"), ("looplength", "88200")]));
+        assert_eq!(points, Some(LoopPoints { start: 44_100, end: 132_300 }));
+        assert_eq!(points.unwrap().seconds(44_100), (1., 3.));
+
+        let points = from_tags(&tags(&[("loopstart", " 10 "), ("loopend", "20")]));
+        assert_eq!(points, Some(LoopPoints { start: 10, end: 20 }));
+    }
+
+    #[test]
+    fn bad_loop_tags() {
+        assert_eq!(from_tags(&tags(&[("looplength", "10")])), None);
+        assert_eq!(from_tags(&tags(&[("loopstart", "10"), ("loopend", "10")])), None);
+        assert_eq!(from_tags(&tags(&[("loopstart", "-1"), ("loopend", "10")])), None);
+        let max = u64::MAX.to_string();
+        assert_eq!(from_tags(&tags(&[("loopstart", &max), ("looplength", "1")])), None);
+    }
+}
@@ -1,281 +1,1174 @@
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime};
 use std::sync::{
-    Arc,
-    atomic::{
-        AtomicUsize, AtomicBool,
-        Ordering::{Relaxed, SeqCst}
-    }
+    atomic::Ordering::Relaxed,
+    mpsc::{self, Receiver},
+    Arc, Mutex
 };
 
-use portaudio as pa;
-use ringbuf::Producer;
-use ffmpeg::{
-    frame::Audio, 
-    time::sleep,
-    format::{
-        Sample,
-        sample::Type::Packed
-    }
+use clap::{parser::ValueSource, ArgMatches};
+use ffmpeg::{format::context::Input, time::sleep};
+
+use crate::autofill::{Library, Strategy};
+use crate::config::Config;
+use crate::queue::SortKey;
+use crate::stats::SessionStats;
+use fluxplayer::{
+    decoder::Decoder,
+    dsp,
+    infer::Patterns,
+    loops,
+    output::OutputFormat,
+    player,
+    playlist::{self, Entry},
+    mix::{Downmix, Mixer},
+    resample::input_layout,
+    stream,
+    tags::{self, TagSelection},
+    template::{clock, TrackContext},
+    Command, Player, PlayerError, Reconnect, VolumeScale, CHANNELS, DEFAULT_VOLUME, FRAMES_PER_BUFFER, SAMPLE_TYPE
 };
 
-const CHANNELS: i32 = 2;
-const SAMPLE_RATE: f64 = 48000.0;
-const FRAMES_PER_BUFFER: u32 = 512;
-const BUFFER_SIZE: usize = SAMPLE_RATE as usize * CHANNELS as usize;
+#[cfg(feature = "tools")]
+mod analyze;
+mod autofill;
+#[cfg(feature = "tools")]
+mod check;
+mod cli;
+mod config;
+mod devices;
+mod features;
+#[cfg(feature = "tools")]
+mod fft;
+mod files;
+#[cfg(feature = "tools")]
+mod gapscan;
+mod keys;
+#[cfg(feature = "listenbrainz")]
+mod listenbrainz;
+#[cfg(feature = "tools")]
+mod meter;
+#[cfg(feature = "tools")]
+mod monitor;
+#[cfg(feature = "tools")]
+mod organize;
+mod playlog;
+mod queue;
+mod stats;
+#[cfg(feature = "tui")]
+mod tui;
 
-const SAMPLE_TYPE: Sample = Sample::F32(Packed);
-const CHANNEL_LAYOUT: ffmpeg::ChannelLayout = ffmpeg::ChannelLayout::STEREO;
+// Where previews start, as a fraction of the track, and the level they're normalized to.
+const PREVIEW_POSITION: f64 = 0.3;
+const PREVIEW_PEAK: f32 = 0.9;
 
-const GAIN: f32 = 0.5;
+const DEFAULT_HIGHPASS_HZ: f64 = 20.;
+// Longest --delay, speaker alignment needs a few milliseconds at most.
+const MAX_DELAY_SECONDS: f64 = 1.;
+const DEFAULT_RECONNECT_ATTEMPTS: u32 = 5;
 
-struct PlayerStatus {
-    is_decoding: AtomicBool,
-    is_playing: AtomicBool,
-    frames_decoded: AtomicUsize,
-    frames_played: AtomicUsize,
-}
+// How often the main loop checks for the end of playback between commands.
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Audio buffered before the first sample is played, so slow storage doesn't stutter.
+const DEFAULT_PRIME_SECONDS: f64 = 0.25;
+
+fn main() {
+    let mut config = match config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+    let mut matches = config.apply(cli::command()).get_matches();
+    // the profile's settings are defaults too, so the command line is parsed again with them
+    if let Some(profile) = matches.get_one::<String>("profile").cloned() {
+        finish(config.use_profile(&profile));
+        matches = config.apply(cli::command()).get_matches();
+    }
+    let quiet = matches.get_flag("quiet");
+    if !quiet {
+        println!("fluxplayer cli\n");
+    }
 
-impl PlayerStatus {
-    fn new() -> Self {
-        Self {
-            is_decoding: AtomicBool::new(false),
-            is_playing: AtomicBool::new(false),
-            frames_decoded: AtomicUsize::new(0),
-            frames_played: AtomicUsize::new(0),
+    match matches.subcommand() {
+        Some(("config", args)) => match args.subcommand() {
+            Some(("init", args)) => return finish(config::init(args.get_flag("force"))),
+            _ => unreachable!("clap requires a config subcommand"),
+        },
+        #[cfg(feature = "tools")]
+        Some(("analyze", args)) => return finish(analyze::run(args.get_one::<PathBuf>("file").unwrap())),
+        #[cfg(feature = "tools")]
+        Some(("check", args)) => return finish(check::run(args.get_one::<PathBuf>("dir").unwrap())),
+        Some(("devices", _)) => return finish(devices::run()),
+        Some(("features", _)) => return finish(features::run()),
+        #[cfg(feature = "tools")]
+        Some(("gapscan", args)) => {
+            let paths: Vec<PathBuf> = args.get_many::<PathBuf>("paths").unwrap().cloned().collect();
+            return finish(gapscan::run(&paths));
+        }
+        #[cfg(feature = "tools")]
+        Some(("monitor", args)) => {
+            return finish(monitor::run(args.get_one::<String>("input").map(String::as_str), args.get_flag("ascii")));
         }
+        #[cfg(feature = "tools")]
+        Some(("organize", args)) => {
+            return finish(organize::run(
+                args.get_one::<PathBuf>("src").unwrap(),
+                args.get_one::<PathBuf>("dest").map(PathBuf::as_path),
+                args.get_one::<String>("pattern").unwrap(),
+                // the config file's patterns are set on the top level
+                &args.get_one::<Patterns>("filename-patterns")
+                    .or_else(|| matches.get_one::<Patterns>("filename-patterns"))
+                    .cloned()
+                    .unwrap_or_default(),
+                args.get_flag("apply"),
+            ));
+        }
+        _ => (),
     }
+
+    finish(play(&matches, &config, quiet));
 }
 
-const METADATA_WHITELIST: [&str; 7] = [
-    "title", "artist", "album", "album_artist", "track", "disc", "genre"
-];
+/// Exit with the error, if there was one.
+fn finish(result: Result<(), PlayerError>) {
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
 
-fn main() {
-    println!("fluxplayer cli\n");
-    let path = match std::env::args().nth(1) {
-        Some(path) => path,
-        None => {
-            println!("usage: ./fluxplayercli <in_file>");
-            return;   
-        }
+/// Play the files on the command line, or return why not. Files that can't be
+/// played are reported and skipped, then counted in the error at the end.
+fn play(matches: &ArgMatches, config: &Config, quiet: bool) -> Result<(), PlayerError> {
+    let paths: Vec<PathBuf> = matches.get_many::<PathBuf>("paths").unwrap().cloned().collect();
+    let output_format = *matches.get_one::<OutputFormat>("output-format").unwrap();
+    let frames_per_buffer = matches.get_one::<u32>("buffer-size").copied().unwrap_or(FRAMES_PER_BUFFER);
+    let prime_seconds = matches.get_one::<f64>("prime").copied().unwrap_or(DEFAULT_PRIME_SECONDS);
+    let device = matches.get_one::<String>("device");
+    let volume = matches.get_one::<f32>("volume").copied().unwrap_or(DEFAULT_VOLUME);
+    let volume_scale = *matches.get_one::<VolumeScale>("volume-scale").unwrap();
+    let gapless = !matches.get_flag("no-gapless");
+    let crossfade = matches.get_one::<f64>("crossfade").copied().unwrap_or(0.);
+    let start_at = matches.get_one::<f64>("start-at").copied();
+    let preview = matches.get_one::<f64>("preview").copied();
+
+    // which tags are shown can be chosen in the config file too, the command line wins
+    let chosen = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let classical = matches.get_flag("classical") && !chosen("tags") && !chosen("show-all-tags");
+    let tag_selection = if classical {
+        TagSelection::classical()
+    } else if matches.get_flag("show-all-tags") && !chosen("tags") {
+        TagSelection::all()
+    } else {
+        matches.get_one::<TagSelection>("tags").cloned().unwrap_or_default()
     };
 
-    ffmpeg::init().unwrap();
-    if let Ok(ref mut input) = ffmpeg::format::input(&path) {
-        println!("{}[Input]", " ".repeat(17)); 
+    // --autofill starts with it on, --library leaves it to the a key
+    let mut autofill = matches.contains_id("autofill");
+    let library = matches.get_one::<PathBuf>("autofill")
+        .or_else(|| matches.get_one::<PathBuf>("library"))
+        .cloned();
+    let strategy = *matches.get_one::<Strategy>("autofill-by").unwrap();
+    let listenbrainz_user = matches.get_one::<String>("listenbrainz").cloned();
+
+    // the number of tries is optional too
+    let reconnect = if matches.contains_id("reconnect") {
+        Some(Reconnect {
+            attempts: matches.get_one::<u32>("reconnect").copied().unwrap_or(DEFAULT_RECONNECT_ATTEMPTS),
+            delay: Duration::from_secs_f64(
+                matches.get_one::<f64>("reconnect-delay").unwrap().min(player::MAX_RECONNECT_DELAY.as_secs_f64())),
+        })
+    } else {
+        None
+    };
+
+    // the cutoff is optional
+    let highpass = if matches.contains_id("highpass") {
+        Some(matches.get_one::<f64>("highpass").copied().unwrap_or(DEFAULT_HIGHPASS_HZ))
+    } else {
+        None
+    };
+
+    let bindings = keys::Bindings::new(&config.keys)
+        .map_err(|reason| PlayerError::Config { path: config.path.clone(), reason })?;
+
+    let mut options = Options {
+        key_legend: bindings.legend(),
+        bindings: Arc::new(Mutex::new(bindings)),
+        profile: matches.get_one::<String>("profile").cloned(),
+        volume,
+        tag_selection,
+        filename_patterns: matches.get_one::<Patterns>("filename-patterns").cloned().unwrap_or_default(),
+        play_log: matches.get_one::<PathBuf>("play-log").cloned(),
+        play_log_format: matches.get_one::<String>("play-log-format").cloned(),
+        preview,
+        status_line_format: matches.get_one::<String>("status-line-format").cloned(),
+        mix_matrix: matches.get_one::<String>("mix-matrix").cloned(),
+        downmix: *matches.get_one::<Downmix>("channels").unwrap(),
+        deemphasis: matches.get_flag("deemphasis"),
+        highpass,
+        looping: matches.get_flag("loop"),
+        // --compressor starts from the night mode settings anyway
+        compressor: matches.get_one::<dsp::CompressorSettings>("compressor").copied()
+            .or_else(|| Some(dsp::NIGHT_MODE).filter(|_| matches.get_flag("night-mode"))),
+        delays: matches.get_one::<String>("delay").cloned(),
+        double_precision: matches.get_one::<String>("precision").map(String::as_str) == Some("64"),
+        quiet,
+    };
+
+    // playlists and tracks that couldn't be played
+    let mut failed = 0;
+
+    // playlist files are expanded in place
+    let mut queue = Vec::new();
+    for path in paths {
+        if stream::is_url(&path) || !playlist::is_playlist(&path) {
+            queue.push(Entry::new(path));
+            continue;
+        }
+        match playlist::read(&path) {
+            Ok(entries) => queue.extend(entries),
+            Err(source) => {
+                eprintln!("Error: {}", PlayerError::Playlist { path, source });
+                failed += 1;
+            }
+        }
+    }
+
+    ffmpeg::init().map_err(PlayerError::Init)?;
+    ffmpeg::format::network::init();
+
+    let mut player = Player::new(output_format, prime_seconds, frames_per_buffer, device.map(String::as_str))?;
+    if !player.has_device() && device.is_none() {
+        eprintln!("No output device found, playing to the null output. Nothing will be heard.\n");
+    }
+    player.set_volume(volume);
+    player.set_volume_scale(volume_scale);
+    player.set_reconnect(reconnect);
+
+    if !quiet {
+        println!("{}[Play Device]", " ".repeat(17));
+        println!("{:>16}: {}", 
+                "Driver", player.host_api_name());
+        println!("{:>16}: {}", 
+                "Output Device", player.device_name());
         println!("{:>16}: {}", 
-                "File Path", &path);
-        println!("{:>16}: {} ({})", 
-                "Container", input.format().name(), input.format().description());
+                "Sample Format", output_format.describe());
+        println!("{:>16}: {} frames", 
+                "Buffer Size", frames_per_buffer);
+    }
+
+    let (command_tx, command_rx) = mpsc::channel();
 
-        for (key, val) in input.metadata().iter() {
-            if METADATA_WHITELIST.contains(&key) {
-                println!("{:>16}: {}", key, val);
+    // SIGINT, when stdin isn't in raw mode. A second one doesn't wait for the fade.
+    let interrupt_tx = command_tx.clone();
+    let mut interrupted = false;
+    let handled = ctrlc::set_handler(move || {
+        if interrupted {
+            process::exit(130);
+        }
+        interrupted = true;
+        let _ = interrupt_tx.send(Command::Interrupt);
+    });
+    if let Err(e) = handled {
+        eprintln!("Error: could not handle Ctrl+C: {}", e);
+    }
+    config::watch(command_tx.clone());
+    let interactive = keys::spawn(command_tx, options.bindings.clone());
+    let mut screen = Screen::Lines { interactive };
+    #[cfg(feature = "tui")]
+    if matches.get_flag("tui") {
+        if !interactive {
+            eprintln!("Error: --tui needs a terminal, printing progress lines instead");
+        } else {
+            match tui::Tui::enter(options.key_legend.clone()) {
+                Ok(tui) => screen = Screen::Tui(tui),
+                Err(e) => eprintln!("Error: could not start the TUI: {}", e),
             }
         }
+    }
+
+    // previews are decoded ahead of time, there's nothing to follow on from
+    let gapless = gapless && preview.is_none();
+    if gapless {
+        player.set_crossfade(crossfade);
+    }
 
-        if let Some(ref stream) = input.streams().best(ffmpeg::media::Type::Audio) {
-            let stream_index = stream.index();
-            let start_pts = stream.start_time();
-            let duration_pts = stream.duration();
-            let duration_sec = duration_pts as f64 * f64::from(stream.time_base());
-
-            let codec = stream.codec();
-
-            println!("\n{}[Stream {}]", " ".repeat(17), stream.index());
-            println!("{:>16}: {:?} - {:?}", 
-                    "Type", codec.medium(), codec.id());
-            println!("{:>16}: {}", 
-                    "Time Base", stream.time_base());
-            println!("{:>16}: {} / {}", 
-                    "Start / Dur.", start_pts, duration_pts);
-            println!("{:>16}: {}", 
-                    "Decode Frames", stream.frames());
-
-            if let Ok(ref mut audio) = codec.decoder().audio() {
-                let file_sample_rate = audio.rate();
-
-                println!("{:>16}: {:.1} kbps (Max: {:.1} kbps)", 
-                    "Bit Rate", 
-                    audio.bit_rate() as f64 / 1000.,
-                    audio.max_bit_rate() as f64 / 1000.
-                );
-                println!("{:>16}: {:?}", 
-                        "Format", audio.format());
-                println!("{:>16}: {}", 
-                        "Sample Rate", file_sample_rate);
-                println!("{:>16}: {:?}", 
-                        "Channel Layout", audio.channel_layout());
-
-                let resample = !(audio.format() == SAMPLE_TYPE
-                    && (audio.channel_layout() & CHANNEL_LAYOUT) == CHANNEL_LAYOUT
-                    && audio.rate() as f64 == SAMPLE_RATE);
-
-                println!("\n{}[Resampler]", " ".repeat(17));
-                println!("{:>16}: {}", 
-                        "Enabled", resample);
-
-                if resample {
-                    println!("{:>16}: {:?} -> {:?}", 
-                            "Format", audio.format(), SAMPLE_TYPE);
-                    println!("{:>16}: {} -> {}", 
-                            "Sample Rate", file_sample_rate as f64, SAMPLE_RATE);
-                    println!("{:>16}: {} -> 2", 
-                            "Channels", audio.channels());                       
+    // the work last grouped under, with --classical
+    let mut work = if classical { Some(None) } else { None };
+
+    // play order, as indices into the queue, and what the rest of it is sorted by
+    let mut order: Vec<usize> = (0..queue.len()).collect();
+    let mut sort_key = SortKey::Original;
+    let mut group = None;
+
+    let mut library = library.map(|root| Library::new(root, strategy, options.filename_patterns.clone()));
+    let mut stats = SessionStats::default();
+
+    // a track that already started gaplessly at the end of the one before
+    let mut continued = None;
+    let mut i = 0;
+    while i < queue.len() {
+        let info = match continued.take() {
+            Some(info) => {
+                if !options.quiet && !screen.is_tui() {
+                    print_track(i, queue.len(), &info, work.as_mut(), (sort_key, &mut group));
+                }
+                info
+            }
+            None => {
+                let track = match open_track(&mut player, &queue[order[i]], &options, None) {
+                    Ok(track) => track,
+                    Err(e) => {
+                        eprintln!("Error: {}", e);
+                        failed += 1;
+                        i += 1;
+                        continue;
+                    }
+                };
+                if !options.quiet && !screen.is_tui() {
+                    print_track(i, queue.len(), &track.info, work.as_mut(), (sort_key, &mut group));
                 }
 
-                let mut swr: Option<ffmpeg::software::resampling::Context> = None;
-                if resample {
-                    swr = Some(
-                        ffmpeg::software::resampler(
-                            (audio.format(), audio.channel_layout(), file_sample_rate),
-                            (SAMPLE_TYPE, CHANNEL_LAYOUT, SAMPLE_RATE as u32),
-                        )
-                        .unwrap(),
-                    );
+                // only the first track starts part-way in
+                let start_at = if i == 0 { start_at } else { None };
+                start_track(&mut player, track, start_at, &options)?
+            }
+        };
+
+        // topped up before the last track, so it still goes on gaplessly, if
+        // the library is scanned by then
+        if autofill && i + 1 >= order.len() {
+            fill(library.as_mut().unwrap(), &info, &mut queue, &mut order, false);
+        }
+
+        #[cfg(feature = "tui")]
+        if let Screen::Tui(ref mut tui) = screen {
+            tui.set_queue(order[i + 1..].iter().map(|&j| queue::name(&queue[j])));
+        }
+
+        let next = order.get(i + 1).map(|&j| &queue[j]).filter(|_| gapless);
+        let mut requests = Requests::default();
+        let (ended, played) = play_track(&mut player, &info, next, &mut options, &command_rx, &mut screen, &mut requests)?;
+        stats.record(played.seconds, info.context.bit_rate, played.skipped);
+        match ended {
+            Ended::Finished => i += 1,
+            Ended::Continued(next) => {
+                continued = Some(next);
+                i += 1;
+            }
+            Ended::SkipNext => {
+                failed += 1;
+                i += 2;
+            }
+            Ended::Jump(key, forward) => i = queue::jump(&queue, &order, i, key, forward, &options.filename_patterns),
+            Ended::Quit => break,
+        }
+
+        if requests.toggle_autofill {
+            match library {
+                Some(ref library) => {
+                    autofill = !autofill;
+                    println!("{:>16}: {}",
+                            "Autofill", if autofill { format!("on, by {}", library.strategy.name()) } else { "off".to_string() });
                 }
+                None => println!("{:>16}: needs --autofill or --library <dir>", "Autofill"),
+            }
+        }
+        if requests.recommendations {
+            queue_recommendations(library.as_mut(), listenbrainz_user.as_deref(), &mut queue, &mut order);
+        }
+        // turned on during the last track, jumped past it, or the scan took
+        // longer than it did. Nothing is playing, so the scan is waited for.
+        if autofill && i >= order.len() {
+            fill(library.as_mut().unwrap(), &info, &mut queue, &mut order, true);
+        }
 
-                let pa = pa::PortAudio::new().unwrap();
-                let pa_settings = pa
-                    .default_output_stream_settings::<f32>(CHANNELS, SAMPLE_RATE, FRAMES_PER_BUFFER)
-                    .expect("Could not set output stream settings.");
-
-                println!("\n{}[Play Device]", " ".repeat(17));
-                let default_out = pa.device_info(pa.default_output_device().unwrap()).unwrap();
-                println!("{:>16}: {}", 
-                        "Driver", pa.host_api_info(default_out.host_api).unwrap().name);
-                println!("{:>16}: {}", 
-                        "Output Device", default_out.name);
-
-                let ringbuffer = ringbuf::RingBuffer::<f32>::new(BUFFER_SIZE);
-                let (mut rb_tx, mut rb_rx) = ringbuffer.split();
-
-                let mut status = Arc::new(PlayerStatus::new());
-
-                let status_cb = status.clone();
-                let status_o = status.clone();
-
-                let callback = move |pa::OutputStreamCallbackArgs { buffer, frames, .. }| {
-                    let recv_size = rb_rx.pop_slice(buffer);
-                    assert_eq!(recv_size % CHANNELS as usize, 0);
-
-                    let mut idx = 0;
-                    for _ in 0..frames {
-                        for _ in 0..CHANNELS {
-                            if idx >= recv_size {
-                                buffer[idx] = 0f32;
-                            } else {
-                                buffer[idx] *= GAIN;
-                            }
-                            idx += 1;
-                        }
+        if requests.sort > 0 {
+            for _ in 0..requests.sort {
+                sort_key = sort_key.cycle();
+            }
+            // a track that already took over gaplessly stays where it is
+            let first = if continued.is_some() { i + 1 } else { i };
+            let rest = &mut order[first.min(queue.len())..];
+            let groups = queue::sort(&queue, rest, sort_key, &options.filename_patterns);
+            print_queue(sort_key, rest.len(), &groups);
+        }
+    }
 
-                        status_cb.frames_played.fetch_add(1, SeqCst);
-                    }
+    // back on the main screen for the summary
+    #[cfg(feature = "tui")]
+    drop(screen);
+    if !quiet {
+        let status = player.status();
+        stats.print(status.underruns.load(Relaxed), status.bytes_read.load(Relaxed));
+    }
 
-                    if !status_cb.is_decoding.load(SeqCst) && rb_rx.is_empty() && recv_size == 0 {
-                        status_cb.is_playing.store(false, SeqCst);
-                        return pa::Complete;
-                    }
+    match failed {
+        0 => Ok(()),
+        count => Err(PlayerError::Skipped(count)),
+    }
+}
 
-                    pa::Continue
-                };
+/// How playback is shown.
+enum Screen {
+    /// Reports and a progress line under each track. Keys are read in raw mode
+    /// while a track plays if `interactive`.
+    Lines { interactive: bool },
+    /// `--tui`, which reads the keys itself.
+    #[cfg(feature = "tui")]
+    Tui(tui::Tui),
+}
+
+impl Screen {
+    fn is_tui(&self) -> bool {
+        !matches!(self, Self::Lines { .. })
+    }
+}
+
+/// Queue commands received while a track played, acted on once it's over.
+#[derive(Default)]
+struct Requests {
+    /// How many times the sort key was cycled.
+    sort: usize,
+    toggle_autofill: bool,
+    recommendations: bool,
+}
+
+/// Append tracks similar to `seed` to the queue, see `autofill::Library`.
+fn fill(library: &mut Library, seed: &TrackInfo, queue: &mut Vec<Entry>, order: &mut Vec<usize>, wait: bool) {
+    if wait && library.is_scanning() {
+        println!("\n{:>16}: waiting for the library scan", "Autofill");
+    }
+    let entries = library.similar(Path::new(&seed.path), &seed.tags, queue, wait);
+    if entries.is_empty() {
+        return;
+    }
+
+    println!("\n{}[Autofill]", " ".repeat(17));
+    for entry in &entries {
+        println!("{:>16}: {}",
+                format!("by {}", library.strategy.name()), entry.path.display());
+    }
+    append(entries, queue, order);
+}
+
+/// Append the tracks ListenBrainz recommends to `user` that are in the library.
+#[cfg(feature = "listenbrainz")]
+fn queue_recommendations(library: Option<&mut Library>, user: Option<&str>, queue: &mut Vec<Entry>, order: &mut Vec<usize>) {
+    println!("\n{}[Recommendations]", " ".repeat(17));
+    let (library, user) = match (library, user) {
+        (Some(library), Some(user)) => (library, user),
+        _ => {
+            println!("{:>16}: needs --listenbrainz <user> and --library <dir>", "Error");
+            return;
+        }
+    };
+
+    let ids = match listenbrainz::recommendations(user) {
+        Ok(ids) => ids,
+        Err(e) => {
+            println!("{:>16}: {}", "Error", e);
+            return;
+        }
+    };
+    let entries = match library.recordings(&ids, queue) {
+        Some(entries) => entries,
+        None => {
+            println!("{:>16}: the library is still being scanned, try again shortly", "Error");
+            return;
+        }
+    };
+    println!("{:>16}: {} recommended, {} of them in the library and not queued",
+            "ListenBrainz", ids.len(), entries.len());
+    for entry in &entries {
+        println!("{:>16}: {}",
+                "Queued", entry.path.display());
+    }
+    append(entries, queue, order);
+}
+
+#[cfg(not(feature = "listenbrainz"))]
+fn queue_recommendations(_: Option<&mut Library>, _: Option<&str>, _: &mut Vec<Entry>, _: &mut Vec<usize>) {
+    println!("\n{}[Recommendations]", " ".repeat(17));
+    println!("{:>16}: built without the listenbrainz feature", "Error");
+}
+
+/// Add `entries` to the end of the queue and of the play order.
+fn append(entries: Vec<Entry>, queue: &mut Vec<Entry>, order: &mut Vec<usize>) {
+    order.extend(queue.len()..queue.len() + entries.len());
+    queue.extend(entries);
+}
+
+/// Per-track settings from the command line.
+struct Options {
+    /// What the keys do, shown above the progress line.
+    key_legend: String,
+    /// Shared with the thread reading the keys, so a changed config file can rebind them.
+    bindings: Arc<Mutex<keys::Bindings>>,
+    /// `--profile`, taken again when the config file changes.
+    profile: Option<String>,
+    /// The volume set at the start, or by the config file since.
+    volume: f32,
+    tag_selection: TagSelection,
+    /// For guessing the tags of untagged files.
+    filename_patterns: Patterns,
+    play_log: Option<PathBuf>,
+    play_log_format: Option<String>,
+    preview: Option<f64>,
+    status_line_format: Option<String>,
+    mix_matrix: Option<String>,
+    downmix: Downmix,
+    deemphasis: bool,
+    highpass: Option<f64>,
+    /// Repeat each track, between its loop points if it has them.
+    looping: bool,
+    /// `--night-mode` or `--compressor`.
+    compressor: Option<dsp::CompressorSettings>,
+    delays: Option<String>,
+    double_precision: bool,
+    /// Only progress and errors are printed, not the reports.
+    quiet: bool,
+}
 
-                let mut pa_stream = pa.open_non_blocking_stream(pa_settings, callback)
-                    .expect("Could not open output device.");
+/// A track opened and set up to play, see `open_track()`.
+struct Track {
+    decoder: Decoder,
+    chain: Box<dyn dsp::Process>,
+    info: TrackInfo,
+}
+
+/// What's kept of a track while it plays, for the status line and play log.
+struct TrackInfo {
+    path: String,
+    tags: tags::Tags,
+    context: TrackContext,
+    duration: f64,
+    // the [Input], [Stream], [Resampler] and [DSP] sections, printed when it starts
+    report: Vec<String>,
+}
+
+/// How `play_track()` ended.
+enum Ended {
+    Finished,
+    /// The next track was queued gaplessly and has taken over.
+    Continued(TrackInfo),
+    /// The next track couldn't be opened to queue it, and was reported.
+    SkipNext,
+    /// Skipped to the next or previous (`false`) album or artist, grouped by the key.
+    Jump(SortKey, bool),
+    Quit,
+}
+
+/// How much of a track `play_track()` played, for the session statistics.
+struct Played {
+    seconds: f64,
+    /// Left early for the next track, album or artist.
+    skipped: bool,
+}
 
-                let mut decode_frame = ffmpeg::frame::Audio::empty();
-                let mut swr_frame = ffmpeg::frame::Audio::empty();
+/// Print the header and report for track `i` of `count`.
+///
+/// With `--classical`, consecutive movements of a work are grouped under a
+/// header naming it, `work` being the one printed last. Likewise for the groups
+/// of a sorted queue, and the last `group`.
+fn print_track(
+    i: usize,
+    count: usize,
+    info: &TrackInfo,
+    work: Option<&mut Option<String>>,
+    (sort_key, group): (SortKey, &mut Option<String>),
+) {
+    let current = sort_key.group(&info.tags);
+    if current.is_some() && current != *group {
+        println!("\n{}[{}]", " ".repeat(17), current.as_deref().unwrap_or_default());
+    }
+    *group = current;
 
-                if pa_stream.start().is_ok() {
-                    status.is_playing.store(true, SeqCst);
-                } else {
-                    panic!("Play failed!");
+    if let Some(work) = work {
+        let current = info.tags.work();
+        if current.is_some() && current != *work {
+            println!("\n{}[Work]", " ".repeat(17));
+            for line in tags::format_tag("Work", current.as_deref().unwrap_or_default()) {
+                println!("{}", line);
+            }
+            let performers = ["performer", "conductor", "ensemble"].iter()
+                .flat_map(|key| info.tags.values(key))
+                .collect::<Vec<_>>();
+            if !performers.is_empty() {
+                for line in tags::format_tag("Performed By", &performers.join(", ")) {
+                    println!("{}", line);
                 }
+            }
+        }
+        *work = current;
+    }
 
-                let othread_handle = std::thread::spawn(move || {
-                    println!(
-                        "\n  DECODE  PLAYPOS DURATION"
-                    );
-                    while status_o.is_playing.load(Relaxed) {
-                        print!(
-                            "\r{:>7.1}s {:>7.1}s {:>7.1}s  [PLAYING]",
-                            status_o.frames_decoded.load(Relaxed) as f64 / SAMPLE_RATE,
-                            status_o.frames_played.load(Relaxed) as f64 / SAMPLE_RATE,
-                            duration_sec
-                        );
-                        let _ = io::stdout().flush();
-
-                        sleep(100_000).unwrap();
-                    }
-                    print!("\n");
-                });
-
-                let mut packets = input.packets();
-                while let Some(Ok((read_stream, read_packet))) = packets.next() {
-                    if read_stream.index() == stream_index {
-                        match audio.decode(&read_packet, &mut decode_frame) {
-                            Ok(true) => {
-                                let ts = decode_frame.timestamp();
-                                decode_frame.set_pts(ts);
-
-                                if resample {
-                                    if swr.as_mut().unwrap().run(&decode_frame, &mut swr_frame).is_ok() {
-                                        send_audio(&mut swr_frame, &mut rb_tx, &mut status);
-                                        let _ = status.is_decoding
-                                            .compare_exchange_weak(false, true, SeqCst, Relaxed);
-                                    }
-                                } else {
-                                    send_audio(&mut decode_frame, &mut rb_tx, &mut status);
-                                    let _ = status.is_decoding
-                                        .compare_exchange_weak(false, true, SeqCst, Relaxed);
-                                }
-                            }
-                            Ok(_) => (),
-                            Err(e) => eprintln!("Error: {:?}", e),
+    println!();
+    if count > 1 {
+        println!("{}[Track {}/{}]", " ".repeat(17), i + 1, count);
+    }
+    for line in &info.report {
+        println!("{}", line);
+    }
+}
+
+/// What the rest of the queue was sorted by, and its groups.
+fn print_queue(sort_key: SortKey, count: usize, groups: &[(String, usize)]) {
+    println!("\n{}[Queue]", " ".repeat(17));
+    println!("{:>16}: {} ({} more tracks)",
+            "Sorted By", sort_key.name(), count);
+    for (group, tracks) in groups {
+        println!("{:>16}: {}",
+                format!("{} track{}", tracks, if *tracks == 1 { "" } else { "s" }), group);
+    }
+}
+
+/// Open `entry` and set up its DSP chain, or say why it can't be played.
+///
+/// It's decoded at `rate` if given, otherwise at its own rate if the device
+/// supports it.
+fn open_track(player: &mut Player, entry: &Entry, options: &Options, rate: Option<u32>) -> Result<Track, PlayerError> {
+    let path = entry.path.to_string_lossy();
+    let path = path.as_ref();
+    let unplayable = |reason: String| PlayerError::Unplayable { path: entry.path.clone(), reason };
+
+    let opened = match player.take_decoder() {
+        Some(previous) => previous.open_next(Path::new(path)),
+        None => Decoder::open(Path::new(path)),
+    };
+    let mut decoder = opened.map_err(|e| PlayerError::unplayable(&entry.path, &e))?;
+
+    let rate = rate.unwrap_or_else(|| player.output_rate_for(decoder.audio().rate()));
+    decoder.set_output_rate(rate)
+        .map_err(|e| unplayable(format!("could not resample to {} Hz: {}", rate, e)))?;
+
+    if let Some(ref spec) = options.mix_matrix {
+        Mixer::parse(spec, usize::from(decoder.audio().channels()))
+            .and_then(|mixer| decoder.set_mixer(mixer).map_err(|e| e.to_string()))
+            .map_err(|e| unplayable(format!("--mix-matrix: {}", e)))?;
+    } else {
+        let layout = input_layout(decoder.audio());
+        let channels = usize::from(decoder.audio().channels());
+        // stereo files are only mixed when everything is played as mono
+        if options.downmix != Downmix::Stereo && (channels != CHANNELS as usize || options.downmix == Downmix::Mono) {
+            decoder.set_mixer(Mixer::for_layout(layout, channels, options.downmix))
+                .map_err(|e| unplayable(format!("could not set up the {} mix: {}", options.downmix.name(), e)))?;
+        }
+    }
+
+    let mut report = Vec::new();
+    let input = decoder.input();
+    report.push(format!("{}[Input]", " ".repeat(17))); 
+    let url = stream::is_url(&entry.path);
+    report.push(format!("{:>16}: {}", 
+            if url { "URL" } else { "File Path" }, path));
+    if let Some(station) = stream::icy_header(input, "icy-name").filter(|_| url) {
+        report.push(format!("{:>16}: {}", 
+                "Station", station));
+    }
+    if let Some(ref title) = entry.title {
+        report.push(format!("{:>16}: {}", 
+                "Playlist Title", title));
+    }
+    report.push(format!("{:>16}: {} ({})", 
+            "Container", input.format().name(), input.format().description()));
+
+    let tags = tags::read_tags(input, Path::new(path), &options.filename_patterns);
+    for (label, val) in tags.shown(&options.tag_selection) {
+        if tags.inferred {
+            report.extend(tags::format_tag(label, &format!("{} (from filename)", val)));
+        } else {
+            report.extend(tags::format_tag(label, &val));
+        }
+    }
+
+    // the whole track loops if the file doesn't say where
+    let source_rate = decoder.audio().rate();
+    let loop_points = if options.looping { loops::read(&entry.path, &tags) } else { None };
+    if let Some(points) = loop_points {
+        let (start, end) = points.seconds(source_rate);
+        report.push(format!("{:>16}: {} - {} ({} - {})",
+                "Loop", points.start, points.end, clock(start), clock(end)));
+    }
+
+    let stream = decoder.stream();
+    let parameters = stream.parameters();
+    let duration_sec = decoder.duration();
+
+    report.push(format!("\n{}[Stream {}]", " ".repeat(17), stream.index()));
+    report.push(format!("{:>16}: {:?} - {:?}", 
+            "Type", parameters.medium(), parameters.id()));
+    report.push(format!("{:>16}: {}", 
+            "Time Base", stream.time_base()));
+    report.push(format!("{:>16}: {} / {}", 
+            "Start / Dur.", stream.start_time(), stream.duration()));
+    report.push(format!("{:>16}: {}", 
+            "Decode Frames", stream.frames()));
+
+    let audio = decoder.audio();
+    report.push(format!("{:>16}: {:.1} kbps (Max: {:.1} kbps)", 
+        "Bit Rate", 
+        decoder.bit_rate() as f64 / 1000.,
+        audio.max_bit_rate() as f64 / 1000.
+    ));
+    report.push(format!("{:>16}: {:?}", 
+            "Format", audio.format()));
+    report.push(format!("{:>16}: {}", 
+            "Sample Rate", audio.rate()));
+    report.push(format!("{:>16}: {:?}", 
+            "Channel Layout", audio.channel_layout()));
+    if decoder.is_reused() {
+        report.push(format!("{:>16}: reused from the previous track", 
+                "Codec Context"));
+    }
+
+    let resampler = decoder.resampler();
+
+    report.push(format!("\n{}[Resampler]", " ".repeat(17)));
+    report.push(format!("{:>16}: {}", 
+            "Enabled", resampler.is_some()));
+
+    if let Some(resampler) = resampler {
+        report.push(format!("{:>16}: {:?} -> {:?}", 
+                "Format", audio.format(), SAMPLE_TYPE));
+        report.push(format!("{:>16}: {} -> {}", 
+                "Sample Rate", audio.rate(), resampler.output_rate()));
+        report.push(format!("{:>16}: {} -> {}", 
+                "Channels", audio.channels(), CHANNELS));
+        if let Some(mixer) = resampler.mixer() {
+            report.push(format!("{:>16}: {}", 
+                    "Mix", mixer.name()));
+            report.push(format!("{:>16}: {:?} -> {}", 
+                    "Layout", input_layout(audio), mixer.describe()));
+        }
+    }
+
+    let chain: Box<dyn dsp::Process> = if options.double_precision {
+        Box::new(build_chain::<f64>(input, options, f64::from(rate)).map_err(unplayable)?)
+    } else {
+        Box::new(build_chain::<f32>(input, options, f64::from(rate)).map_err(unplayable)?)
+    };
+
+    if !chain.is_empty() {
+        report.push(format!("\n{}[DSP]", " ".repeat(17)));
+        report.push(format!("{:>16}: {}", 
+                "Precision", if options.double_precision { "f64" } else { "f32" }));
+        for (i, name) in chain.names().iter().enumerate() {
+            report.push(format!("{:>16}: {}", 
+                    format!("Stage {}", i + 1), name));
+        }
+    }
+
+    if options.looping {
+        match loop_points {
+            Some(points) => {
+                let (start, end) = points.seconds(source_rate);
+                decoder.set_loop(start, Some(end));
+            }
+            None => decoder.set_loop(0., None),
+        }
+    }
+
+    let context = TrackContext::new(path, &tags, &decoder);
+    let info = TrackInfo {
+        path: path.to_string(),
+        tags,
+        context,
+        duration: duration_sec,
+        report,
+    };
+    Ok(Track { decoder, chain, info })
+}
+
+/// Start playing `track` from the top, or from `start_at`.
+fn start_track(player: &mut Player, track: Track, start_at: Option<f64>, options: &Options) -> Result<TrackInfo, PlayerError> {
+    let Track { mut decoder, mut chain, info } = track;
+
+    match options.preview {
+        // previews are decoded up front so they can be normalized before playing
+        Some(seconds) => {
+            if info.duration > 0. {
+                if let Err(e) = decoder.seek(info.duration * PREVIEW_POSITION) {
+                    eprintln!("Error: could not seek for preview: {}", e);
+                }
+            }
+            let excerpt = decode_excerpt(&mut decoder, chain.as_mut(), seconds);
+            player.play_samples(excerpt, decoder.output_rate())?;
+        }
+        None => {
+            if let Some(seconds) = start_at {
+                if let Err(e) = decoder.seek(seconds) {
+                    eprintln!("Error: could not seek to {}: {}", clock(seconds), e);
+                }
+            }
+            player.play(decoder, chain)?;
+        }
+    }
+
+    Ok(info)
+}
+
+/// Follow one track to its end, or until skipped. With `next`, that track is
+/// opened and queued behind this one once it's done decoding, so it starts
+/// without a gap.
+fn play_track(
+    player: &mut Player,
+    info: &TrackInfo,
+    next: Option<&Entry>,
+    options: &mut Options,
+    command_rx: &Receiver<Command>,
+    screen: &mut Screen,
+    requests: &mut Requests,
+) -> Result<(Ended, Played), PlayerError> {
+    let started_at = SystemTime::now();
+    let status = player.status();
+    let number = status.track_number();
+    let first_frame = status.track_start();
+
+    let raw_mode = match *screen {
+        Screen::Lines { interactive: true } => keys::RawMode::enable(),
+        _ => None,
+    };
+    // raw mode doesn't return the cursor on \n
+    let eol = if raw_mode.is_some() || screen.is_tui() { "\r" } else { "" };
+
+    let status_o = player.status();
+    let track_o = info.context.clone();
+    let duration_sec = info.duration;
+    let status_line_format = options.status_line_format.clone();
+    let key_legend = options.key_legend.clone();
+    let lines = !screen.is_tui();
+    let othread_handle = std::thread::spawn(move || {
+        if !lines {
+            return;
+        }
+
+        let current = || status_o.is_playing.load(Relaxed) && status_o.track_number() == number;
+
+        if let Some(format) = status_line_format {
+            // one line per change, which is what waybar/polybar modules read
+            let mut last = String::new();
+            while current() {
+                let line = track_o.render(&format, Some(&status_o));
+                if line != last {
+                    println!("{}{}", line, eol);
+                    let _ = io::stdout().flush();
+                    last = line;
+                }
+
+                sleep(100_000).unwrap();
+            }
+            return;
+        }
+
+        println!("{}", eol);
+        println!(
+            "  DECODE  PLAYPOS DURATION VOLUME{}{}",
+            if eol.is_empty() { String::new() } else { format!("  ({})", key_legend) },
+            eol
+        );
+        // internet radio has no end, and names each song as it starts
+        let duration = if duration_sec > 0. { format!("{:>7.1}s", duration_sec) } else { format!("{:>8}", "live") };
+        let mut title = None;
+        while current() {
+            let now = status_o.stream_title.lock().unwrap().clone();
+            if now.is_some() && now != title {
+                print!("\r{}\r", " ".repeat(50));
+                println!("{:>16}: {}{}", "Now Playing", now.as_deref().unwrap_or_default(), eol);
+                title = now;
+            }
+
+            print!(
+                "\r{:>7.1}s {:>7.1}s {} {:>6}  [{}]",
+                status_o.decoded(),
+                status_o.position(),
+                duration,
+                status_o.describe_volume(),
+                status_o.state()
+            );
+            let _ = io::stdout().flush();
+
+            sleep(100_000).unwrap();
+        }
+        println!("{}", eol);
+    });
+
+    let mut ended = Ended::Finished;
+    let mut skipped = false;
+    let mut queued = None;
+    let current = || status.is_playing.load(Relaxed) && status.track_number() == number;
+    while current() {
+        if queued.is_none() && !status.is_decoding.load(Relaxed) {
+            let track = match (next, &ended) {
+                (Some(entry), Ended::Finished) => {
+                    // resampled to the current rate if need be, changing it would mean a gap
+                    let rate = player.sample_rate();
+                    match open_track(player, entry, options, Some(rate)) {
+                        Ok(track) => Some(track),
+                        Err(e) => {
+                            eprintln!("Error: {}{}", e, eol);
+                            ended = Ended::SkipNext;
+                            None
                         }
                     }
                 }
-
-                if resample && swr.as_ref().unwrap().delay().is_some() {
-                    while let Ok(Some(_)) = swr.as_mut().unwrap().flush(&mut swr_frame) {
-                        send_audio(&mut swr_frame, &mut rb_tx, &mut status);
-                        let _ = status.is_decoding.compare_exchange_weak(false, true, SeqCst, Relaxed);
+                _ => None,
+            };
+            match track {
+                // if this track ran out first, the main loop opens the next one again
+                Some(track) => {
+                    if !player.play_next(track.decoder, track.chain) {
+                        break;
                     }
+                    queued = Some(track.info);
                 }
+                // nothing follows, so the end held back for a crossfade plays as it is
+                None => player.play_out(),
+            }
+        }
 
-                status.is_decoding.store(false, Relaxed);
-                while status.is_playing.load(Relaxed) {
-                    sleep(1_000_000).unwrap();
+        if let Ok(command) = command_rx.recv_timeout(COMMAND_POLL_INTERVAL) {
+            match command {
+                Command::Stop | Command::Interrupt => ended = Ended::Quit,
+                // stopping the stream drops whatever was queued behind this track
+                Command::Next => {
+                    queued = None;
+                    skipped = true;
                 }
+                // applied once this track is over
+                Command::CycleSort => requests.sort += 1,
+                Command::ToggleAutofill => requests.toggle_autofill = !requests.toggle_autofill,
+                Command::QueueRecommendations => requests.recommendations = true,
+                Command::NextAlbum => ended = Ended::Jump(SortKey::Album, true),
+                Command::PreviousAlbum => ended = Ended::Jump(SortKey::Album, false),
+                Command::NextArtist => ended = Ended::Jump(SortKey::Artist, true),
+                Command::PreviousArtist => ended = Ended::Jump(SortKey::Artist, false),
+                Command::ConfigChanged => match reload_config(player, options) {
+                    Ok(()) => {
+                        #[cfg(feature = "tui")]
+                        if let Screen::Tui(ref mut tui) = screen {
+                            tui.set_legend(options.key_legend.clone());
+                        }
+                        if !options.quiet && !screen.is_tui() {
+                            print!("\r{}\r", " ".repeat(50));
+                            println!("{:>16}: reloaded, the status line changes from the next track{}", "Config", eol);
+                        }
+                    }
+                    Err(e) => eprintln!("Error: {}, keeping the settings from before{}", e, eol),
+                },
+                _ => (),
+            }
+            if let Ended::Jump(..) = ended {
+                skipped = true;
+            }
+            if let Err(e) = player.apply(command) {
+                eprintln!("Error: {}{}", e, eol);
+            }
+        }
 
-                othread_handle.join().unwrap();
+        // a frame that couldn't be drawn is made up by the next one
+        #[cfg(feature = "tui")]
+        if let Screen::Tui(ref mut tui) = screen {
+            let _ = tui.draw(info, &status);
+        }
+    }
 
-                pa_stream.stop().unwrap();
-                pa_stream.close().unwrap();
-            }
+    let continued = status.track_number() != number;
+    let waited = if continued { Ok(()) } else { player.wait() };
+    othread_handle.join().unwrap();
+    drop(raw_mode);
+    waited?;
+
+    // a track that was followed gaplessly ended where the next one started
+    let played = if continued {
+        (status.track_start() - first_frame) as f64 / status.sample_rate()
+    } else {
+        status.played()
+    };
+
+    if let Some(ref log) = options.play_log {
+        let record = playlog::PlayRecord {
+            start: started_at,
+            // the callback's frame count is the audible timeline, unlike wall clock here
+            end: started_at + Duration::from_secs_f64(played),
+            played,
+            duration: info.duration,
+            path: info.path.clone(),
+            artist: info.tags.get("artist").map(str::to_string),
+            title: info.tags.get("title").map(str::to_string),
+            album: info.tags.get("album").map(str::to_string),
+            device: player.device_name().to_string(),
+        };
+        let written = match options.play_log_format {
+            Some(ref format) => playlog::append_line(log, &info.context.render(format, Some(&status))),
+            None => playlog::append(log, &record),
+        };
+        if let Err(e) = written {
+            eprintln!("Error: could not write play log: {}", e);
+        }
+    }
+
+    let ended = match (ended, queued) {
+        (Ended::Quit, _) => Ended::Quit,
+        (Ended::Jump(key, forward), _) => Ended::Jump(key, forward),
+        (_, Some(info)) if continued => Ended::Continued(info),
+        (ended, _) => ended,
+    };
+    Ok((ended, Played { seconds: played, skipped }))
+}
+
+/// Take the settings that can change while playing from the config file again:
+/// the volume, how the volume keys step, the key bindings and the status line.
+/// Options on the command line still override them.
+fn reload_config(player: &mut Player, options: &mut Options) -> Result<(), PlayerError> {
+    let mut config = config::load()?;
+    if let Some(ref profile) = options.profile {
+        config.use_profile(profile)?;
+    }
+    let config_error = |reason: String| PlayerError::Config { path: config.path.clone(), reason };
+    // the command line is parsed again, for the settings as they'd be at launch
+    let matches = config.apply(cli::command()).try_get_matches()
+        .map_err(|e| config_error(e.to_string().lines().next().unwrap_or_default().trim_start_matches("error: ").to_string()))?;
+    let bindings = keys::Bindings::new(&config.keys).map_err(config_error)?;
+
+    // the volume keys may have moved it since, so it's only set when the setting changed
+    let volume = matches.get_one::<f32>("volume").copied().unwrap_or(DEFAULT_VOLUME);
+    if volume != options.volume {
+        player.set_volume(volume);
+        options.volume = volume;
+    }
+    player.set_volume_scale(*matches.get_one::<VolumeScale>("volume-scale").unwrap());
+    options.key_legend = bindings.legend();
+    *options.bindings.lock().unwrap() = bindings;
+    options.status_line_format = matches.get_one::<String>("status-line-format").cloned();
+    Ok(())
+}
+
+/// DSP stages for the track and flags, computing in `T`, or why the flags
+/// don't work at `rate`.
+fn build_chain<T: dsp::Sample>(input: &Input, options: &Options, rate: f64) -> Result<dsp::Chain<T>, String> {
+    let mut chain = dsp::Chain::new();
+    if let Some(db) = tags::track_gain_db(input) {
+        chain.push(Box::new(dsp::Gain::new(db)));
+    }
+    if options.deemphasis || tags::has_pre_emphasis(input) {
+        chain.push(Box::new(dsp::Deemphasis::new(rate, CHANNELS as usize)));
+    }
+    if let Some(cutoff) = options.highpass {
+        if cutoff >= rate / 2. {
+            return Err(format!("--highpass {} Hz is at or above the Nyquist frequency at {} Hz", cutoff, rate));
         }
+        chain.push(Box::new(dsp::Biquad::highpass(
+            rate, cutoff, std::f64::consts::FRAC_1_SQRT_2, CHANNELS as usize)));
     }
+    if let Some(settings) = options.compressor {
+        chain.push(Box::new(dsp::Compressor::new(settings, rate, CHANNELS as usize)));
+    }
+    if let Some(ref spec) = options.delays {
+        // --delay was only checked at 48 kHz
+        let delays = parse_delays(spec, rate).map_err(|e| format!("--delay at {} Hz: {}", rate, e))?;
+        chain.push(Box::new(dsp::Delay::new(&delays)));
+    }
+    Ok(chain)
 }
 
-#[inline]
-fn send_audio(audio_frame: &mut Audio, rb_tx: &mut Producer<f32>, status: &mut Arc<PlayerStatus>) {
-    // void* arrays in C makes me unsafe :(
-    let (head, data, tail) = unsafe { audio_frame.data(0).align_to::<f32>() };
 
-    assert!(head.is_empty() && tail.is_empty());
+/// Parse `L:0,R:1.5` (milliseconds, or with a `samples` suffix) into per-channel
+/// sample delays at `rate`.
+fn parse_delays(spec: &str, rate: f64) -> Result<Vec<usize>, String> {
+    let mut delays = vec![0; CHANNELS as usize];
+
+    for part in spec.split(',') {
+        let (channel, amount) = match part.split_once(':') {
+            Some(split) => split,
+            None => return Err(format!("expected <channel>:<delay>, got \"{}\"", part)),
+        };
+
+        let index = match channel.trim().to_uppercase().as_str() {
+            "L" => 0,
+            "R" => 1,
+            n => match n.parse::<usize>() {
+                Ok(n) if n >= 1 => n - 1,
+                _ => return Err(format!("unknown channel \"{}\"", channel)),
+            },
+        };
+        if index >= delays.len() {
+            return Err(format!("channel \"{}\" out of range", channel));
+        }
+
+        let amount = amount.trim();
+        let delay = if let Some(samples) = amount.strip_suffix("samples") {
+            samples.trim().parse::<usize>().map_err(|e| e.to_string())?
+        } else {
+            let ms = amount.trim_end_matches("ms").parse::<f64>().map_err(|e| e.to_string())?;
+            if !ms.is_finite() {
+                return Err(format!("\"{}\" is not a number of milliseconds", amount));
+            }
+            if ms < 0. {
+                return Err("delays can't be negative".to_string());
+            }
+            (ms / 1000. * rate).round() as usize
+        };
+        if delay as f64 > MAX_DELAY_SECONDS * rate {
+            return Err(format!("\"{}\" is longer than the most a channel can be delayed, {} ms",
+                    amount, MAX_DELAY_SECONDS * 1000.));
+        }
+        delays[index] = delay;
+    }
+
+    Ok(delays)
+}
+
+/// Decode `seconds` of output-format audio from the current position, peak-normalized.
+fn decode_excerpt(decoder: &mut Decoder, chain: &mut dyn dsp::Process, seconds: f64) -> Vec<f32> {
+    let limit = (seconds * f64::from(decoder.output_rate())) as usize * CHANNELS as usize;
+    let mut excerpt = Vec::with_capacity(limit);
 
-    let mut sent_size = 0;
-    while sent_size < data.len() {
-        if sent_size > 0 {
-            sleep(10_000).unwrap();
+    while excerpt.len() < limit {
+        match decoder.next_samples() {
+            Some(samples) => excerpt.extend_from_slice(chain.run(samples)),
+            None => break,
         }
+    }
+    excerpt.truncate(limit);
 
-        let current_size = rb_tx.push_slice(&data[sent_size..]);
-        sent_size += current_size;
+    let peak = excerpt.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+    if peak > 0. {
+        let scale = PREVIEW_PEAK / peak;
+        excerpt.iter_mut().for_each(|s| *s *= scale);
+    }
 
-        assert_eq!(sent_size % CHANNELS as usize, 0);
+    excerpt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays() {
+        assert_eq!(parse_delays("L:10ms", 48_000.), Ok(vec![480, 0]));
+        assert_eq!(parse_delays("r:12 samples, 1:0.5", 48_000.), Ok(vec![24, 12]));
+    }
+
+    #[test]
+    fn bad_delays() {
+        for spec in ["L", "C:1", "3:1", "0:1", "L:-1", "L:inf", "L:NaN", "L:1001ms",
+                     "R:48001samples", "R:-1samples", "L:1e300"] {
+            assert!(parse_delays(spec, 48_000.).is_err(), "{}", spec);
+        }
+    }
 
-        status.frames_decoded.fetch_add(current_size / CHANNELS as usize, Relaxed);
+    #[test]
+    fn delay_limit_follows_the_rate() {
+        assert!(parse_delays("R:46000samples", 48_000.).is_ok());
+        assert!(parse_delays("R:46000samples", 44_100.).is_err());
+        assert_eq!(parse_delays("L:1000ms", 44_100.), Ok(vec![44_100, 0]));
     }
 }
@@ -0,0 +1,148 @@
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering::Relaxed};
+use std::time::{Duration, Instant};
+
+const FLOOR_DB: f32 = -60.;
+const BAR_WIDTH: usize = 40;
+const PEAK_HOLD: Duration = Duration::from_millis(1500);
+
+/// Per-channel peak levels shared between the audio callback and the display.
+pub struct Levels {
+    peaks: Vec<AtomicU32>,
+    clips: Vec<AtomicUsize>,
+}
+
+impl Levels {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            peaks: (0..channels).map(|_| AtomicU32::new(0)).collect(),
+            clips: (0..channels).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+
+    /// Record an interleaved buffer, called from the audio callback.
+    pub fn update(&self, buffer: &[f32]) {
+        let channels = self.peaks.len();
+        for (channel, (peak, clips)) in self.peaks.iter().zip(&self.clips).enumerate() {
+            let mut max = f32::from_bits(peak.load(Relaxed));
+            for sample in buffer.iter().skip(channel).step_by(channels) {
+                let level = sample.abs();
+                if level >= 1. {
+                    clips.fetch_add(1, Relaxed);
+                }
+                max = max.max(level);
+            }
+            peak.store(max.to_bits(), Relaxed);
+        }
+    }
+
+    /// Peak since the last call, per channel.
+    fn take_peaks(&self) -> Vec<f32> {
+        self.peaks.iter().map(|p| f32::from_bits(p.swap(0, Relaxed))).collect()
+    }
+
+    fn clips(&self, channel: usize) -> usize {
+        self.clips[channel].load(Relaxed)
+    }
+}
+
+// Partial cells for the Unicode bars, in eighths.
+const EIGHTHS: [char; 8] = [' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BarStyle {
+    /// Block characters with 1/8 cell resolution.
+    Unicode,
+    Ascii,
+}
+
+impl BarStyle {
+    /// Unicode unless the terminal is dumb or the locale isn't UTF-8.
+    pub fn detect() -> Self {
+        let dumb = std::env::var("TERM").map_or(true, |term| term == "dumb");
+        let utf8 = ["LC_ALL", "LC_CTYPE", "LANG"].iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|val| !val.is_empty())
+            .is_some_and(|val| {
+                let val = val.to_lowercase();
+                val.contains("utf-8") || val.contains("utf8")
+            });
+
+        if dumb || !utf8 {
+            Self::Ascii
+        } else {
+            Self::Unicode
+        }
+    }
+}
+
+/// Terminal renderer with peak hold, one line per channel.
+pub struct Meter {
+    held: Vec<(f32, Instant)>,
+    style: BarStyle,
+}
+
+impl Meter {
+    pub fn new(channels: usize, style: BarStyle) -> Self {
+        Self {
+            held: vec![(FLOOR_DB, Instant::now()); channels],
+            style,
+        }
+    }
+
+    pub fn lines(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn render(&mut self, levels: &Levels) -> Vec<String> {
+        let now = Instant::now();
+
+        levels.take_peaks().into_iter().enumerate()
+            .map(|(channel, peak)| {
+                let db = to_dbfs(peak);
+                let held = &mut self.held[channel];
+                if db >= held.0 || now.duration_since(held.1) > PEAK_HOLD {
+                    *held = (db, now);
+                }
+
+                let label = match (self.held.len(), channel) {
+                    (2, 0) => "L".to_string(),
+                    (2, 1) => "R".to_string(),
+                    (_, channel) => (channel + 1).to_string(),
+                };
+
+                format!("{:>3} [{}] {:>6.1} dBFS  peak {:>6.1}  clip {}",
+                        label, bar(db, self.held[channel].0, self.style), db, self.held[channel].0,
+                        levels.clips(channel))
+            })
+            .collect()
+    }
+}
+
+fn to_dbfs(level: f32) -> f32 {
+    if level > 0. {
+        (20. * level.log10()).max(FLOOR_DB)
+    } else {
+        FLOOR_DB
+    }
+}
+
+fn bar(db: f32, held_db: f32, style: BarStyle) -> String {
+    // in eighths of a cell
+    let position = |db: f32| ((db - FLOOR_DB) / -FLOOR_DB * (BAR_WIDTH * 8) as f32).round() as usize;
+    let level = position(db).min(BAR_WIDTH * 8);
+    let held = position(held_db).div_ceil(8).min(BAR_WIDTH);
+
+    (0..BAR_WIDTH)
+        .map(|i| {
+            let eighths = level.saturating_sub(i * 8).min(8);
+            match style {
+                BarStyle::Unicode if eighths == 8 => '█',
+                BarStyle::Unicode if eighths > 0 => EIGHTHS[eighths],
+                BarStyle::Ascii if eighths >= 4 => '#',
+                _ if i + 1 == held && held * 8 > level + 4 => '|',
+                BarStyle::Unicode => ' ',
+                BarStyle::Ascii => '-',
+            }
+        })
+        .collect()
+}
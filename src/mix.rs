@@ -0,0 +1,250 @@
+use ffmpeg::ChannelLayout;
+
+use std::f32::consts::FRAC_1_SQRT_2;
+
+/// How sources that aren't stereo are mixed to it, `--channels`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Downmix {
+    /// Every speaker to the side it's on, LFE kept at -6 dB for 2.1 music.
+    Stereo,
+    /// ITU-R BS.775: centre and surrounds at -3 dB, LFE dropped.
+    Itu,
+    /// Only the front speakers, centre split between them.
+    Front,
+    /// Everything summed to both speakers, stereo sources included.
+    Mono,
+}
+
+impl Downmix {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "stereo" => Some(Self::Stereo),
+            "itu" => Some(Self::Itu),
+            "front" => Some(Self::Front),
+            "mono" => Some(Self::Mono),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Stereo => "stereo",
+            Self::Itu => "ITU",
+            Self::Front => "front only",
+            Self::Mono => "mono",
+        }
+    }
+}
+
+/// Mixes interleaved frames of any layout down (or up) to stereo.
+pub struct Mixer {
+    // (left, right) gain for each input channel
+    gains: Vec<(f32, f32)>,
+    // what the mix does, e.g. `5.1 downmixed to stereo (ITU)`
+    name: String,
+}
+
+impl Mixer {
+    /// `downmix` mix for `layout`, scaled so no output channel can clip.
+    ///
+    /// A layout that doesn't have `channels` speakers, which some decoders
+    /// report, is mixed as if its channels were front left, right, then centre.
+    pub fn for_layout(layout: ChannelLayout, channels: usize, downmix: Downmix) -> Self {
+        let positions: Vec<u64> = if positions(layout).count() == channels {
+            positions(layout).collect()
+        } else {
+            (0..channels)
+                .map(|i| match i {
+                    0 if channels > 1 => ChannelLayout::FRONT_LEFT.bits(),
+                    1 => ChannelLayout::FRONT_RIGHT.bits(),
+                    _ => ChannelLayout::FRONT_CENTER.bits(),
+                })
+                .collect()
+        };
+
+        let name = match (channels, downmix) {
+            (1, Downmix::Mono) | (2, Downmix::Stereo) => layout_name(layout, channels),
+            (1, _) => "mono upmixed to stereo".to_string(),
+            (_, Downmix::Mono) => format!("{} downmixed to mono", layout_name(layout, channels)),
+            _ => format!("{} downmixed to stereo ({})", layout_name(layout, channels), downmix.name()),
+        };
+
+        if channels == 1 {
+            // dual mono, not a centre speaker at -3 dB
+            return Self { gains: vec![(1., 1.)], name };
+        }
+
+        let mut gains: Vec<(f32, f32)> = positions.into_iter()
+            .map(|bit| match downmix {
+                Downmix::Mono => (1., 1.),
+                _ => position_gains(bit, downmix),
+            })
+            .collect();
+
+        let loudest = gains.iter()
+            .fold((0f32, 0f32), |(l, r), (gl, gr)| (l + gl, r + gr));
+        let scale = 1. / loudest.0.max(loudest.1).max(1.);
+        for (l, r) in &mut gains {
+            *l *= scale;
+            *r *= scale;
+        }
+
+        Self { gains, name }
+    }
+
+    /// Parse `L;R` rows of per-input-channel gains, e.g. `1,0,0.7;0,1,0.7`.
+    pub fn parse(spec: &str, channels: usize) -> Result<Self, String> {
+        let rows: Vec<Vec<f32>> = spec.split(';')
+            .map(|row| {
+                row.split(',')
+                    .map(|gain| gain.trim().parse::<f32>().map_err(|e| format!("\"{}\": {}", gain, e)))
+                    .collect()
+            })
+            .collect::<Result<_, _>>()?;
+
+        if rows.len() != 2 {
+            return Err(format!("expected 2 rows (left;right), got {}", rows.len()));
+        }
+        if rows.iter().any(|row| row.len() != channels) {
+            return Err(format!("expected {} gains per row, one for each input channel", channels));
+        }
+
+        Ok(Self {
+            gains: rows[0].iter().copied().zip(rows[1].iter().copied()).collect(),
+            name: format!("{} channels by --mix-matrix", channels),
+        })
+    }
+
+    /// What the mix does, for the track report.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn describe(&self) -> String {
+        let row = |gains: Vec<f32>| {
+            gains.iter().map(|g| format!("{:.2}", g)).collect::<Vec<_>>().join(" ")
+        };
+
+        format!("L [{}] R [{}]",
+                row(self.gains.iter().map(|g| g.0).collect()),
+                row(self.gains.iter().map(|g| g.1).collect()))
+    }
+
+    /// Mix interleaved `input` into stereo `output`, replacing its contents.
+    pub fn mix(&self, input: &[f32], output: &mut Vec<f32>) {
+        output.clear();
+        for frame in input.chunks_exact(self.gains.len()) {
+            let (l, r) = frame.iter().zip(&self.gains)
+                .fold((0., 0.), |(l, r), (s, (gl, gr))| (l + s * gl, r + s * gr));
+            output.push(l);
+            output.push(r);
+        }
+    }
+}
+
+/// The speaker positions in `layout`, as single bits in channel order.
+fn positions(layout: ChannelLayout) -> impl Iterator<Item = u64> {
+    (0..64)
+        .map(|bit| 1u64 << bit)
+        .filter(move |bit| layout.bits() & bit != 0)
+}
+
+/// `5.1`, or `6 channels` for a layout without a common name.
+fn layout_name(layout: ChannelLayout, channels: usize) -> String {
+    const NAMES: [(ChannelLayout, &str); 16] = [
+        (ChannelLayout::MONO, "mono"),
+        (ChannelLayout::STEREO, "stereo"),
+        (ChannelLayout::_2POINT1, "2.1"),
+        (ChannelLayout::SURROUND, "3.0"),
+        (ChannelLayout::_3POINT1, "3.1"),
+        (ChannelLayout::_4POINT0, "4.0"),
+        (ChannelLayout::_4POINT1, "4.1"),
+        (ChannelLayout::QUAD, "quad"),
+        (ChannelLayout::_5POINT0, "5.0"),
+        (ChannelLayout::_5POINT1, "5.1"),
+        (ChannelLayout::_5POINT0_BACK, "5.0"),
+        (ChannelLayout::_5POINT1_BACK, "5.1"),
+        (ChannelLayout::_6POINT1, "6.1"),
+        (ChannelLayout::_7POINT0, "7.0"),
+        (ChannelLayout::_7POINT1, "7.1"),
+        (ChannelLayout::_2_2, "quad (side)"),
+    ];
+
+    NAMES.iter()
+        .find(|(known, _)| *known == layout)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("{} channels", channels))
+}
+
+/// Gains for one speaker position (a single layout bit).
+fn position_gains(bit: u64, downmix: Downmix) -> (f32, f32) {
+    const H: f32 = FRAC_1_SQRT_2;
+
+    let is = |position: ChannelLayout| bit == position.bits();
+    let left = is(ChannelLayout::BACK_LEFT) || is(ChannelLayout::SIDE_LEFT);
+    let right = is(ChannelLayout::BACK_RIGHT) || is(ChannelLayout::SIDE_RIGHT);
+
+    if is(ChannelLayout::FRONT_LEFT) {
+        (1., 0.)
+    } else if is(ChannelLayout::FRONT_RIGHT) {
+        (0., 1.)
+    } else if is(ChannelLayout::FRONT_CENTER) {
+        (H, H)
+    } else if is(ChannelLayout::LOW_FREQUENCY) {
+        match downmix {
+            // kept, 2.1 music carries its bass there
+            Downmix::Stereo => (0.5, 0.5),
+            _ => (0., 0.),
+        }
+    } else if downmix == Downmix::Front {
+        (0., 0.)
+    } else if left {
+        (H, 0.)
+    } else if right {
+        (0., H)
+    } else {
+        (0.5, 0.5)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mixed(mixer: &Mixer, input: &[f32]) -> Vec<f32> {
+        let mut output = Vec::new();
+        mixer.mix(input, &mut output);
+        output
+    }
+
+    #[test]
+    fn matrix() {
+        let mixer = Mixer::parse("1,0,0.5;0,1,0.5", 3).unwrap();
+        assert_eq!(mixed(&mixer, &[1., 0., 1., 0., 0.5, 0.]), vec![1.5, 0.5, 0., 0.5]);
+
+        assert!(Mixer::parse("1,0,0.5", 3).is_err());
+        assert!(Mixer::parse("1,0;0,1", 3).is_err());
+        assert!(Mixer::parse("1,0,x;0,1,0.5", 3).is_err());
+    }
+
+    #[test]
+    fn itu_downmix_doesnt_clip() {
+        let mixer = Mixer::for_layout(ChannelLayout::_5POINT1, 6, Downmix::Itu);
+        assert_eq!(mixer.name(), "5.1 downmixed to stereo (ITU)");
+        let output = mixed(&mixer, &[1.; 6]);
+        assert!((output[0] - 1.).abs() < 1e-6 && (output[1] - 1.).abs() < 1e-6, "{:?}", output);
+        // LFE dropped
+        assert_eq!(mixed(&mixer, &[0., 0., 0., 1., 0., 0.]), vec![0., 0.]);
+    }
+
+    #[test]
+    fn mono_and_unknown_layouts() {
+        let mixer = Mixer::for_layout(ChannelLayout::MONO, 1, Downmix::Stereo);
+        assert_eq!(mixer.name(), "mono upmixed to stereo");
+        assert_eq!(mixed(&mixer, &[0.5]), vec![0.5, 0.5]);
+
+        // no layout, taken as front left and right
+        let mixer = Mixer::for_layout(ChannelLayout::empty(), 2, Downmix::Stereo);
+        assert_eq!(mixed(&mixer, &[0.25, 0.75]), vec![0.25, 0.75]);
+    }
+}
@@ -0,0 +1,125 @@
+use std::io::{self, Write};
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering::Relaxed}
+};
+
+use portaudio as pa;
+use ffmpeg::time::sleep;
+
+use crate::meter::{BarStyle, Levels, Meter};
+use fluxplayer::{PlayerError, CHANNELS, DEFAULT_VOLUME};
+
+// Small buffers keep the round trip short, at the cost of more callbacks.
+const MONITOR_FRAMES_PER_BUFFER: u32 = 128;
+
+pub fn run(input_spec: Option<&str>, ascii: bool) -> Result<(), PlayerError> {
+    let style = if ascii { BarStyle::Ascii } else { BarStyle::detect() };
+
+    let pa = pa::PortAudio::new()?;
+
+    let input_device = match input_spec {
+        Some(spec) => find_input_device(&pa, spec).ok_or_else(|| PlayerError::InputNotFound(spec.to_string()))?,
+        None => pa.default_input_device().map_err(|_| PlayerError::NoDefaultInput)?,
+    };
+    let output_device = pa.default_output_device().map_err(|_| PlayerError::NoDefaultDevice)?;
+
+    let input_info = pa.device_info(input_device)?;
+    let output_info = pa.device_info(output_device)?;
+
+    let input_channels = input_info.max_input_channels.min(CHANNELS);
+    // the callback maps output channels onto input ones
+    if input_channels <= 0 {
+        return Err(PlayerError::NoInputChannels(input_info.name.to_string()));
+    }
+    let sample_rate = input_info.default_sample_rate;
+
+    println!("{}[Monitor]", " ".repeat(17));
+    println!("{:>16}: {} ({} ch)",
+            "Input Device", input_info.name, input_channels);
+    println!("{:>16}: {}",
+            "Output Device", output_info.name);
+    println!("{:>16}: {}",
+            "Sample Rate", sample_rate);
+
+    let in_params = pa::StreamParameters::<f32>::new(
+        input_device, input_channels, true, input_info.default_low_input_latency);
+    let out_params = pa::StreamParameters::<f32>::new(
+        output_device, CHANNELS, true, output_info.default_low_output_latency);
+
+    pa.is_duplex_format_supported(in_params, out_params, sample_rate)
+        .map_err(|_| PlayerError::DuplexUnsupported(sample_rate))?;
+
+    let settings = pa::DuplexStreamSettings::new(
+        in_params, out_params, sample_rate, MONITOR_FRAMES_PER_BUFFER);
+
+    let levels = Arc::new(Levels::new(input_channels as usize));
+    let levels_cb = levels.clone();
+
+    let callback = move |pa::DuplexStreamCallbackArgs { in_buffer, out_buffer, frames, .. }| {
+        levels_cb.update(in_buffer);
+
+        let in_channels = input_channels as usize;
+        for frame in 0..frames {
+            for channel in 0..CHANNELS as usize {
+                // mono inputs feed both sides
+                let sample = in_buffer[frame * in_channels + channel.min(in_channels - 1)];
+                out_buffer[frame * CHANNELS as usize + channel] = sample * DEFAULT_VOLUME;
+            }
+        }
+
+        pa::Continue
+    };
+
+    let mut pa_stream = pa.open_non_blocking_stream(settings, callback)?;
+    pa_stream.start()?;
+
+    println!("\nMonitoring, press Enter to stop.\n");
+
+    let running = Arc::new(AtomicBool::new(true));
+    let running_o = running.clone();
+    let othread_handle = std::thread::spawn(move || {
+        let mut meter = Meter::new(input_channels as usize, style);
+        let mut first = true;
+        while running_o.load(Relaxed) {
+            if !first {
+                print!("\x1b[{}A", meter.lines());
+            }
+            first = false;
+
+            for line in meter.render(&levels) {
+                println!("\r{}", line);
+            }
+            let _ = io::stdout().flush();
+
+            sleep(50_000).unwrap();
+        }
+    });
+
+    let _ = io::stdin().read_line(&mut String::new());
+    running.store(false, Relaxed);
+    othread_handle.join().unwrap();
+
+    pa_stream.stop()?;
+    pa_stream.close()?;
+    Ok(())
+}
+
+/// Input device by index or by (case-insensitive) part of its name.
+fn find_input_device(pa: &pa::PortAudio, spec: &str) -> Option<pa::DeviceIndex> {
+    let devices: Vec<_> = pa.devices().ok()?
+        .filter_map(Result::ok)
+        .filter(|(_, info)| info.max_input_channels > 0)
+        .collect();
+
+    if let Ok(index) = spec.parse::<u32>() {
+        return devices.iter()
+            .find(|(device, _)| device.0 == index)
+            .map(|(device, _)| *device);
+    }
+
+    let spec = spec.to_lowercase();
+    devices.iter()
+        .find(|(_, info)| info.name.to_lowercase().contains(&spec))
+        .map(|(device, _)| *device)
+}
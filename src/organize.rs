@@ -0,0 +1,125 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::files;
+use fluxplayer::infer::Patterns;
+use fluxplayer::PlayerError;
+use fluxplayer::tags::{self, Tags};
+use fluxplayer::template;
+
+pub const DEFAULT_PATTERN: &str = "{album_artist}/{album}/{track} {title}";
+
+pub fn run(src: &Path, dest: Option<&Path>, pattern: &str, filename_patterns: &Patterns, apply: bool) -> Result<(), PlayerError> {
+    let dest = dest.map(Path::to_path_buf).unwrap_or_else(|| {
+        if src.is_dir() {
+            src.to_path_buf()
+        } else {
+            src.parent().map(Path::to_path_buf).unwrap_or_default()
+        }
+    });
+
+    ffmpeg::init().map_err(PlayerError::Init)?;
+
+    let mut moved = 0;
+    for file in files::collect_files(src) {
+        let input = match ffmpeg::format::input(&file) {
+            Ok(input) => input,
+            Err(_) => continue,
+        };
+        if input.streams().best(ffmpeg::media::Type::Audio).is_none() {
+            continue;
+        }
+
+        let tags = tags::read_tags(&input, &file, filename_patterns);
+        // appended rather than set_extension(), titles may contain dots
+        let mut target = dest.join(render(pattern, &tags)).into_os_string();
+        if let Some(ext) = file.extension() {
+            target.push(".");
+            target.push(ext);
+        }
+        let target = PathBuf::from(target);
+
+        if target == file {
+            continue;
+        }
+
+        println!("{} -> {}{}", file.display(), target.display(),
+                if tags.inferred { " (from filename)" } else { "" });
+
+        if apply {
+            if target.exists() {
+                eprintln!("Skipped: {} already exists", target.display());
+                continue;
+            }
+            match move_file(&file, &target) {
+                Ok(()) => moved += 1,
+                Err(e) => eprintln!("Error: {}: {}", file.display(), e),
+            }
+        }
+    }
+
+    if apply {
+        println!("\n{} file(s) moved.", moved);
+    } else {
+        println!("\nDry run, nothing was moved. Pass --apply to move files.");
+    }
+    Ok(())
+}
+
+fn render(pattern: &str, tags: &Tags) -> PathBuf {
+    template::render(pattern, |key| Some(sanitize(&field(key, tags))))
+        .split('/')
+        .map(|c| c.trim())
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+fn field(key: &str, tags: &Tags) -> String {
+    let value = match key {
+        "album_artist" => tags.get("album_artist").or_else(|| tags.get("artist")),
+        _ => tags.get(key),
+    };
+
+    match (key, value) {
+        // "3/12" -> "03"
+        ("track", Some(track)) => {
+            let number = track.split('/').next().unwrap_or(track).trim();
+            match number.parse::<u32>() {
+                Ok(n) => format!("{:02}", n),
+                Err(_) => number.to_string(),
+            }
+        }
+        ("disc", Some(disc)) => disc.split('/').next().unwrap_or(disc).trim().to_string(),
+        (_, Some(value)) => value.to_string(),
+        ("track", None) | ("disc", None) => String::new(),
+        (_, None) => format!("Unknown {}", key.replace('_', " ")),
+    }
+}
+
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim_matches('.')
+        .to_string()
+}
+
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // rename() can't cross filesystems, fall back to copy + remove
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+
+    Ok(())
+}
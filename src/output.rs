@@ -0,0 +1,411 @@
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering::SeqCst}
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use portaudio as pa;
+use ringbuf::Consumer;
+
+use crate::status::PlayerStatus;
+use crate::CHANNELS;
+
+/// Sample format the output stream is opened with. Decoding and DSP stay in f32.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    S16,
+    S24,
+    S32,
+    F32,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "s16" => Some(Self::S16),
+            "s24" => Some(Self::S24),
+            "s32" => Some(Self::S32),
+            "f32" => Some(Self::F32),
+            _ => None,
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::S16 => "s16 (TPDF dither)",
+            // PortAudio's 24-bit format is packed, so it goes out left-justified in 32 bits
+            Self::S24 => "s24 in s32 (TPDF dither)",
+            Self::S32 => "s32",
+            Self::F32 => "f32",
+        }
+    }
+}
+
+/// Float to integer conversion with optional TPDF dither at the target bit depth.
+pub struct Quantizer {
+    scale: f64,
+    shift: u32,
+    dither: bool,
+    seed: u32,
+}
+
+impl Quantizer {
+    fn new(format: OutputFormat) -> Self {
+        let (bits, shift, dither) = match format {
+            OutputFormat::S16 => (16, 0, true),
+            OutputFormat::S24 => (24, 8, true),
+            // f32 only has 24 bits of mantissa, below that there is nothing to dither
+            OutputFormat::S32 | OutputFormat::F32 => (32, 0, false),
+        };
+
+        Self {
+            scale: f64::from(1u32 << (bits - 1)),
+            shift,
+            dither,
+            seed: 0x9e37_79b9,
+        }
+    }
+
+    fn quantize(&mut self, sample: f32) -> i32 {
+        let mut value = f64::from(sample) * self.scale;
+        if self.dither {
+            // sum of two uniform values in [-0.5, 0.5) LSB is triangular over +-1 LSB
+            value += self.random() + self.random();
+        }
+
+        let value = value.round().max(-self.scale).min(self.scale - 1.) as i64;
+        (value << self.shift) as i32
+    }
+
+    /// Uniform in [-0.5, 0.5), xorshift32.
+    fn random(&mut self) -> f64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        f64::from(self.seed) / f64::from(u32::MAX) - 0.5
+    }
+}
+
+/// Stream sample types the f32 path can be written out as.
+pub trait OutputSample: pa::Sample + 'static {
+    fn convert(sample: f32, quantizer: &mut Quantizer) -> Self;
+}
+
+impl OutputSample for f32 {
+    fn convert(sample: f32, _: &mut Quantizer) -> Self {
+        sample
+    }
+}
+
+impl OutputSample for i16 {
+    fn convert(sample: f32, quantizer: &mut Quantizer) -> Self {
+        quantizer.quantize(sample) as i16
+    }
+}
+
+impl OutputSample for i32 {
+    fn convert(sample: f32, quantizer: &mut Quantizer) -> Self {
+        quantizer.quantize(sample)
+    }
+}
+
+/// Which device an output stream is opened on, and how.
+#[derive(Clone, Copy, Debug)]
+pub struct OutputConfig {
+    /// None for the null output, see `NullStream`.
+    pub device: Option<pa::DeviceIndex>,
+    pub format: OutputFormat,
+    /// Frames handed to the device per callback.
+    pub frames_per_buffer: u32,
+}
+
+pub enum OutputStream {
+    F32(pa::Stream<pa::NonBlocking, pa::Output<f32>>),
+    I16(pa::Stream<pa::NonBlocking, pa::Output<i16>>),
+    I32(pa::Stream<pa::NonBlocking, pa::Output<i32>>),
+    Null(NullStream),
+}
+
+impl OutputStream {
+    /// Open the configured output device, playing samples from `rb_rx` once `prime`
+    /// samples are buffered (or decoding has finished).
+    pub fn open(
+        pa: &pa::PortAudio,
+        config: OutputConfig,
+        rate: u32,
+        rb_rx: Consumer<f32>,
+        prime: usize,
+        status: Arc<PlayerStatus>,
+    ) -> Result<Self, pa::Error> {
+        let device = match config.device {
+            Some(device) => device,
+            None => {
+                let fill = callback(config, rb_rx, prime, status);
+                return Ok(Self::Null(NullStream::new(fill, rate, config.frames_per_buffer)));
+            }
+        };
+
+        match config.format {
+            OutputFormat::F32 => open_typed(pa, device, config, rate, rb_rx, prime, status).map(Self::F32),
+            OutputFormat::S16 => open_typed(pa, device, config, rate, rb_rx, prime, status).map(Self::I16),
+            OutputFormat::S24 | OutputFormat::S32 => {
+                open_typed(pa, device, config, rate, rb_rx, prime, status).map(Self::I32)
+            }
+        }
+    }
+
+    pub fn start(&mut self) -> Result<(), pa::Error> {
+        match self {
+            Self::F32(stream) => stream.start(),
+            Self::I16(stream) => stream.start(),
+            Self::I32(stream) => stream.start(),
+            Self::Null(stream) => {
+                stream.start();
+                Ok(())
+            }
+        }
+    }
+
+    pub fn stop(&mut self) -> Result<(), pa::Error> {
+        match self {
+            Self::F32(stream) => stream.stop(),
+            Self::I16(stream) => stream.stop(),
+            Self::I32(stream) => stream.stop(),
+            Self::Null(stream) => {
+                stream.stop();
+                Ok(())
+            }
+        }
+    }
+
+    pub fn close(&mut self) -> Result<(), pa::Error> {
+        match self {
+            Self::F32(stream) => stream.close(),
+            Self::I16(stream) => stream.close(),
+            Self::I32(stream) => stream.close(),
+            Self::Null(stream) => {
+                stream.stop();
+                Ok(())
+            }
+        }
+    }
+}
+
+type Callback = Box<dyn FnMut(&mut [f32]) -> pa::CallbackResult + Send>;
+
+/// Stands in for an output device when there is none. A thread of its own runs
+/// the callback in real time, so tracks play and are timed as usual, silently.
+pub struct NullStream {
+    callback: Arc<Mutex<Callback>>,
+    // how long each callback's buffer lasts, and how many samples it holds
+    period: Duration,
+    buffer_len: usize,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NullStream {
+    fn new(callback: impl FnMut(&mut [f32]) -> pa::CallbackResult + Send + 'static, rate: u32, frames_per_buffer: u32) -> Self {
+        Self {
+            callback: Arc::new(Mutex::new(Box::new(callback))),
+            period: Duration::from_secs_f64(f64::from(frames_per_buffer) / f64::from(rate)),
+            buffer_len: frames_per_buffer as usize * CHANNELS as usize,
+            running: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+
+    fn start(&mut self) {
+        self.stop();
+        self.running.store(true, SeqCst);
+
+        let callback = self.callback.clone();
+        let running = self.running.clone();
+        let period = self.period;
+        let mut buffer = vec![0f32; self.buffer_len];
+        self.thread = Some(thread::spawn(move || {
+            let mut callback = callback.lock().unwrap();
+            let mut next = Instant::now();
+            while running.load(SeqCst) {
+                if callback(&mut buffer) != pa::Continue {
+                    break;
+                }
+                next += period;
+                thread::sleep(next.saturating_duration_since(Instant::now()));
+            }
+        }));
+    }
+
+    fn stop(&mut self) {
+        self.running.store(false, SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Output device by index or by (case-insensitive) part of its name.
+pub fn find_output_device(pa: &pa::PortAudio, spec: &str) -> Option<pa::DeviceIndex> {
+    let devices: Vec<_> = pa.devices().ok()?
+        .filter_map(Result::ok)
+        .filter(|(_, info)| info.max_output_channels > 0)
+        .collect();
+
+    if let Ok(index) = spec.parse::<u32>() {
+        return devices.iter()
+            .find(|(device, _)| device.0 == index)
+            .map(|(device, _)| *device);
+    }
+
+    let spec = spec.to_lowercase();
+    devices.iter()
+        .find(|(_, info)| info.name.to_lowercase().contains(&spec))
+        .map(|(device, _)| *device)
+}
+
+/// Whether `device` can open a stereo stream of `format` at `rate`.
+pub fn is_rate_supported(pa: &pa::PortAudio, device: pa::DeviceIndex, format: OutputFormat, rate: u32) -> bool {
+    fn check<S: OutputSample>(pa: &pa::PortAudio, device: pa::DeviceIndex, rate: u32) -> bool {
+        let latency = match pa.device_info(device) {
+            Ok(info) => info.default_low_output_latency,
+            Err(_) => return false,
+        };
+        let params = pa::StreamParameters::<S>::new(device, CHANNELS, true, latency);
+        pa.is_output_format_supported(params, f64::from(rate)).is_ok()
+    }
+
+    match format {
+        OutputFormat::F32 => check::<f32>(pa, device, rate),
+        OutputFormat::S16 => check::<i16>(pa, device, rate),
+        OutputFormat::S24 | OutputFormat::S32 => check::<i32>(pa, device, rate),
+    }
+}
+
+fn open_typed<S: OutputSample>(
+    pa: &pa::PortAudio,
+    device: pa::DeviceIndex,
+    config: OutputConfig,
+    rate: u32,
+    rb_rx: Consumer<f32>,
+    prime: usize,
+    status: Arc<PlayerStatus>,
+) -> Result<pa::Stream<pa::NonBlocking, pa::Output<S>>, pa::Error> {
+    let latency = pa.device_info(device)?.default_low_output_latency;
+    let params = pa::StreamParameters::<S>::new(device, CHANNELS, true, latency);
+    let pa_settings = pa::OutputStreamSettings::new(params, f64::from(rate), config.frames_per_buffer);
+
+    let mut fill = callback::<S>(config, rb_rx, prime, status);
+    pa.open_non_blocking_stream(pa_settings, move |args: pa::OutputStreamCallbackArgs<S>| fill(args.buffer))
+}
+
+/// What the output callback does with each buffer: fill it from `rb_rx`, and
+/// say when playback is over.
+fn callback<S: OutputSample>(
+    OutputConfig { format, frames_per_buffer, .. }: OutputConfig,
+    mut rb_rx: Consumer<f32>,
+    prime: usize,
+    status_cb: Arc<PlayerStatus>,
+) -> impl FnMut(&mut [S]) -> pa::CallbackResult + Send {
+    let mut quantizer = Quantizer::new(format);
+    let mut samples = vec![0f32; frames_per_buffer as usize * CHANNELS as usize];
+    let mut last_gain = status_cb.gain();
+    // frames of the fade out played so far
+    let mut faded = 0;
+
+    move |buffer: &mut [S]| {
+        let stale = status_cb.stale_samples.swap(0, SeqCst);
+        if stale > 0 {
+            let discarded = rb_rx.discard(stale);
+            status_cb.advance(discarded / CHANNELS as usize);
+        }
+
+        if !status_cb.is_primed.load(SeqCst) {
+            if rb_rx.len() < prime && status_cb.is_decoding.load(SeqCst) {
+                for out in buffer.iter_mut() {
+                    *out = S::convert(0., &mut quantizer);
+                }
+                return pa::Continue;
+            }
+            status_cb.is_primed.store(true, SeqCst);
+        }
+
+        samples.resize(buffer.len(), 0.);
+        let recv_size = rb_rx.pop_slice(&mut samples);
+        assert_eq!(recv_size % CHANNELS as usize, 0);
+        if recv_size < buffer.len() && (status_cb.is_decoding.load(SeqCst) || status_cb.is_holding.load(SeqCst)) {
+            status_cb.underruns.fetch_add(1, SeqCst);
+        }
+
+        // an interrupted track fades to silence instead of stopping mid-waveform
+        let fade_out = status_cb.fade_out.load(SeqCst);
+        let fade = |idx: usize| match fade_out {
+            0 => 1.,
+            frames => 1. - ((faded + idx / CHANNELS as usize) as f32 / frames as f32).min(1.),
+        };
+
+        // volume changes are ramped over one buffer, a jump would click
+        let gain = status_cb.gain();
+        let ramp = (gain - last_gain) / buffer.len() as f32;
+        for (idx, out) in buffer.iter_mut().enumerate() {
+            let sample = if idx >= recv_size { 0f32 } else { samples[idx] * (last_gain + ramp * idx as f32) * fade(idx) };
+            *out = S::convert(sample, &mut quantizer);
+        }
+        last_gain = gain;
+        status_cb.frames_played.fetch_add(recv_size / CHANNELS as usize, SeqCst);
+        status_cb.advance(recv_size / CHANNELS as usize);
+
+        if fade_out == 0 {
+            faded = 0;
+        } else {
+            faded += buffer.len() / CHANNELS as usize;
+            if faded >= fade_out {
+                status_cb.is_faded.store(true, SeqCst);
+                status_cb.is_playing.store(false, SeqCst);
+                return pa::Complete;
+            }
+        }
+
+        let is_done = !status_cb.is_decoding.load(SeqCst) && !status_cb.is_holding.load(SeqCst);
+        if is_done && rb_rx.is_empty() && recv_size == 0 {
+            status_cb.is_playing.store(false, SeqCst);
+            return pa::Complete;
+        }
+
+        pa::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_scale() {
+        let mut quantizer = Quantizer::new(OutputFormat::S32);
+        assert_eq!(quantizer.quantize(0.5), 1 << 30);
+        assert_eq!(quantizer.quantize(1.), i32::MAX);
+        assert_eq!(quantizer.quantize(-1.), i32::MIN);
+        assert_eq!(quantizer.quantize(2.), i32::MAX);
+
+        let mut quantizer = Quantizer::new(OutputFormat::S16);
+        assert_eq!(i16::convert(1.5, &mut quantizer), i16::MAX);
+        assert_eq!(i16::convert(-1.5, &mut quantizer), i16::MIN);
+    }
+
+    #[test]
+    fn dither_stays_within_an_lsb() {
+        let mut quantizer = Quantizer::new(OutputFormat::S16);
+        let values: Vec<i32> = (0..1000).map(|_| quantizer.quantize(0.)).collect();
+        assert!(values.iter().all(|v| (-1..=1).contains(v)));
+        assert!(values.iter().any(|v| *v != 0));
+
+        // 24 bits, left-justified in 32
+        let mut quantizer = Quantizer::new(OutputFormat::S24);
+        for _ in 0..1000 {
+            let value = quantizer.quantize(0.5);
+            assert_eq!(value & 0xff, 0);
+            assert!(((value >> 8) - (1 << 22)).abs() <= 1, "{}", value);
+        }
+    }
+}
@@ -0,0 +1,799 @@
+use std::collections::VecDeque;
+use std::f32::consts::FRAC_PI_2;
+use std::mem;
+use std::sync::{
+    Arc,
+    atomic::Ordering::{Relaxed, SeqCst}
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use ffmpeg::time::sleep;
+use portaudio as pa;
+use ringbuf::Producer;
+
+use crate::decoder::Decoder;
+use crate::dsp;
+use crate::error::PlayerError;
+use crate::output::{find_output_device, is_rate_supported, OutputConfig, OutputFormat, OutputStream};
+use crate::status::PlayerStatus;
+use crate::{CHANNELS, SAMPLE_RATE};
+
+/// `--device` name of the null output, which plays silently in real time.
+pub const NULL_DEVICE: &str = "null";
+
+// How long an interrupted track takes to fade out.
+const FADE_OUT_SECONDS: f64 = 0.1;
+
+/// Requests from a frontend, applied with `Player::apply()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    TogglePause,
+    /// Seek this many seconds from the current position, backwards if negative.
+    SeekBy(f64),
+    VolumeUp,
+    VolumeDown,
+    ToggleMute,
+    /// Drop the output by `DIM_DB` until toggled again.
+    ToggleDim,
+    /// Stop this track. For the player this is the same as `Stop`, a frontend
+    /// with a queue moves on to the next one.
+    Next,
+    Stop,
+    /// Ctrl+C: fade out, then stop like `Stop`.
+    Interrupt,
+    /// Reorder the rest of the queue by the next sort key. Only a frontend with
+    /// a queue acts on it, the player ignores it.
+    CycleSort,
+    /// Turn `--autofill` on or off. Only a frontend with a queue acts on it.
+    ToggleAutofill,
+    /// Queue what ListenBrainz recommends that's in the library. Only a
+    /// frontend with a queue acts on it.
+    QueueRecommendations,
+    /// Skip to the first track of the next album or artist, or back to the
+    /// previous one. The player stops this track as for `Next`, the frontend
+    /// picks where to go on.
+    NextAlbum,
+    PreviousAlbum,
+    NextArtist,
+    PreviousArtist,
+    /// The config file changed, see `config::watch()` in the cli. Only a
+    /// frontend acts on it.
+    ConfigChanged,
+}
+
+/// How the volume keys step: evenly in amplitude, or evenly in dB.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VolumeScale {
+    Linear,
+    Decibel,
+}
+
+impl VolumeScale {
+    const LINEAR_STEP: f32 = 0.05;
+    const DB_STEP: f32 = 2.;
+    // -60 dB, stepping down from here goes to silence and back up from silence lands here
+    const DB_FLOOR: f32 = 0.001;
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "linear" => Some(Self::Linear),
+            "db" | "log" => Some(Self::Decibel),
+            _ => None,
+        }
+    }
+
+    /// `volume` moved one step up, or down.
+    pub fn step(self, volume: f32, up: bool) -> f32 {
+        let sign = if up { 1. } else { -1. };
+        match self {
+            Self::Linear => volume + sign * Self::LINEAR_STEP,
+            Self::Decibel if volume < Self::DB_FLOOR => if up { Self::DB_FLOOR } else { 0. },
+            Self::Decibel => {
+                let stepped = volume * 10f32.powf(sign * Self::DB_STEP / 20.);
+                if stepped < Self::DB_FLOOR { 0. } else { stepped }
+            }
+        }
+        .clamp(0., 1.)
+    }
+}
+
+/// How a live stream that drops is opened again, see `Player::set_reconnect()`.
+#[derive(Clone, Copy, Debug)]
+pub struct Reconnect {
+    /// Tries in a row before the stream is given up on.
+    pub attempts: u32,
+    /// Wait before the first try, doubled after each failed one up to
+    /// `MAX_RECONNECT_DELAY`.
+    pub delay: Duration,
+}
+
+/// Longest wait between reconnection attempts.
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Plays decoded audio on the output device picked with `--device`, the default
+/// one otherwise, or silently on the null output (`NullStream`) when there is none.
+///
+/// Decoding runs on its own thread, so `play()` returns as soon as playback has
+/// started; `wait()` blocks until it has finished.
+pub struct Player {
+    // dropped before `pa`, which terminates PortAudio
+    stream: OutputStream,
+    pa: pa::PortAudio,
+    rb_tx: Option<Producer<f32>>,
+    status: Arc<PlayerStatus>,
+    decode_thread: Option<JoinHandle<(Producer<f32>, Option<Decoder>)>>,
+    // handed back by the decode thread, for the next track to reuse
+    last_decoder: Option<Decoder>,
+    // whether the PortAudio stream is started, it stays so after the callback completes
+    running: bool,
+    // only decoders can seek, not samples handed over with play_samples()
+    seekable: bool,
+    volume_scale: VolumeScale,
+    // seconds at the end of a track that overlap the start of the next one
+    crossfade: f64,
+    // for live streams, none ends the track when they drop
+    reconnect: Option<Reconnect>,
+    // kept to reopen the stream at another sample rate
+    output: OutputConfig,
+    prime_seconds: f64,
+    device_name: String,
+    host_api_name: String,
+}
+
+impl Player {
+    /// Open an output device, given by index or part of its name, or the default
+    /// one. Playback starts once `prime_seconds` of audio are buffered, and the
+    /// device is handed `frames_per_buffer` frames at a time.
+    ///
+    /// Without a default device, or given `null`, it plays to the null output
+    /// instead, see `has_device()`.
+    pub fn new(
+        format: OutputFormat,
+        prime_seconds: f64,
+        frames_per_buffer: u32,
+        device: Option<&str>,
+    ) -> Result<Self, PlayerError> {
+        let pa = pa::PortAudio::new()?;
+
+        let device = match device {
+            Some(NULL_DEVICE) => None,
+            Some(spec) => Some(find_output_device(&pa, spec).ok_or_else(|| PlayerError::DeviceNotFound(spec.to_string()))?),
+            None => pa.default_output_device().ok(),
+        };
+        let (device_name, host_api_name) = match device {
+            Some(device) => {
+                let device_info = pa.device_info(device)?;
+                let host_api_name = pa.host_api_info(device_info.host_api)
+                    .map(|info| info.name.to_string())
+                    .unwrap_or_default();
+                (device_info.name.to_string(), host_api_name)
+            }
+            None => ("null, nothing is heard".to_string(), "none".to_string()),
+        };
+
+        let status = Arc::new(PlayerStatus::new());
+        let output = OutputConfig { device, format, frames_per_buffer };
+        let (stream, rb_tx) = open_stream(&pa, output, prime_seconds, SAMPLE_RATE as u32, &status)?;
+
+        Ok(Self {
+            stream,
+            pa,
+            rb_tx: Some(rb_tx),
+            status,
+            decode_thread: None,
+            last_decoder: None,
+            running: false,
+            seekable: false,
+            volume_scale: VolumeScale::Linear,
+            crossfade: 0.,
+            reconnect: None,
+            output,
+            prime_seconds,
+            device_name,
+            host_api_name,
+        })
+    }
+
+    /// False when playing to the null output, for lack of a device.
+    pub fn has_device(&self) -> bool {
+        self.output.device.is_some()
+    }
+
+    pub fn device_name(&self) -> &str {
+        &self.device_name
+    }
+
+    pub fn host_api_name(&self) -> &str {
+        &self.host_api_name
+    }
+
+    pub fn portaudio(&self) -> &pa::PortAudio {
+        &self.pa
+    }
+
+    pub fn status(&self) -> Arc<PlayerStatus> {
+        self.status.clone()
+    }
+
+    /// Linear volume from 0 to 1, applied from the next output buffer on.
+    pub fn set_volume(&self, volume: f32) {
+        self.status.set_volume(volume);
+    }
+
+    /// How `Command::VolumeUp` and `VolumeDown` step.
+    pub fn set_volume_scale(&mut self, scale: VolumeScale) {
+        self.volume_scale = scale;
+    }
+
+    /// Overlap the last `seconds` of each track with the start of the one queued
+    /// with `play_next()`. 0 for none.
+    ///
+    /// The end of every track played from now on is held back until the next
+    /// one is queued, or `play_out()` says there is none.
+    pub fn set_crossfade(&mut self, seconds: f64) {
+        self.crossfade = seconds.max(0.);
+    }
+
+    /// Reopen live streams that drop, like internet radio, as `reconnect` says.
+    /// Without it the track ends there. Applies from the next track played.
+    pub fn set_reconnect(&mut self, reconnect: Option<Reconnect>) {
+        self.reconnect = reconnect;
+    }
+
+    /// Rate the output stream runs at.
+    pub fn sample_rate(&self) -> u32 {
+        self.status.sample_rate() as u32
+    }
+
+    /// The rate to play a file of `rate` at: its own if the device takes it, so
+    /// it doesn't have to be resampled, otherwise `SAMPLE_RATE`.
+    pub fn output_rate_for(&self, rate: u32) -> u32 {
+        // the null output takes any rate
+        let supported = match self.output.device {
+            Some(device) => is_rate_supported(&self.pa, device, self.output.format, rate),
+            None => true,
+        };
+        if supported {
+            rate
+        } else {
+            SAMPLE_RATE as u32
+        }
+    }
+
+    /// Reopen the output stream at `rate`, stopping playback, unless it already
+    /// runs at that rate.
+    pub fn set_sample_rate(&mut self, rate: u32) -> Result<(), PlayerError> {
+        if rate == self.sample_rate() {
+            return Ok(());
+        }
+
+        self.stop()?;
+        self.stream.close()?;
+        let (stream, rb_tx) = open_stream(&self.pa, self.output, self.prime_seconds, rate, &self.status)?;
+        self.stream = stream;
+        self.rb_tx = Some(rb_tx);
+        // left over from the old ring buffer
+        self.status.stale_samples.store(0, SeqCst);
+        Ok(())
+    }
+
+    /// Start playing `decoder` through `chain`, from wherever it was last sought
+    /// to. The stream is switched to the decoder's output rate first.
+    pub fn play(&mut self, decoder: Decoder, chain: Box<dyn dsp::Process>) -> Result<(), PlayerError> {
+        self.set_sample_rate(decoder.output_rate())?;
+        let origin = decoder.origin();
+        let hold = self.crossfade_samples();
+        let seekable = !decoder.is_live();
+        self.start(origin, decode(decoder, chain, Vec::new(), hold, self.reconnect))?;
+        self.seekable = seekable;
+        Ok(())
+    }
+
+    /// Have `decoder` follow the current track without a gap. Call it once the
+    /// current track has been decoded (`is_decoding` is false), so its last
+    /// buffered second leaves time to get the next one going.
+    ///
+    /// False if the current track had already run out or was stopped, or if
+    /// `decoder` doesn't output at the stream's rate. Nothing is queued then,
+    /// and the next track has to be started with `play()`.
+    pub fn play_next(&mut self, decoder: Decoder, chain: Box<dyn dsp::Process>) -> bool {
+        if decoder.output_rate() != self.sample_rate() {
+            return false;
+        }
+        self.join_decoder();
+
+        // claimed before checking, so the callback can't complete in between
+        self.status.is_decoding.store(true, SeqCst);
+        if !self.status.is_playing.load(SeqCst) || self.status.stop_requested.load(SeqCst) {
+            self.status.is_decoding.store(false, SeqCst);
+            return false;
+        }
+
+        let tail = mem::take(&mut *self.status.tail.lock().unwrap());
+        self.status.is_holding.store(false, SeqCst);
+
+        let rb_tx = self.rb_tx.take().unwrap();
+        self.status.set_boundary(rb_tx.len() / CHANNELS as usize);
+        let hold = self.crossfade_samples();
+        let seekable = !decoder.is_live();
+        self.spawn_decoder(rb_tx, decode(decoder, chain, tail, hold, self.reconnect));
+        self.seekable = seekable;
+        true
+    }
+
+    /// Nothing follows the current track, so play the end that was held back
+    /// for a crossfade as it is. Does nothing if there isn't one.
+    pub fn play_out(&mut self) {
+        if !self.status.is_holding.load(SeqCst) {
+            return;
+        }
+        self.join_decoder();
+
+        self.status.is_decoding.store(true, SeqCst);
+        let tail = mem::take(&mut *self.status.tail.lock().unwrap());
+        self.status.is_holding.store(false, SeqCst);
+
+        // passed through, so it can still be reused by the next track
+        let decoder = self.last_decoder.take();
+        let rb_tx = self.rb_tx.take().unwrap();
+        self.spawn_decoder(rb_tx, move |rb_tx, status| {
+            send_samples(&tail, rb_tx, status);
+            decoder
+        });
+    }
+
+    /// Start playing already decoded output-format samples at `rate`.
+    pub fn play_samples(&mut self, samples: Vec<f32>, rate: u32) -> Result<(), PlayerError> {
+        self.set_sample_rate(rate)?;
+        self.start(0., move |rb_tx, status| {
+            send_samples(&samples, rb_tx, status);
+            None
+        })
+    }
+
+    /// The decoder of the last track once it has finished or was stopped, see
+    /// `Decoder::open_next()`.
+    pub fn take_decoder(&mut self) -> Option<Decoder> {
+        // a track that is still playing may already be done decoding
+        if !self.status.is_decoding.load(SeqCst) {
+            self.join_decoder();
+        }
+        self.last_decoder.take()
+    }
+
+    pub fn apply(&mut self, command: Command) -> Result<(), PlayerError> {
+        match command {
+            Command::TogglePause if self.status.is_paused.load(SeqCst) => self.resume(),
+            Command::TogglePause => self.pause(),
+            Command::SeekBy(seconds) => {
+                self.seek(self.status.position() + seconds);
+                Ok(())
+            }
+            Command::VolumeUp | Command::VolumeDown => {
+                let volume = self.volume_scale.step(self.status.volume(), command == Command::VolumeUp);
+                self.set_volume(volume);
+                self.status.is_muted.store(false, SeqCst);
+                Ok(())
+            }
+            Command::ToggleMute => {
+                self.status.is_muted.fetch_xor(true, SeqCst);
+                Ok(())
+            }
+            Command::ToggleDim => {
+                self.status.is_dimmed.fetch_xor(true, SeqCst);
+                Ok(())
+            }
+            Command::Next
+            | Command::NextAlbum
+            | Command::PreviousAlbum
+            | Command::NextArtist
+            | Command::PreviousArtist
+            | Command::Stop => self.stop(),
+            Command::Interrupt => self.fade_out(),
+            Command::CycleSort
+            | Command::ToggleAutofill
+            | Command::QueueRecommendations
+            | Command::ConfigChanged => Ok(()),
+        }
+    }
+
+    /// Continue playback from `seconds` into the track. Only the last request
+    /// before the decode thread gets to it is acted on.
+    ///
+    /// Once the decoder has reached the end of the file it is too late to seek,
+    /// and the request is dropped. So is one made while a gaplessly queued track
+    /// is waiting to start.
+    pub fn seek(&self, seconds: f64) {
+        if self.seekable && self.status.is_decoding.load(SeqCst) && !self.status.has_boundary() {
+            *self.status.seek_request.lock().unwrap() = Some(seconds.max(0.));
+        }
+    }
+
+    /// Pause the output. The decode thread stops by itself once the buffer is full.
+    pub fn pause(&mut self) -> Result<(), PlayerError> {
+        if self.status.is_playing.load(SeqCst) && !self.status.is_paused.swap(true, SeqCst) {
+            self.stop_stream()?;
+        }
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> Result<(), PlayerError> {
+        if self.status.is_paused.swap(false, SeqCst) {
+            self.stream.start()?;
+            self.running = true;
+        }
+        Ok(())
+    }
+
+    /// Stop playback and wait for the decode thread to exit.
+    pub fn stop(&mut self) -> Result<(), PlayerError> {
+        self.status.stop_requested.store(true, SeqCst);
+        self.join_decoder();
+        self.status.is_holding.store(false, SeqCst);
+        self.status.tail.lock().unwrap().clear();
+
+        self.status.is_playing.store(false, SeqCst);
+        self.status.is_paused.store(false, SeqCst);
+        self.stop_stream()
+    }
+
+    /// Fade out over `FADE_OUT_SECONDS`, then stop. Stopping outright cuts the
+    /// waveform off wherever it is, which pops.
+    pub fn fade_out(&mut self) -> Result<(), PlayerError> {
+        let status = &self.status;
+        if status.is_playing.load(SeqCst) && !status.is_paused.load(SeqCst) {
+            // nothing more is decoded, the fade plays out what's buffered
+            status.stop_requested.store(true, SeqCst);
+            let frames = (FADE_OUT_SECONDS * status.sample_rate()) as usize;
+            status.fade_out.store(frames.max(1), SeqCst);
+
+            // not forever, in case the device has stalled
+            let deadline = Instant::now() + Duration::from_secs_f64(FADE_OUT_SECONDS * 5.);
+            while !status.is_faded.load(SeqCst) && status.is_playing.load(SeqCst) && Instant::now() < deadline {
+                sleep(5_000).unwrap();
+            }
+        }
+        self.stop()
+    }
+
+    /// Block until everything has been decoded and played.
+    pub fn wait(&mut self) -> Result<(), PlayerError> {
+        self.play_out();
+        while self.status.is_playing.load(Relaxed) {
+            sleep(100_000).unwrap();
+        }
+
+        // a track queued with play_next() just as the buffer ran dry never got to play
+        if self.status.is_decoding.load(SeqCst) {
+            self.status.stop_requested.store(true, SeqCst);
+        }
+        self.join_decoder();
+
+        // the callback has completed, but the stream still has to be stopped before a restart
+        self.stop_stream()
+    }
+
+    fn crossfade_samples(&self) -> usize {
+        (self.crossfade * self.status.sample_rate()) as usize * CHANNELS as usize
+    }
+
+    fn stop_stream(&mut self) -> Result<(), PlayerError> {
+        if self.running {
+            self.running = false;
+            self.stream.stop()?;
+        }
+        Ok(())
+    }
+
+    fn start<F>(&mut self, origin: f64, feed: F) -> Result<(), PlayerError>
+    where
+        F: FnOnce(&mut Producer<f32>, &PlayerStatus) -> Option<Decoder> + Send + 'static,
+    {
+        self.stop()?;
+
+        self.seekable = false;
+        let status = &self.status;
+        status.frames_played.store(0, SeqCst);
+        status.reset_track(origin);
+        status.seek_request.lock().unwrap().take();
+        status.is_primed.store(false, SeqCst);
+        status.is_paused.store(false, SeqCst);
+        status.stop_requested.store(false, SeqCst);
+        status.fade_out.store(0, SeqCst);
+        status.is_faded.store(false, SeqCst);
+        status.is_decoding.store(true, SeqCst);
+
+        let rb_tx = self.rb_tx.take().unwrap();
+        self.spawn_decoder(rb_tx, feed);
+
+        self.stream.start()?;
+        self.running = true;
+        self.status.is_playing.store(true, SeqCst);
+        Ok(())
+    }
+
+    fn spawn_decoder<F>(&mut self, mut rb_tx: Producer<f32>, feed: F)
+    where
+        F: FnOnce(&mut Producer<f32>, &PlayerStatus) -> Option<Decoder> + Send + 'static,
+    {
+        let status_d = self.status.clone();
+        self.decode_thread = Some(thread::spawn(move || {
+            let decoder = feed(&mut rb_tx, &status_d);
+            status_d.is_decoding.store(false, SeqCst);
+            (rb_tx, decoder)
+        }));
+    }
+
+    fn join_decoder(&mut self) {
+        if let Some(handle) = self.decode_thread.take() {
+            let (rb_tx, decoder) = handle.join().unwrap();
+            self.last_decoder = decoder;
+            if self.status.stop_requested.load(SeqCst) {
+                // whatever a stopped track left behind is dropped by the callback
+                self.status.stale_samples.store(rb_tx.len(), SeqCst);
+            }
+            self.rb_tx = Some(rb_tx);
+        }
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        let _ = self.stop();
+        let _ = self.stream.close();
+    }
+}
+
+/// An output stream on `device` at `rate`, and the ring buffer feeding it.
+fn open_stream(
+    pa: &pa::PortAudio,
+    output: OutputConfig,
+    prime_seconds: f64,
+    rate: u32,
+    status: &Arc<PlayerStatus>,
+) -> Result<(OutputStream, Producer<f32>), pa::Error> {
+    // the ring buffer holds a second, and at least the whole priming amount and one more callback
+    let prime = (prime_seconds * f64::from(rate)) as usize * CHANNELS as usize;
+    let second = rate as usize * CHANNELS as usize;
+    let ringbuffer = ringbuf::RingBuffer::<f32>::new(
+        second.max(prime + output.frames_per_buffer as usize * CHANNELS as usize));
+    let (rb_tx, rb_rx) = ringbuffer.split();
+
+    let stream = OutputStream::open(pa, output, rate, rb_rx, prime, status.clone())?;
+    status.set_sample_rate(rate);
+    Ok((stream, rb_tx))
+}
+
+/// Decode thread body playing `decoder` through `chain`, handling seeks on the way.
+///
+/// `tail`, the held back end of the previous track, is faded out under the start
+/// of this one. The last `hold` samples of this one are held back in turn. A live
+/// stream that drops is reopened as `reconnect` says.
+fn decode(
+    mut decoder: Decoder,
+    mut chain: Box<dyn dsp::Process>,
+    tail: Vec<f32>,
+    hold: usize,
+    reconnect: Option<Reconnect>,
+) -> impl FnOnce(&mut Producer<f32>, &PlayerStatus) -> Option<Decoder> + Send + 'static {
+    move |rb_tx, status| {
+        let mut fade = Crossfade::new(tail);
+        let mut mixed = Vec::new();
+        let mut held = VecDeque::new();
+        let mut stopped = false;
+        let live = decoder.is_live();
+        let mut loops = decoder.loops();
+        // of what the decoder read, what's been added to status.bytes_read
+        let mut counted = 0;
+        *status.stream_title.lock().unwrap() = None;
+
+        loop {
+            // ahead of what's heard by the buffered audio, like the rest of decoding
+            if let Some(title) = decoder.stream_title() {
+                *status.stream_title.lock().unwrap() = Some(title);
+            }
+            count_read(&decoder, &mut counted, status);
+
+            let target = status.seek_request.lock().unwrap().take();
+            if let Some(target) = target {
+                match decoder.seek(target) {
+                    Ok(()) => {
+                        flush(rb_tx, status);
+                        status.set_origin(decoder.origin());
+                        fade = Crossfade::new(Vec::new());
+                        held.clear();
+                    }
+                    Err(e) => eprintln!("Error: could not seek: {}", e),
+                }
+            }
+
+            let samples = match decoder.next_samples() {
+                Some(samples) if fade.is_done() => chain.run(samples),
+                Some(samples) => {
+                    fade.mix(chain.run(samples), &mut mixed);
+                    &mixed[..]
+                }
+                // a track shorter than the fade still lets the previous one finish
+                None if !fade.is_done() => {
+                    fade.finish(&mut mixed);
+                    &mixed[..]
+                }
+                None if live => match reconnect {
+                    Some(policy) if reopen(&mut decoder, policy, status) => continue,
+                    _ => break,
+                },
+                None => break,
+            };
+
+            let frames = samples.len() / CHANNELS as usize;
+            let sent = if hold == 0 {
+                send_samples(samples, rb_tx, status)
+            } else {
+                held.extend(samples.iter().copied());
+                send_held(&mut held, hold, rb_tx, status)
+            };
+            // these samples came from the loop start, positions count from there
+            // once they're heard
+            if decoder.loops() != loops {
+                loops = decoder.loops();
+                status.rebase(decoder.origin(), frames);
+            }
+            if !sent && status.stop_requested.load(Relaxed) {
+                stopped = true;
+                break;
+            }
+        }
+
+        count_read(&decoder, &mut counted, status);
+
+        if !held.is_empty() && !stopped {
+            *status.tail.lock().unwrap() = held.into();
+            status.is_holding.store(true, SeqCst);
+        }
+        Some(decoder)
+    }
+}
+
+/// Add what `decoder` read since `counted` bytes to `status.bytes_read`. A
+/// reconnected stream counts from 0 again.
+fn count_read(decoder: &Decoder, counted: &mut u64, status: &PlayerStatus) {
+    let read = decoder.bytes_read();
+    status.bytes_read.fetch_add(read.saturating_sub(*counted), Relaxed);
+    *counted = read;
+}
+
+/// Try to reopen the stream `decoder` was playing after it dropped, waiting
+/// longer after each failed try. False once all of them failed, or if playback
+/// was stopped meanwhile.
+fn reopen(decoder: &mut Decoder, policy: Reconnect, status: &PlayerStatus) -> bool {
+    let mut delay = policy.delay.min(MAX_RECONNECT_DELAY);
+    for attempt in 1..=policy.attempts {
+        eprintln!("The stream dropped, reconnecting in {:.0}s (try {} of {})",
+                delay.as_secs_f64(), attempt, policy.attempts);
+
+        let until = Instant::now() + delay;
+        while Instant::now() < until {
+            if status.stop_requested.load(Relaxed) {
+                return false;
+            }
+            sleep(100_000).unwrap();
+        }
+
+        match decoder.reconnect() {
+            Ok(()) => return true,
+            Err(e) => eprintln!("Error: could not reconnect: {}", e),
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+    false
+}
+
+/// Send all but the last `hold` samples of `held`.
+fn send_held(held: &mut VecDeque<f32>, hold: usize, rb_tx: &mut Producer<f32>, status: &PlayerStatus) -> bool {
+    let excess = held.len().saturating_sub(hold);
+    let (front, back) = held.as_slices();
+    let first = excess.min(front.len());
+    let sent = send_samples(&front[..first], rb_tx, status)
+        && send_samples(&back[..excess - first], rb_tx, status);
+    held.drain(..excess);
+    sent
+}
+
+/// The end of the previous track, faded out while the next one fades in.
+struct Crossfade {
+    tail: Vec<f32>,
+    // frames mixed so far
+    position: usize,
+}
+
+impl Crossfade {
+    fn new(tail: Vec<f32>) -> Self {
+        Self { tail, position: 0 }
+    }
+
+    fn frames(&self) -> usize {
+        self.tail.len() / CHANNELS as usize
+    }
+
+    fn is_done(&self) -> bool {
+        self.position >= self.frames()
+    }
+
+    /// Mix the next stretch of the tail into `samples`, writing to `output`.
+    fn mix(&mut self, samples: &[f32], output: &mut Vec<f32>) {
+        let channels = CHANNELS as usize;
+        let frames = self.frames();
+
+        output.clear();
+        for (i, frame) in samples.chunks_exact(channels).enumerate() {
+            let at = self.position + i;
+            if at >= frames {
+                output.extend_from_slice(frame);
+                continue;
+            }
+
+            // equal power, so the overlap of two unrelated tracks doesn't dip
+            let t = (at as f32 + 0.5) / frames as f32 * FRAC_PI_2;
+            let tail = &self.tail[at * channels..(at + 1) * channels];
+            output.extend(frame.iter().zip(tail).map(|(s, old)| s * t.sin() + old * t.cos()));
+        }
+        self.position += samples.len() / channels;
+    }
+
+    /// The rest of the tail, faded out over silence.
+    fn finish(&mut self, output: &mut Vec<f32>) {
+        let silence = vec![0.; (self.frames() - self.position) * CHANNELS as usize];
+        self.mix(&silence, output);
+    }
+}
+
+/// Push `data` into the ring buffer, waiting for room. False if playback was
+/// stopped or a seek is pending.
+fn send_samples(data: &[f32], rb_tx: &mut Producer<f32>, status: &PlayerStatus) -> bool {
+    let mut sent_size = 0;
+    while sent_size < data.len() {
+        if status.stop_requested.load(Relaxed) || status.is_seeking() {
+            return false;
+        }
+        if sent_size > 0 {
+            sleep(10_000).unwrap();
+        }
+
+        let current_size = rb_tx.push_slice(&data[sent_size..]);
+        sent_size += current_size;
+
+        assert_eq!(sent_size % CHANNELS as usize, 0);
+
+        status.frames_decoded.fetch_add(current_size / CHANNELS as usize, Relaxed);
+    }
+
+    true
+}
+
+/// Have the callback drop everything queued before a seek, and wait until it has.
+fn flush(rb_tx: &Producer<f32>, status: &PlayerStatus) {
+    status.stale_samples.store(rb_tx.len(), SeqCst);
+    // also waits out a pause, the callback only discards while running
+    while status.stale_samples.load(SeqCst) > 0 && !status.stop_requested.load(Relaxed) {
+        sleep(1_000).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume_steps() {
+        assert!((VolumeScale::Linear.step(0.5, true) - 0.55).abs() < 1e-6);
+        assert_eq!(VolumeScale::Linear.step(0.02, false), 0.);
+        assert_eq!(VolumeScale::Linear.step(0.98, true), 1.);
+
+        // 2 dB either way, into silence below the floor and back out of it
+        assert!((VolumeScale::Decibel.step(0.5, false) - 0.5 * 10f32.powf(-0.1)).abs() < 1e-6);
+        assert_eq!(VolumeScale::Decibel.step(0.0011, false), 0.);
+        assert_eq!(VolumeScale::Decibel.step(0., true), VolumeScale::DB_FLOOR);
+        assert_eq!(VolumeScale::Decibel.step(1., true), 1.);
+    }
+}
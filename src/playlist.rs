@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// One track of a queue, from the command line or a playlist file.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    /// Title given by the playlist, e.g. from `#EXTINF`.
+    pub title: Option<String>,
+}
+
+impl Entry {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, title: None }
+    }
+}
+
+pub fn is_playlist(path: &Path) -> bool {
+    matches!(extension(path).as_str(), "m3u" | "m3u8" | "pls")
+}
+
+/// Tracks listed in an M3U, M3U8 or PLS file. Relative paths are resolved
+/// against the playlist's directory.
+pub fn read(path: &Path) -> io::Result<Vec<Entry>> {
+    let bytes = fs::read(path)?;
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        // plain .m3u predates UTF-8 and is usually Latin-1
+        Err(e) => e.into_bytes().iter().map(|&b| char::from(b)).collect(),
+    };
+    let text = text.trim_start_matches('\u{feff}');
+
+    let base = path.parent().unwrap_or_else(|| Path::new(""));
+    let entries = match extension(path).as_str() {
+        "pls" => parse_pls(text, base),
+        _ => parse_m3u(text, base),
+    };
+    Ok(entries)
+}
+
+fn parse_m3u(text: &str, base: &Path) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut title = None;
+
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>,<title>
+            title = info.split_once(',')
+                .map(|(_, title)| title.trim().to_string())
+                .filter(|title| !title.is_empty());
+        } else if !line.starts_with('#') {
+            entries.push(Entry {
+                path: resolve(line, base),
+                title: title.take(),
+            });
+        }
+    }
+
+    entries
+}
+
+fn parse_pls(text: &str, base: &Path) -> Vec<Entry> {
+    // File1=, Title1=... may come in any order
+    let mut files = BTreeMap::new();
+    let mut titles = BTreeMap::new();
+
+    for line in text.lines().map(str::trim) {
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim().to_lowercase(), value.trim()),
+            None => continue,
+        };
+
+        if let Some(index) = key.strip_prefix("file").and_then(|i| i.parse::<usize>().ok()) {
+            files.insert(index, resolve(value, base));
+        } else if let Some(index) = key.strip_prefix("title").and_then(|i| i.parse::<usize>().ok()) {
+            titles.insert(index, value.to_string());
+        }
+    }
+
+    files.into_iter()
+        .map(|(index, path)| Entry { path, title: titles.remove(&index) })
+        .collect()
+}
+
+/// A playlist location as a path: URLs are kept, `file://` URLs decoded, and
+/// relative paths joined to `base`.
+fn resolve(location: &str, base: &Path) -> PathBuf {
+    if let Some(path) = location.strip_prefix("file://") {
+        return PathBuf::from(percent_decode(path));
+    }
+    if location.contains("://") {
+        return PathBuf::from(location);
+    }
+
+    let path = Path::new(location);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        base.join(path)
+    }
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn m3u_titles_and_paths() {
+        let text = "#EXTM3U\n#EXTINF:215,Artist - Song\nsongs/a.flac\n\n/music/b.mp3\n\
+                    #EXTINF:-1,\nfile:///music/My%20Song.ogg\nhttp://radio.example/stream\n";
+        let entries = parse_m3u(text, Path::new("/lists"));
+
+        let paths: Vec<_> = entries.iter().map(|e| e.path.clone()).collect();
+        assert_eq!(paths, [
+            PathBuf::from("/lists/songs/a.flac"),
+            PathBuf::from("/music/b.mp3"),
+            PathBuf::from("/music/My Song.ogg"),
+            PathBuf::from("http://radio.example/stream"),
+        ]);
+        let titles: Vec<_> = entries.iter().map(|e| e.title.as_deref()).collect();
+        assert_eq!(titles, [Some("Artist - Song"), None, None, None]);
+    }
+
+    #[test]
+    fn pls_entries_out_of_order() {
+        let text = "[playlist]\nTitle2=Second\nFile2=b.mp3\nFile1=a.mp3\nNumberOfEntries=2\n";
+        let entries = parse_pls(text, Path::new("dir"));
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, Path::new("dir/a.mp3"));
+        assert_eq!(entries[0].title, None);
+        assert_eq!(entries[1].path, Path::new("dir/b.mp3"));
+        assert_eq!(entries[1].title.as_deref(), Some("Second"));
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b%C3%A9"), "a bé");
+        // stray or broken escapes are kept as they are
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+}
@@ -0,0 +1,155 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One audible play of a track, from stream start to the last sample played.
+pub struct PlayRecord {
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub played: f64,
+    pub duration: f64,
+    pub path: String,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub device: String,
+}
+
+const CSV_HEADER: &str = "start,end,played,duration,path,artist,title,album,device";
+
+/// Append `record` to `log`, as JSON lines for `.json`/`.jsonl` files and CSV otherwise.
+pub fn append(log: &Path, record: &PlayRecord) -> io::Result<()> {
+    let json = matches!(
+        log.extension().and_then(|e| e.to_str()),
+        Some("json") | Some("jsonl")
+    );
+    let is_new = !log.exists();
+
+    let mut file = OpenOptions::new().create(true).append(true).open(log)?;
+
+    if json {
+        let body: Vec<String> = CSV_HEADER.split(',')
+            .zip(record.fields())
+            .map(|(key, val)| match val {
+                Field::Text(text) => format!("\"{}\":{}", key, json_string(&text)),
+                Field::Number(n) => format!("\"{}\":{:.3}", key, n),
+                Field::Missing => format!("\"{}\":null", key),
+            })
+            .collect();
+        writeln!(file, "{{{}}}", body.join(","))
+    } else {
+        if is_new {
+            writeln!(file, "{}", CSV_HEADER)?;
+        }
+        let row: Vec<String> = record.fields().into_iter()
+            .map(|val| match val {
+                Field::Text(text) => csv_field(&text),
+                Field::Number(n) => format!("{:.3}", n),
+                Field::Missing => String::new(),
+            })
+            .collect();
+        writeln!(file, "{}", row.join(","))
+    }
+}
+
+/// Append a preformatted line, for user-defined log formats.
+pub fn append_line(log: &Path, line: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(log)?;
+    writeln!(file, "{}", line)
+}
+
+enum Field {
+    Text(String),
+    Number(f64),
+    Missing,
+}
+
+impl PlayRecord {
+    // same order as CSV_HEADER
+    fn fields(&self) -> Vec<Field> {
+        let text = |v: &Option<String>| match v {
+            Some(v) => Field::Text(v.clone()),
+            None => Field::Missing,
+        };
+
+        vec![
+            Field::Text(iso8601(self.start)),
+            Field::Text(iso8601(self.end)),
+            Field::Number(self.played),
+            Field::Number(self.duration),
+            Field::Text(self.path.clone()),
+            text(&self.artist),
+            text(&self.title),
+            text(&self.album),
+            Field::Text(self.device.clone()),
+        ]
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// UTC timestamp like `2020-05-01T12:34:56Z`.
+fn iso8601(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as i64;
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+
+    // days since epoch -> civil date (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(iso8601(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601(UNIX_EPOCH + Duration::from_secs(1_588_336_496)), "2020-05-01T12:34:56Z");
+        // a leap day
+        assert_eq!(iso8601(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a, \"b\""), "\"a, \"\"b\"\"\"");
+        assert_eq!(json_string("say \"hi\"\\\n\u{1}"), "\"say \\\"hi\\\"\\\\\\n\\u0001\"");
+    }
+}
@@ -0,0 +1,227 @@
+use std::cmp::Ordering;
+use std::path::Path;
+
+use fluxplayer::infer::Patterns;
+use fluxplayer::playlist::Entry;
+use fluxplayer::tags::{self, Tags};
+
+/// What the upcoming part of the queue is ordered and grouped by, cycled with `s`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortKey {
+    /// Command line and playlist order.
+    Original,
+    Album,
+    Artist,
+    Date,
+    Track,
+}
+
+impl SortKey {
+    pub fn cycle(self) -> Self {
+        match self {
+            Self::Original => Self::Album,
+            Self::Album => Self::Artist,
+            Self::Artist => Self::Date,
+            Self::Date => Self::Track,
+            Self::Track => Self::Original,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Original => "original order",
+            Self::Album => "album",
+            Self::Artist => "artist",
+            Self::Date => "date",
+            Self::Track => "track number",
+        }
+    }
+
+    /// The group a track is listed under, e.g. `Artist - Album`. None when the
+    /// queue isn't grouped.
+    pub fn group(self, tags: &Tags) -> Option<String> {
+        match self {
+            Self::Original | Self::Track => None,
+            Self::Album => Some(match album_artist(tags) {
+                Some(artist) => format!("{} - {}", artist, tags.get("album").unwrap_or("Unknown Album")),
+                None => tags.get("album").unwrap_or("Unknown Album").to_string(),
+            }),
+            Self::Artist => Some(tags.values("artist").first().unwrap_or(&"Unknown Artist").to_string()),
+            Self::Date => Some(year(tags).unwrap_or("Unknown Year").to_string()),
+        }
+    }
+}
+
+/// Sort `order`, indices into `entries`, by `key`. Ties are left in their
+/// original order, so sorting by album keeps each album in track order.
+///
+/// Returns the groups the tracks now fall into, and how many are in each.
+pub fn sort(entries: &[Entry], order: &mut [usize], key: SortKey, patterns: &Patterns) -> Vec<(String, usize)> {
+    order.sort_unstable();
+    if key == SortKey::Original {
+        return Vec::new();
+    }
+
+    let keyed: Vec<(usize, Tags)> = order.iter().map(|&i| (i, read_tags(&entries[i].path, patterns))).collect();
+    sort_tagged(order, keyed, key)
+}
+
+/// `sort()`, once the tags of each track in `order` are read into `keyed`.
+fn sort_tagged(order: &mut [usize], mut keyed: Vec<(usize, Tags)>, key: SortKey) -> Vec<(String, usize)> {
+    keyed.sort_by(|(_, a), (_, b)| compare(a, b, key));
+
+    let mut groups: Vec<(String, usize)> = Vec::new();
+    for (slot, (i, tags)) in order.iter_mut().zip(keyed) {
+        *slot = i;
+
+        let group = key.group(&tags).unwrap_or_default();
+        match groups.last_mut() {
+            Some((last, count)) if *last == group => *count += 1,
+            _ => groups.push((group, 1)),
+        }
+    }
+    groups
+}
+
+/// Where in `order` to go on from track `i` to reach the first track of the
+/// next `key` group, album or artist, or of the previous one when `!forward`.
+///
+/// Going forward from the last group runs off the end of the queue. Going back
+/// from the first one restarts it.
+pub fn jump(entries: &[Entry], order: &[usize], i: usize, key: SortKey, forward: bool, patterns: &Patterns) -> usize {
+    jump_by(order.len(), i, forward, |i| key.group(&read_tags(&entries[order[i]].path, patterns)))
+}
+
+/// `jump()` in a queue of `len` tracks, with `group(i)` the group of track `i`.
+fn jump_by(len: usize, i: usize, forward: bool, group: impl Fn(usize) -> Option<String>) -> usize {
+    let current = group(i);
+
+    if forward {
+        return (i + 1..len)
+            .find(|&j| group(j) != current)
+            .unwrap_or(len);
+    }
+
+    let start = |i: usize, of: &Option<String>| {
+        (0..i).rev()
+            .take_while(|&j| group(j) == *of)
+            .last()
+            .unwrap_or(i)
+    };
+    match start(i, &current) {
+        0 => 0,
+        first => start(first - 1, &group(first - 1)),
+    }
+}
+
+fn compare(a: &Tags, b: &Tags, key: SortKey) -> Ordering {
+    let position = |tags: &Tags| (number(tags.get("disc")), number(tags.get("track")));
+    let album = |tags: &Tags| (year(tags).map(str::to_string), tags.get("album").map(str::to_lowercase));
+
+    match key {
+        SortKey::Original => Ordering::Equal,
+        SortKey::Album => album_artist(a).map(str::to_lowercase).cmp(&album_artist(b).map(str::to_lowercase))
+            .then_with(|| album(a).cmp(&album(b)))
+            .then_with(|| position(a).cmp(&position(b))),
+        SortKey::Artist => artist(a).cmp(&artist(b))
+            .then_with(|| album(a).cmp(&album(b)))
+            .then_with(|| position(a).cmp(&position(b))),
+        SortKey::Date => year(a).cmp(&year(b))
+            .then_with(|| album(a).cmp(&album(b)))
+            .then_with(|| position(a).cmp(&position(b))),
+        SortKey::Track => position(a).cmp(&position(b)),
+    }
+}
+
+/// How `entry` is listed without opening it: its playlist title, or its file name.
+#[cfg_attr(not(feature = "tui"), allow(dead_code))]
+pub fn name(entry: &Entry) -> String {
+    entry.title.clone().unwrap_or_else(|| {
+        entry.path.file_name().map_or_else(|| entry.path.display().to_string(), |name| name.to_string_lossy().into_owned())
+    })
+}
+
+pub fn read_tags(path: &Path, patterns: &Patterns) -> Tags {
+    match ffmpeg::format::input(&path) {
+        Ok(input) => tags::read_tags(&input, path, patterns),
+        Err(_) => Tags { entries: Vec::new(), inferred: false },
+    }
+}
+
+fn album_artist(tags: &Tags) -> Option<&str> {
+    tags.get("album_artist").or_else(|| tags.values("artist").first().copied())
+}
+
+fn artist(tags: &Tags) -> Option<String> {
+    tags.values("artist").first().map(|artist| artist.to_lowercase())
+}
+
+/// `2003` from `2003-05-12`, `2003` and the like.
+fn year(tags: &Tags) -> Option<&str> {
+    let date = tags.get("date").or_else(|| tags.get("year"))?.trim();
+    Some(date.get(..4).unwrap_or(date))
+}
+
+/// `3` from `3/12`, missing numbers sorting last.
+fn number(value: Option<&str>) -> u32 {
+    value.and_then(|v| v.split('/').next())
+        .and_then(|n| n.trim().parse().ok())
+        .unwrap_or(u32::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(entries: &[(&str, &str)]) -> Tags {
+        Tags { entries: entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(), inferred: false }
+    }
+
+    #[test]
+    fn sort_by_album() {
+        let keyed = vec![
+            (0, tags(&[("artist", "B"), ("album", "Second"), ("track", "2/9")])),
+            (1, tags(&[("artist", "A"), ("album", "First"), ("track", "1")])),
+            (2, tags(&[("artist", "B"), ("album", "Second"), ("track", "1/9")])),
+            (3, tags(&[("album", "Loose")])),
+        ];
+        let mut order = [0, 1, 2, 3];
+        let groups = sort_tagged(&mut order, keyed, SortKey::Album);
+        // no artist sorts first
+        assert_eq!(order, [3, 1, 2, 0]);
+        assert_eq!(groups, vec![("Loose".to_string(), 1), ("A - First".to_string(), 1), ("B - Second".to_string(), 2)]);
+    }
+
+    #[test]
+    fn sort_by_date_and_track() {
+        let keyed = || vec![
+            (0, tags(&[("date", "2003-05-12"), ("track", "3")])),
+            (1, tags(&[("year", "1999"), ("track", "1")])),
+            (2, tags(&[("track", "2")])),
+        ];
+        let mut order = [0, 1, 2];
+        let groups = sort_tagged(&mut order, keyed(), SortKey::Date);
+        assert_eq!(order, [2, 1, 0]);
+        assert_eq!(groups[0], ("Unknown Year".to_string(), 1));
+
+        let groups = sort_tagged(&mut order, keyed(), SortKey::Track);
+        assert_eq!(order, [1, 2, 0]);
+        assert_eq!(groups, vec![(String::new(), 3)]);
+    }
+
+    #[test]
+    fn jumps() {
+        let albums = ["A", "A", "B", "B", "B", "C"];
+        let group = |i: usize| Some(albums[i].to_string());
+        assert_eq!(jump_by(albums.len(), 0, true, group), 2);
+        assert_eq!(jump_by(albums.len(), 3, true, group), 5);
+        // off the end from the last album
+        assert_eq!(jump_by(albums.len(), 5, true, group), 6);
+
+        // back to the start of the album before, from anywhere in this one
+        assert_eq!(jump_by(albums.len(), 3, false, group), 0);
+        assert_eq!(jump_by(albums.len(), 5, false, group), 2);
+        // the first album restarts
+        assert_eq!(jump_by(albums.len(), 1, false, group), 0);
+    }
+}
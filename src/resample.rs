@@ -0,0 +1,126 @@
+use ffmpeg::{
+    decoder,
+    format::Sample,
+    frame::Audio,
+    software::resampling::Context,
+    ChannelLayout
+};
+
+use crate::decoder::frame_samples;
+use crate::mix::{Downmix, Mixer};
+use crate::{CHANNEL_LAYOUT, SAMPLE_TYPE};
+
+/// Converts decoded frames to the output format.
+///
+/// swresample only changes the sample format and rate; channels are mixed to
+/// stereo by a `Mixer` afterwards, so its matrix can be replaced.
+pub struct Resampler {
+    swr: Context,
+    format: Sample,
+    rate: u32,
+    output_rate: u32,
+    layout: ChannelLayout,
+    mixer: Option<Mixer>,
+    frame: Audio,
+    mixed: Vec<f32>,
+}
+
+/// The decoder's channel layout, or the default one for its channel count when it
+/// doesn't say.
+pub fn input_layout(audio: &decoder::Audio) -> ChannelLayout {
+    if audio.channel_layout().is_empty() {
+        ChannelLayout::default(i32::from(audio.channels()))
+    } else {
+        audio.channel_layout()
+    }
+}
+
+impl Resampler {
+    /// Whether frames from `audio` have to be converted before they can be played
+    /// at `output_rate`. Planar float is only interleaved, by the decoder.
+    pub fn is_needed(audio: &decoder::Audio, output_rate: u32) -> bool {
+        !(matches!(audio.format(), Sample::F32(_))
+            && input_layout(audio) == CHANNEL_LAYOUT
+            && audio.rate() == output_rate)
+    }
+
+    pub fn new(audio: &decoder::Audio, output_rate: u32) -> Result<Self, ffmpeg::Error> {
+        let layout = input_layout(audio);
+
+        Ok(Self {
+            swr: context(audio.format(), audio.rate(), layout, output_rate)?,
+            format: audio.format(),
+            rate: audio.rate(),
+            output_rate,
+            layout,
+            mixer: if layout == CHANNEL_LAYOUT {
+                None
+            } else {
+                Some(Mixer::for_layout(layout, usize::from(audio.channels()), Downmix::Stereo))
+            },
+            frame: Audio::empty(),
+            mixed: Vec::new(),
+        })
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    pub fn mixer(&self) -> Option<&Mixer> {
+        self.mixer.as_ref()
+    }
+
+    pub fn set_mixer(&mut self, mixer: Mixer) {
+        self.mixer = Some(mixer);
+    }
+
+    pub fn into_mixer(self) -> Option<Mixer> {
+        self.mixer
+    }
+
+    /// Drop buffered samples, e.g. after seeking.
+    pub fn reset(&mut self) -> Result<(), ffmpeg::Error> {
+        self.swr = context(self.format, self.rate, self.layout, self.output_rate)?;
+        Ok(())
+    }
+
+    pub fn run(&mut self, frame: &mut Audio) -> Result<(), ffmpeg::Error> {
+        if frame.channel_layout().is_empty() {
+            frame.set_channel_layout(self.layout);
+        }
+        self.swr.run(frame, &mut self.frame)?;
+        self.mix();
+        Ok(())
+    }
+
+    /// Drain buffered samples, false once there are none left.
+    pub fn flush(&mut self) -> bool {
+        let flushed = self.swr.delay().is_some() && matches!(self.swr.flush(&mut self.frame), Ok(Some(_)));
+        if flushed {
+            self.mix();
+        }
+        flushed
+    }
+
+    /// The last converted block of interleaved stereo samples.
+    pub fn output(&self) -> &[f32] {
+        match self.mixer {
+            Some(_) => &self.mixed,
+            None => frame_samples(&self.frame),
+        }
+    }
+
+    fn mix(&mut self) {
+        if let Some(ref mixer) = self.mixer {
+            mixer.mix(frame_samples(&self.frame), &mut self.mixed);
+        }
+    }
+}
+
+fn context(format: Sample, rate: u32, layout: ChannelLayout, output_rate: u32) -> Result<Context, ffmpeg::Error> {
+    ffmpeg::software::resampler(
+        (format, layout, rate),
+        (SAMPLE_TYPE, layout, output_rate),
+    )
+}
@@ -0,0 +1,51 @@
+use fluxplayer::template::clock;
+
+/// Totals over everything played this session, printed on exit.
+#[derive(Default)]
+pub struct SessionStats {
+    tracks: usize,
+    skips: usize,
+    seconds: f64,
+    // bits of the encoded streams, for what was played of each track
+    bits: f64,
+    // seconds of the tracks whose bit rate is known
+    rated_seconds: f64,
+}
+
+impl SessionStats {
+    /// Count a track of which `seconds` were played, at `bit_rate` (0 if
+    /// unknown). `skipped` if the user moved on before its end.
+    pub fn record(&mut self, seconds: f64, bit_rate: usize, skipped: bool) {
+        self.tracks += 1;
+        if skipped {
+            self.skips += 1;
+        }
+        self.seconds += seconds;
+        if bit_rate > 0 {
+            self.bits += bit_rate as f64 * seconds;
+            self.rated_seconds += seconds;
+        }
+    }
+
+    /// Print the summary, with the `underruns` the output had and the
+    /// `bytes_read` from files and streams.
+    pub fn print(&self, underruns: usize, bytes_read: u64) {
+        if self.tracks == 0 {
+            return;
+        }
+
+        println!("\n{}[Session]", " ".repeat(17));
+        println!("{:>16}: {} ({} skipped)",
+                "Tracks Played", self.tracks, self.skips);
+        println!("{:>16}: {}",
+                "Listening Time", clock(self.seconds));
+        println!("{:>16}: {}",
+                "Underruns", underruns);
+        if self.rated_seconds > 0. {
+            println!("{:>16}: {:.1} kbps",
+                    "Avg. Bit Rate", self.bits / self.rated_seconds / 1000.);
+        }
+        println!("{:>16}: {:.1} MB",
+                "Data Read", bytes_read as f64 / 1_000_000.);
+    }
+}
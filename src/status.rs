@@ -0,0 +1,314 @@
+use std::sync::{
+    Mutex,
+    atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering::Relaxed}
+};
+
+use crate::{DEFAULT_VOLUME, SAMPLE_RATE};
+
+/// How far dimming drops the output, in dB.
+pub const DIM_DB: f32 = 20.;
+
+const NO_BOUNDARY: usize = usize::MAX;
+const NO_ORIGIN: usize = usize::MAX;
+
+/// Playback state shared between the player, its decode thread and the output callback.
+pub struct PlayerStatus {
+    pub is_decoding: AtomicBool,
+    pub is_playing: AtomicBool,
+    pub is_primed: AtomicBool,
+    pub is_paused: AtomicBool,
+    pub is_muted: AtomicBool,
+    pub is_dimmed: AtomicBool,
+    pub stop_requested: AtomicBool,
+    pub frames_decoded: AtomicUsize,
+    pub frames_played: AtomicUsize,
+    /// Samples left in the ring buffer by a stopped track, for the callback to skip.
+    pub stale_samples: AtomicUsize,
+    /// Seek target waiting for the decode thread, in seconds.
+    pub seek_request: Mutex<Option<f64>>,
+    /// Set while the end of a decoded track is held back from the ring buffer,
+    /// to be crossfaded into the next one.
+    pub is_holding: AtomicBool,
+    /// The samples held back.
+    pub tail: Mutex<Vec<f32>>,
+    /// Frames to fade out over before the callback stops, 0 unless interrupted.
+    pub fade_out: AtomicUsize,
+    /// Set by the callback once the fade out has reached silence.
+    pub is_faded: AtomicBool,
+    /// Buffers the callback couldn't fill while the track was still decoding,
+    /// over the whole session.
+    pub underruns: AtomicUsize,
+    /// Bytes read from files and streams, over the whole session.
+    pub bytes_read: AtomicU64,
+    /// What an internet radio station says is playing, from its ICY metadata.
+    pub stream_title: Mutex<Option<String>>,
+    // position of the last seek (f64 bits) and the value of frames_played there
+    origin: AtomicU64,
+    origin_frame: AtomicUsize,
+    // where a loop last jumped back to (f64 bits) and the value of frames_played
+    // once that's heard, NO_ORIGIN if it hasn't
+    loop_origin: AtomicU64,
+    loop_origin_frame: AtomicUsize,
+    // linear volume (f32 bits), read by the callback
+    volume: AtomicU32,
+    // rate the output stream runs at, in Hz
+    sample_rate: AtomicU32,
+    // frames left to play before a gaplessly queued track starts, and how many
+    // frames of the current one had been decoded when it was queued
+    boundary: AtomicUsize,
+    boundary_decoded: AtomicUsize,
+    // frames_played where the current track started, and how many have started
+    track_start: AtomicUsize,
+    track_number: AtomicUsize,
+}
+
+impl PlayerStatus {
+    pub fn new() -> Self {
+        Self {
+            is_decoding: AtomicBool::new(false),
+            is_playing: AtomicBool::new(false),
+            is_primed: AtomicBool::new(false),
+            is_paused: AtomicBool::new(false),
+            is_muted: AtomicBool::new(false),
+            is_dimmed: AtomicBool::new(false),
+            stop_requested: AtomicBool::new(false),
+            frames_decoded: AtomicUsize::new(0),
+            frames_played: AtomicUsize::new(0),
+            stale_samples: AtomicUsize::new(0),
+            seek_request: Mutex::new(None),
+            is_holding: AtomicBool::new(false),
+            tail: Mutex::new(Vec::new()),
+            fade_out: AtomicUsize::new(0),
+            is_faded: AtomicBool::new(false),
+            underruns: AtomicUsize::new(0),
+            bytes_read: AtomicU64::new(0),
+            stream_title: Mutex::new(None),
+            origin: AtomicU64::new(0),
+            origin_frame: AtomicUsize::new(0),
+            loop_origin: AtomicU64::new(0),
+            loop_origin_frame: AtomicUsize::new(NO_ORIGIN),
+            volume: AtomicU32::new(DEFAULT_VOLUME.to_bits()),
+            sample_rate: AtomicU32::new(SAMPLE_RATE as u32),
+            boundary: AtomicUsize::new(NO_BOUNDARY),
+            boundary_decoded: AtomicUsize::new(0),
+            track_start: AtomicUsize::new(0),
+            track_number: AtomicUsize::new(0),
+        }
+    }
+
+    /// Rate of the output stream, which frame counts are in.
+    pub fn sample_rate(&self) -> f64 {
+        f64::from(self.sample_rate.load(Relaxed))
+    }
+
+    pub fn set_sample_rate(&self, rate: u32) {
+        self.sample_rate.store(rate, Relaxed);
+    }
+
+    /// Position in the track decoded up to.
+    pub fn decoded(&self) -> f64 {
+        // decoding is past any loop jump already
+        let origin = match self.loop_origin_frame.load(Relaxed) {
+            NO_ORIGIN => self.origin(),
+            _ => f64::from_bits(self.loop_origin.load(Relaxed)),
+        };
+        origin + self.frames_decoded.load(Relaxed) as f64 / self.sample_rate()
+    }
+
+    /// Position in the track played up to.
+    pub fn position(&self) -> f64 {
+        let played = self.frames_played.load(Relaxed);
+        let (origin, origin_frame) = match self.loop_origin_frame.load(Relaxed) {
+            NO_ORIGIN => (self.origin(), self.origin_frame.load(Relaxed)),
+            frame if played >= frame => (f64::from_bits(self.loop_origin.load(Relaxed)), frame),
+            _ => (self.origin(), self.origin_frame.load(Relaxed)),
+        };
+        origin + played.saturating_sub(origin_frame) as f64 / self.sample_rate()
+    }
+
+    /// Seconds of the current track actually played, not counting what was
+    /// skipped by seeking.
+    pub fn played(&self) -> f64 {
+        self.frames_played.load(Relaxed).saturating_sub(self.track_start()) as f64 / self.sample_rate()
+    }
+
+    /// Value of `frames_played` where the current track started.
+    pub fn track_start(&self) -> usize {
+        self.track_start.load(Relaxed)
+    }
+
+    /// Counts up every time a gaplessly queued track starts playing.
+    pub fn track_number(&self) -> usize {
+        self.track_number.load(Relaxed)
+    }
+
+    /// Start a new track, counting from the start of playback or after a
+    /// gapless boundary.
+    pub fn reset_track(&self, origin: f64) {
+        self.boundary.store(NO_BOUNDARY, Relaxed);
+        self.track_start.store(self.frames_played.load(Relaxed), Relaxed);
+        self.set_origin(origin);
+    }
+
+    /// Have the next track start after `frames` more frames leave the ring buffer.
+    pub fn set_boundary(&self, frames: usize) {
+        self.boundary_decoded.store(self.frames_decoded.load(Relaxed), Relaxed);
+        self.boundary.store(frames, Relaxed);
+    }
+
+    pub fn has_boundary(&self) -> bool {
+        self.boundary.load(Relaxed) != NO_BOUNDARY
+    }
+
+    /// Count `frames` leaving the ring buffer, played or discarded. Called by the
+    /// output callback, which moves on to the queued track once its first frame is out.
+    pub fn advance(&self, frames: usize) {
+        let remaining = self.boundary.load(Relaxed);
+        if remaining == NO_BOUNDARY {
+            return;
+        }
+        if frames < remaining {
+            self.boundary.store(remaining - frames, Relaxed);
+            return;
+        }
+
+        let start = self.frames_played.load(Relaxed).saturating_sub(frames - remaining);
+        self.boundary.store(NO_BOUNDARY, Relaxed);
+        let decoded = self.boundary_decoded.load(Relaxed);
+        let _ = self.frames_decoded.fetch_update(Relaxed, Relaxed, |d| Some(d.saturating_sub(decoded)));
+        self.track_start.store(start, Relaxed);
+        self.origin_frame.store(start, Relaxed);
+        self.origin.store(0f64.to_bits(), Relaxed);
+        self.loop_origin_frame.store(NO_ORIGIN, Relaxed);
+        self.track_number.fetch_add(1, Relaxed);
+    }
+
+    /// Count positions from `seconds` on, for a track that starts or was sought there.
+    /// Resets the decoded count.
+    pub fn set_origin(&self, seconds: f64) {
+        self.frames_decoded.store(0, Relaxed);
+        self.origin_frame.store(self.frames_played.load(Relaxed), Relaxed);
+        self.origin.store(seconds.to_bits(), Relaxed);
+        self.loop_origin_frame.store(NO_ORIGIN, Relaxed);
+    }
+
+    /// Count positions from `seconds` again once the frame decoded `frames` ago
+    /// is heard, for a loop that jumped back there. What was decoded before the
+    /// jump is still in the ring buffer. Resets the decoded count to `frames`.
+    ///
+    /// A loop shorter than the ring buffer may show its start a little early.
+    pub fn rebase(&self, seconds: f64, frames: usize) {
+        // the previous jump is taken as heard
+        let start = match self.loop_origin_frame.load(Relaxed) {
+            NO_ORIGIN => self.origin_frame.load(Relaxed),
+            frame => {
+                self.origin.store(self.loop_origin.load(Relaxed), Relaxed);
+                self.origin_frame.store(frame, Relaxed);
+                frame
+            }
+        };
+        let decoded = self.frames_decoded.swap(frames, Relaxed);
+        self.loop_origin.store(seconds.to_bits(), Relaxed);
+        self.loop_origin_frame.store(start + decoded.saturating_sub(frames), Relaxed);
+    }
+
+    fn origin(&self) -> f64 {
+        f64::from_bits(self.origin.load(Relaxed))
+    }
+
+    /// Linear volume, regardless of muting.
+    pub fn volume(&self) -> f32 {
+        f32::from_bits(self.volume.load(Relaxed))
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.volume.store(volume.clamp(0., 1.).to_bits(), Relaxed);
+    }
+
+    /// What the output is scaled by, 0 while muted and `DIM_DB` less while dimmed.
+    pub fn gain(&self) -> f32 {
+        if self.is_muted.load(Relaxed) {
+            0.
+        } else if self.is_dimmed.load(Relaxed) {
+            self.volume() * 10f32.powf(-DIM_DB / 20.)
+        } else {
+            self.volume()
+        }
+    }
+
+    /// Volume as shown to the user, e.g. `50%`, `50% dimmed` or `muted`.
+    pub fn describe_volume(&self) -> String {
+        if self.is_muted.load(Relaxed) {
+            "muted".to_string()
+        } else if self.is_dimmed.load(Relaxed) {
+            format!("{:.0}% dimmed", self.volume() * 100.)
+        } else {
+            format!("{:.0}%", self.volume() * 100.)
+        }
+    }
+
+    pub fn is_seeking(&self) -> bool {
+        self.seek_request.lock().unwrap().is_some()
+    }
+
+    /// Short label for the current state, e.g. `PLAYING`.
+    pub fn state(&self) -> &'static str {
+        if !self.is_playing.load(Relaxed) {
+            "STOPPED"
+        } else if self.is_paused.load(Relaxed) {
+            "PAUSED"
+        } else if !self.is_primed.load(Relaxed) {
+            "PRIMING"
+        } else {
+            "PLAYING"
+        }
+    }
+}
+
+impl Default for PlayerStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loop_rebase() {
+        let status = PlayerStatus::new();
+        status.set_sample_rate(10);
+        status.set_origin(0.);
+
+        // a loop back to 2 s after 8 s, 2 s of it decoded too
+        status.frames_decoded.store(100, Relaxed);
+        status.rebase(2., 20);
+        assert_eq!(status.decoded(), 4.);
+
+        let position_at = |frames| {
+            status.frames_played.store(frames, Relaxed);
+            status.position()
+        };
+        assert_eq!(position_at(50), 5.);
+        assert_eq!(position_at(80), 2.);
+        assert_eq!(position_at(95), 3.5);
+
+        // seeking forgets the loop
+        status.set_origin(1.);
+        assert_eq!(position_at(105), 2.);
+    }
+
+    #[test]
+    fn mute_and_dim() {
+        let status = PlayerStatus::new();
+        status.set_volume(0.5);
+        status.is_dimmed.store(true, Relaxed);
+        assert!((status.gain() - 0.05).abs() < 1e-6);
+        assert_eq!(status.describe_volume(), "50% dimmed");
+
+        status.is_muted.store(true, Relaxed);
+        assert_eq!(status.gain(), 0.);
+        assert_eq!(status.describe_volume(), "muted");
+    }
+}
@@ -0,0 +1,75 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_int, c_void};
+use std::path::Path;
+use std::ptr;
+
+use ffmpeg::format::context::Input;
+
+// libavutil/opt.h, options of the AVIOContext are found through the format context
+const AV_OPT_SEARCH_CHILDREN: c_int = 1;
+
+/// Whether `path` is an `http://` or `https://` URL, which ffmpeg streams.
+pub fn is_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| {
+        let path = path.to_ascii_lowercase();
+        path.starts_with("http://") || path.starts_with("https://")
+    })
+}
+
+/// Open `path`, asking servers for ICY (SHOUTcast) metadata if it's a URL.
+pub fn open(path: &Path) -> Result<Input, ffmpeg::Error> {
+    if !is_url(path) {
+        return ffmpeg::format::input(&path);
+    }
+    let mut options = ffmpeg::Dictionary::new();
+    options.set("icy", "1");
+    // a stalled connection ends the stream, in microseconds, so it can be reconnected
+    options.set("rw_timeout", "15000000");
+    ffmpeg::format::input_with_dictionary(&path, options)
+}
+
+/// A header the server sent with its ICY metadata, e.g. `icy-name`.
+pub fn icy_header(input: &Input, name: &str) -> Option<String> {
+    let headers = option(input, "icy_metadata_headers")?;
+    headers.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// What the station says is playing, from the last ICY metadata packet:
+/// `StreamTitle='Artist - Title';StreamUrl='...';`.
+pub fn icy_title(input: &Input) -> Option<String> {
+    let packet = option(input, "icy_metadata_packet")?;
+    let start = packet.find("StreamTitle='")? + "StreamTitle='".len();
+    // titles may have quotes in them, the field ends at the quote before a ;
+    let rest = &packet[start..];
+    let title = rest.find("';").map_or(rest.trim_end_matches('\''), |end| &rest[..end]);
+    Some(title.trim().to_string()).filter(|title| !title.is_empty())
+}
+
+/// Bytes read so far from the file or server behind `input`.
+pub fn bytes_read(input: &Input) -> u64 {
+    // a statistic the I/O context keeps, which rust-ffmpeg doesn't expose
+    unsafe {
+        let pb = (*input.as_ptr()).pb;
+        if pb.is_null() { 0 } else { (*pb).bytes_read.max(0) as u64 }
+    }
+}
+
+/// A string option of the input's I/O context, None for files.
+fn option(input: &Input, name: &str) -> Option<String> {
+    let name = CString::new(name).ok()?;
+    let mut value: *mut u8 = ptr::null_mut();
+    // rust-ffmpeg has no access to AVOptions
+    unsafe {
+        let pb = (*input.as_ptr()).pb;
+        if pb.is_null() || ffmpeg::ffi::av_opt_get(pb as *mut c_void, name.as_ptr(), AV_OPT_SEARCH_CHILDREN, &mut value) < 0 || value.is_null() {
+            return None;
+        }
+        let text = CStr::from_ptr(value as *const _).to_string_lossy().into_owned();
+        ffmpeg::ffi::av_free(value as *mut c_void);
+        Some(text)
+    }
+}
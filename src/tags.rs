@@ -0,0 +1,324 @@
+use std::path::Path;
+
+use ffmpeg::format::context::Input;
+
+use crate::infer;
+use crate::stream;
+
+/// Tags shown for a track unless `--tags` says otherwise, in this order.
+pub const METADATA_WHITELIST: [&str; 8] = [
+    "title", "artist", "album", "album_artist", "track", "disc", "genre", "bpm"
+];
+
+/// Tags shown with `--classical`, where who composed and who performed matters
+/// more than the artist.
+pub const CLASSICAL_TAGS: [(&str, &str); 10] = [
+    ("composer", "Composer"),
+    ("work", "Work"),
+    ("movement", "Movement"),
+    ("title", "Title"),
+    ("performer", "Performer"),
+    // files that only name the performer here
+    ("artist", "Artist"),
+    ("conductor", "Conductor"),
+    ("ensemble", "Ensemble"),
+    ("album", "Album"),
+    ("track", "Track"),
+];
+
+// Tags that often hold several values, which ffmpeg joins with `;`.
+const MULTI_VALUE: [&str; 8] = [
+    "artist", "album_artist", "composer", "performer", "conductor", "ensemble", "genre", "mood"
+];
+
+// Most a gain tag may raise or lower the level, anything past it is a broken tag.
+const MAX_GAIN_DB: f32 = 60.;
+
+// Info lines are wrapped to this width, continuing under where the value starts.
+const LINE_WIDTH: usize = 80;
+const VALUE_COLUMN: usize = 18;
+
+pub struct Tags {
+    /// Every tag of the file, under normalized lowercase keys, in file order.
+    pub entries: Vec<(String, String)>,
+    pub inferred: bool,
+}
+
+impl Tags {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The work a classical track is a movement of, e.g. `Beethoven: Symphony No. 5`.
+    /// iTunes before 12.5 kept it in the grouping tag.
+    pub fn work(&self) -> Option<String> {
+        let work = self.get("work").or_else(|| self.get("grouping"))?;
+        match self.values("composer").first() {
+            Some(composer) => Some(format!("{}: {}", composer, work)),
+            None => Some(work.to_string()),
+        }
+    }
+
+    /// Each value of `key`, for tags that can have several.
+    pub fn values(&self, key: &str) -> Vec<&str> {
+        match self.get(key) {
+            Some(val) if MULTI_VALUE.contains(&key) => split_values(val).collect(),
+            Some(val) => vec![val],
+            None => Vec::new(),
+        }
+    }
+
+    /// Tags picked by `selection` and their labels, in its order, with multiple
+    /// values joined for display.
+    pub fn shown<'a>(&'a self, selection: &'a TagSelection) -> Vec<(&'a str, String)> {
+        match selection.keys {
+            None => self.entries.iter()
+                .map(|(k, _)| (k.as_str(), self.values(k).join(", ")))
+                .collect(),
+            Some(ref keys) => keys.iter()
+                .filter(|(key, _)| self.get(key).is_some())
+                .map(|(key, label)| (label.as_deref().unwrap_or(key), self.values(key).join(", ")))
+                .collect(),
+        }
+    }
+}
+
+/// Which tags are shown for a track, in what order, and under which labels.
+#[derive(Clone, Debug)]
+pub struct TagSelection {
+    // (key, label) pairs, None to show every tag as it is
+    keys: Option<Vec<(String, Option<String>)>>,
+}
+
+impl TagSelection {
+    /// Every tag the file has, in file order.
+    pub fn all() -> Self {
+        Self { keys: None }
+    }
+
+    /// `CLASSICAL_TAGS`, for `--classical`.
+    pub fn classical() -> Self {
+        Self {
+            keys: Some(CLASSICAL_TAGS.iter().map(|(key, label)| (key.to_string(), Some(label.to_string()))).collect()),
+        }
+    }
+
+    /// Parse a comma separated list of keys, each optionally relabelled, e.g.
+    /// `title,artist,album_artist=Album Artist`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let keys: Vec<_> = spec.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| match item.split_once('=') {
+                Some((key, label)) => (normalize_key(key.trim()), Some(label.trim().to_string())),
+                None => (normalize_key(item), None),
+            })
+            .collect();
+
+        if keys.is_empty() {
+            return Err("expected a comma separated list of tags".to_string());
+        }
+        Ok(Self { keys: Some(keys) })
+    }
+}
+
+impl Default for TagSelection {
+    fn default() -> Self {
+        Self {
+            keys: Some(METADATA_WHITELIST.iter().map(|key| (key.to_string(), None)).collect()),
+        }
+    }
+}
+
+/// All tags of `input`, guessed from `path` by `patterns` when the file has
+/// none of the `METADATA_WHITELIST` ones.
+pub fn read_tags(input: &Input, path: &Path, patterns: &infer::Patterns) -> Tags {
+    let mut entries: Vec<(String, String)> = Vec::new();
+
+    // Some containers (Ogg, Opus) keep their tags on the stream instead.
+    let stream = input.streams().best(ffmpeg::media::Type::Audio);
+    let stream_metadata = stream.as_ref().map(|s| s.metadata());
+    let sources = std::iter::once(input.metadata()).chain(stream_metadata);
+
+    for metadata in sources {
+        for (key, val) in metadata.iter() {
+            let key = normalize_key(key);
+            match entries.iter_mut().find(|(k, _)| *k == key) {
+                // repeated keys, e.g. one ARTIST comment per artist
+                Some((_, existing)) if MULTI_VALUE.contains(&key.as_str()) => {
+                    if !split_values(existing).any(|v| v == val.trim()) {
+                        existing.push(';');
+                        existing.push_str(val);
+                    }
+                }
+                Some(_) => (),
+                None => entries.push((key, val.to_string())),
+            }
+        }
+    }
+
+    // a URL's last part is rarely a song's name
+    if entries.iter().any(|(k, _)| METADATA_WHITELIST.contains(&k.as_str())) || stream::is_url(path) {
+        return Tags { entries, inferred: false };
+    }
+
+    Tags {
+        entries: infer::infer_metadata(path, patterns)
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+        inferred: true,
+    }
+}
+
+/// `label: value` info lines, with long and multi-line values (comments,
+/// lyrics) wrapped and indented to carry on under the first line.
+pub fn format_tag(label: &str, value: &str) -> Vec<String> {
+    let width = LINE_WIDTH - VALUE_COLUMN;
+    let wrapped: Vec<String> = value.lines()
+        .flat_map(|line| wrap(line.trim_end(), width))
+        .collect();
+
+    let mut lines = Vec::new();
+    for (i, line) in wrapped.iter().enumerate() {
+        if i == 0 {
+            lines.push(format!("{:>16}: {}", label, line));
+        } else if line.is_empty() {
+            lines.push(String::new());
+        } else {
+            lines.push(format!("{}{}", " ".repeat(VALUE_COLUMN), line));
+        }
+    }
+    if lines.is_empty() {
+        lines.push(format!("{:>16}: ", label));
+    }
+    lines
+}
+
+/// Split `line` at spaces into lines of at most `width` characters, where the
+/// words allow it.
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![String::new()];
+    for word in line.split_whitespace() {
+        let current = lines.last_mut().unwrap();
+        let len = current.chars().count();
+        if len == 0 {
+            current.push_str(word);
+        } else if len + 1 + word.chars().count() <= width {
+            current.push(' ');
+            current.push_str(word);
+        } else {
+            lines.push(word.to_string());
+        }
+    }
+    lines
+}
+
+/// The values of a multi-value tag, `;` (or NUL) separated.
+fn split_values(val: &str) -> impl Iterator<Item = &str> {
+    val.split([';', '\0'])
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+/// Lowercase `key`, and map the names some formats use to ffmpeg's.
+fn normalize_key(key: &str) -> String {
+    match key.to_lowercase().as_str() {
+        "tbpm" => "bpm".to_string(),
+        "tkey" | "initialkey" => "key".to_string(),
+        "albumartist" | "album artist" => "album_artist".to_string(),
+        "tracknumber" => "track".to_string(),
+        "discnumber" => "disc".to_string(),
+        // MP4 and Vorbis names for the classical work and movement tags
+        "\u{a9}wrk" => "work".to_string(),
+        "\u{a9}mvn" | "movementname" => "movement".to_string(),
+        "orchestra" => "ensemble".to_string(),
+        // Picard's recording ID, as Vorbis, ID3 TXXX and MP4 name it
+        "musicbrainz track id" | "musicbrainz_recordingid" => "musicbrainz_trackid".to_string(),
+        key => key.to_string(),
+    }
+}
+
+/// Whether the file is tagged as pre-emphasized (common for old CD rips).
+pub fn has_pre_emphasis(input: &Input) -> bool {
+    let stream = input.streams().best(ffmpeg::media::Type::Audio);
+    let stream_metadata = stream.as_ref().map(|s| s.metadata());
+
+    std::iter::once(input.metadata()).chain(stream_metadata).any(|metadata| {
+        metadata.iter().any(|(key, val)| {
+            matches!(key.to_lowercase().replace('_', "").as_str(), "preemphasis" | "emphasis")
+                && matches!(val.to_lowercase().as_str(), "1" | "yes" | "true" | "on" | "50/15")
+        })
+    })
+}
+
+/// Track gain in dB from REPLAYGAIN_TRACK_GAIN or Opus R128_TRACK_GAIN, held back
+/// so the tagged track peak doesn't clip.
+pub fn track_gain_db(input: &Input) -> Option<f32> {
+    let stream = input.streams().best(ffmpeg::media::Type::Audio);
+    let stream_metadata = stream.as_ref().map(|s| s.metadata());
+
+    let mut gain = None;
+    let mut peak = None;
+    for metadata in std::iter::once(input.metadata()).chain(stream_metadata) {
+        for (key, val) in metadata.iter() {
+            match key.to_lowercase().as_str() {
+                "replaygain_track_gain" => {
+                    gain = gain.or_else(|| parse_db(val));
+                }
+                // Q7.8 dB on top of the header output gain, which libavcodec already
+                // applies; +5 dB moves the -23 LUFS reference up to ReplayGain's.
+                "r128_track_gain" => {
+                    gain = gain.or_else(|| val.trim().parse::<i16>().ok().map(|q| f32::from(q) / 256. + 5.));
+                }
+                "replaygain_track_peak" => {
+                    peak = peak.or_else(|| val.trim().parse::<f32>().ok().filter(|peak| peak.is_finite()));
+                }
+                _ => (),
+            }
+        }
+    }
+
+    Some(held_gain(gain?, peak))
+}
+
+/// `gain` lowered so `peak` doesn't clip, within `MAX_GAIN_DB` either way.
+fn held_gain(gain: f32, peak: Option<f32>) -> f32 {
+    let gain = match peak {
+        Some(peak) if peak > 0. => gain.min(-20. * peak.log10()),
+        _ => gain,
+    };
+    gain.clamp(-MAX_GAIN_DB, MAX_GAIN_DB)
+}
+
+/// `"-6.54 dB"` -> `-6.54`, None for what isn't a finite number.
+fn parse_db(val: &str) -> Option<f32> {
+    let val = val.trim();
+    let number = val.strip_suffix("dB").or_else(|| val.strip_suffix("db")).unwrap_or(val);
+    number.trim().parse().ok().filter(|db: &f32| db.is_finite())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gain_tags() {
+        assert_eq!(parse_db("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_db(" +2db"), Some(2.));
+        assert_eq!(parse_db("inf dB"), None);
+        assert_eq!(parse_db("NaN"), None);
+        assert_eq!(parse_db("loud"), None);
+    }
+
+    #[test]
+    fn gain_held_back() {
+        assert_eq!(held_gain(6., Some(1.)), 0.);
+        assert_eq!(held_gain(6., None), 6.);
+        assert_eq!(held_gain(-3., Some(0.5)), -3.);
+        assert_eq!(held_gain(200., Some(0.)), MAX_GAIN_DB);
+        assert_eq!(held_gain(-200., None), -MAX_GAIN_DB);
+    }
+}
@@ -0,0 +1,130 @@
+use std::path::Path;
+
+use crate::decoder::Decoder;
+use crate::status::PlayerStatus;
+use crate::tags::Tags;
+
+/// Replace each `{key}` in `template` with `lookup(key)`. Unknown keys render as nothing.
+pub fn render<F>(template: &str, lookup: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        if let Some(value) = lookup(&rest[start + 1..end]) {
+            out.push_str(&value);
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+
+    out
+}
+
+/// Everything a template can refer to for the track being played.
+///
+/// Placeholders are the tags themselves (`{artist}`, `{title}`, ...) plus `{path}`,
+/// `{filename}`, `{bitrate}` (kbps), `{dur}` (`live` for radio), and with a status
+/// `{pos}` and `{state}`. A radio station's song title stands in for `{title}`.
+#[derive(Clone)]
+pub struct TrackContext {
+    pub path: String,
+    pub tags: Vec<(String, String)>,
+    pub bit_rate: usize,
+    pub duration: f64,
+}
+
+impl TrackContext {
+    pub fn new(path: &str, tags: &Tags, decoder: &Decoder) -> Self {
+        // lossless decoders often leave this to the container
+        let bit_rate = match decoder.bit_rate() {
+            0 => decoder.input().bit_rate().max(0) as usize,
+            bit_rate => bit_rate,
+        };
+
+        Self {
+            path: path.to_string(),
+            tags: tags.entries.iter().map(|(k, _)| (k.clone(), tags.values(k).join(", "))).collect(),
+            bit_rate,
+            duration: decoder.duration(),
+        }
+    }
+
+    pub fn render(&self, template: &str, status: Option<&PlayerStatus>) -> String {
+        render(template, |key| self.lookup(key, status))
+    }
+
+    fn lookup(&self, key: &str, status: Option<&PlayerStatus>) -> Option<String> {
+        match (key, status) {
+            ("pos", Some(status)) => Some(clock(status.position())),
+            ("state", Some(status)) => Some(status.state().to_lowercase()),
+            ("volume", Some(status)) => Some(status.describe_volume()),
+            // internet radio names each song as it starts
+            ("title", Some(status)) => status.stream_title.lock().unwrap().clone()
+                .or_else(|| self.lookup(key, None)),
+            ("dur", _) if self.duration > 0. => Some(clock(self.duration)),
+            ("dur", _) => Some("live".to_string()),
+            ("path", _) => Some(self.path.clone()),
+            ("filename", _) => Path::new(&self.path).file_name()
+                .map(|name| name.to_string_lossy().into_owned()),
+            ("bitrate", _) if self.bit_rate > 0 => Some((self.bit_rate / 1000).to_string()),
+            (key, _) => self.tags.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone()),
+        }
+    }
+}
+
+/// `m:ss`, or `h:mm:ss` from an hour on.
+pub fn clock(seconds: f64) -> String {
+    let total = seconds.max(0.) as u64;
+    let (hours, minutes, seconds) = (total / 3600, total % 3600 / 60, total % 60);
+
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders() {
+        let lookup = |key: &str| Some(key.to_uppercase()).filter(|_| key != "missing");
+        assert_eq!(render("{artist} - {title}", lookup), "ARTIST - TITLE");
+        assert_eq!(render("[{missing}]", lookup), "[]");
+        assert_eq!(render("no keys", lookup), "no keys");
+        // an unclosed brace is kept as it is
+        assert_eq!(render("{artist} {title", lookup), "ARTIST {title");
+    }
+
+    #[test]
+    fn track_context() {
+        let track = TrackContext {
+            path: "/music/Album/01 Song.flac".to_string(),
+            tags: vec![("artist".to_string(), "Someone".to_string())],
+            bit_rate: 0,
+            duration: 0.,
+        };
+        assert_eq!(track.render("{artist}: {filename} ({dur}) {bitrate}", None), "Someone: 01 Song.flac (live) ");
+    }
+
+    #[test]
+    fn clocks() {
+        assert_eq!(clock(0.), "0:00");
+        assert_eq!(clock(83.9), "1:23");
+        assert_eq!(clock(3723.), "1:02:03");
+        assert_eq!(clock(-5.), "0:00");
+    }
+}
@@ -0,0 +1,133 @@
+use std::io::{self, Stdout};
+
+use crossterm::{
+    cursor,
+    execute,
+    terminal::{EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, Gauge, List, ListItem, Paragraph},
+    Frame, Terminal,
+};
+
+use fluxplayer::{status::PlayerStatus, template::clock};
+
+use crate::keys::RawMode;
+use crate::TrackInfo;
+
+/// Most of the queue listed, the panel only shows what fits anyway.
+const QUEUE_LIMIT: usize = 200;
+
+/// The full screen display of `--tui`. It's kept up for the whole session, so
+/// the terminal doesn't flash back to the shell between tracks.
+pub struct Tui {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    /// What plays after the current track, as shown in the queue panel.
+    queue: Vec<String>,
+    legend: String,
+    // dropped after the screen is left, see Drop
+    _raw_mode: RawMode,
+}
+
+impl Tui {
+    /// Switch to the alternate screen, with `legend` as the key hints.
+    pub fn enter(legend: String) -> io::Result<Self> {
+        let raw_mode = RawMode::enable()
+            .ok_or_else(|| io::Error::other("could not put the terminal in raw mode"))?;
+        execute!(io::stdout(), EnterAlternateScreen, cursor::Hide)?;
+        let terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+        Ok(Self { terminal, queue: Vec::new(), legend, _raw_mode: raw_mode })
+    }
+
+    /// Show `legend` as the key hints from now on.
+    pub fn set_legend(&mut self, legend: String) {
+        self.legend = legend;
+    }
+
+    /// List `upcoming` in the queue panel, for the next track. Anything printed
+    /// between tracks, like autofill reports, is cleared away.
+    pub fn set_queue(&mut self, upcoming: impl Iterator<Item = String>) {
+        self.queue = upcoming.take(QUEUE_LIMIT).collect();
+        let _ = self.terminal.clear();
+    }
+
+    /// Redraw for `info` as it plays. Only what changed reaches the terminal.
+    pub fn draw(&mut self, info: &TrackInfo, status: &PlayerStatus) -> io::Result<()> {
+        let (queue, legend) = (&self.queue, &self.legend);
+        self.terminal.draw(|frame| render(frame, info, status, queue, legend))?;
+        Ok(())
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), LeaveAlternateScreen, cursor::Show);
+    }
+}
+
+fn render(frame: &mut Frame, info: &TrackInfo, status: &PlayerStatus, queue: &[String], legend: &str) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Length(3), Constraint::Min(4), Constraint::Length(1)])
+        .split(frame.size());
+    let panels = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+        .split(rows[2]);
+
+    frame.render_widget(progress(info, status), rows[0]);
+    frame.render_widget(volume(status), rows[1]);
+    render_report(frame, info, panels[0]);
+    render_queue(frame, queue, panels[1]);
+    frame.render_widget(Paragraph::new(format!(" {}", legend)).style(Style::default().fg(Color::DarkGray)), rows[3]);
+}
+
+/// Position in the track, which the seek keys move.
+fn progress(info: &TrackInfo, status: &PlayerStatus) -> Gauge<'static> {
+    // what a radio station says is playing comes before the tags
+    let title = status.stream_title.lock().unwrap().clone()
+        .unwrap_or_else(|| info.tags.get("title").unwrap_or(&info.path).to_string());
+    let position = status.position();
+    let (ratio, duration) = if info.duration > 0. {
+        ((position / info.duration).clamp(0., 1.), clock(info.duration))
+    } else {
+        (0., "live".to_string())
+    };
+
+    Gauge::default()
+        .block(Block::default().borders(Borders::ALL).title(format!(" {} ", title)))
+        .gauge_style(Style::default().fg(Color::Cyan))
+        .ratio(ratio)
+        .label(format!("{} / {}  [{}]", clock(position), duration, status.state()))
+}
+
+fn volume(status: &PlayerStatus) -> Gauge<'static> {
+    Gauge::default()
+        .block(Block::default().borders(Borders::ALL).title(" Volume "))
+        .gauge_style(Style::default().fg(Color::Green))
+        .ratio(f64::from(status.gain()))
+        .label(status.describe_volume())
+}
+
+/// The track's report as printed in line mode, its tags among it.
+fn render_report(frame: &mut Frame, info: &TrackInfo, area: Rect) {
+    let report = Paragraph::new(info.report.join("\n"))
+        .block(Block::default().borders(Borders::ALL).title(" Now Playing "));
+    frame.render_widget(report, area);
+}
+
+fn render_queue(frame: &mut Frame, queue: &[String], area: Rect) {
+    let items: Vec<_> = queue.iter()
+        .enumerate()
+        .map(|(n, name)| ListItem::new(format!("{:>3}. {}", n + 1, name)))
+        .collect();
+    let list = if items.is_empty() {
+        List::new([ListItem::new("end of the queue").style(Style::default().add_modifier(Modifier::DIM))])
+    } else {
+        List::new(items)
+    };
+    frame.render_widget(list.block(Block::default().borders(Borders::ALL).title(" Up Next ")), area);
+}